@@ -0,0 +1,104 @@
+-- Schema as used before migrations were shipped. Existing databases already contain the
+-- tables, so everything in here has to be idempotent.
+CREATE TABLE IF NOT EXISTS twitch_users
+(
+    id          INTEGER PRIMARY KEY,
+    username    TEXT NOT NULL,
+    avatar      TEXT NOT NULL,
+    eventsub_id TEXT NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS twitch_notifications
+(
+    id       SERIAL PRIMARY KEY,
+    guild_id BIGINT  NOT NULL,
+    user_id  INTEGER NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS twitch_streams
+(
+    id         INTEGER PRIMARY KEY,
+    user_id    INTEGER     NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    ended_at   TIMESTAMPTZ
+);
+
+-- Older databases may have been created without the constraints added below. Rows that
+-- violate them, i.e. notifications and streams of unknown users and duplicate
+-- notifications, are moved into quarantine tables instead of being dropped. They can be
+-- inspected and restored by hand, and are reported with a warning when the migration runs.
+CREATE TABLE IF NOT EXISTS quarantined_twitch_notifications
+(
+    LIKE twitch_notifications,
+    reason         TEXT        NOT NULL,
+    quarantined_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+CREATE TABLE IF NOT EXISTS quarantined_twitch_streams
+(
+    LIKE twitch_streams,
+    reason         TEXT        NOT NULL,
+    quarantined_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+WITH moved AS (
+    DELETE FROM twitch_notifications tn
+        WHERE NOT EXISTS (SELECT 1 FROM twitch_users tu WHERE tu.id = tn.user_id)
+        RETURNING tn.*
+)
+INSERT INTO quarantined_twitch_notifications
+SELECT moved.*, 'unknown_user' FROM moved;
+
+WITH moved AS (
+    DELETE FROM twitch_streams ts
+        WHERE NOT EXISTS (SELECT 1 FROM twitch_users tu WHERE tu.id = ts.user_id)
+        RETURNING ts.*
+)
+INSERT INTO quarantined_twitch_streams
+SELECT moved.*, 'unknown_user' FROM moved;
+
+WITH moved AS (
+    DELETE FROM twitch_notifications a USING twitch_notifications b
+        WHERE a.guild_id = b.guild_id AND a.user_id = b.user_id AND a.id > b.id
+        RETURNING a.*
+)
+INSERT INTO quarantined_twitch_notifications
+SELECT moved.*, 'duplicate' FROM moved;
+
+DO
+$$
+    DECLARE
+        notifications BIGINT := (SELECT count(*) FROM quarantined_twitch_notifications);
+        streams       BIGINT := (SELECT count(*) FROM quarantined_twitch_streams);
+    BEGIN
+        IF notifications > 0 OR streams > 0 THEN
+            RAISE WARNING 'Moved % notifications and % streams violating the new constraints into quarantined_twitch_notifications and quarantined_twitch_streams',
+                notifications, streams;
+        END IF;
+    END
+$$;
+
+DO
+$$
+    BEGIN
+        IF NOT EXISTS (SELECT 1 FROM pg_constraint WHERE conname = 'twitch_notifications_guild_id_user_id_key') THEN
+            ALTER TABLE twitch_notifications
+                ADD CONSTRAINT twitch_notifications_guild_id_user_id_key UNIQUE (guild_id, user_id);
+        END IF;
+
+        IF NOT EXISTS (SELECT 1 FROM pg_constraint WHERE conname = 'twitch_notifications_user_id_fkey') THEN
+            ALTER TABLE twitch_notifications
+                ADD CONSTRAINT twitch_notifications_user_id_fkey
+                    FOREIGN KEY (user_id) REFERENCES twitch_users (id) ON DELETE CASCADE;
+        END IF;
+
+        IF NOT EXISTS (SELECT 1 FROM pg_constraint WHERE conname = 'twitch_streams_user_id_fkey') THEN
+            ALTER TABLE twitch_streams
+                ADD CONSTRAINT twitch_streams_user_id_fkey
+                    FOREIGN KEY (user_id) REFERENCES twitch_users (id) ON DELETE CASCADE;
+        END IF;
+    END
+$$;
+
+CREATE INDEX IF NOT EXISTS twitch_notifications_user_id_idx ON twitch_notifications (user_id);
+CREATE INDEX IF NOT EXISTS twitch_streams_open_idx ON twitch_streams (user_id) WHERE ended_at IS NULL;
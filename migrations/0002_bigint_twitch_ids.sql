@@ -0,0 +1,7 @@
+-- Twitch ids are opaque strings which already outgrow INTEGER for streams. The quarantine
+-- tables copy the columns of the tables they hold rows of, so they are widened as well.
+ALTER TABLE twitch_notifications ALTER COLUMN user_id TYPE BIGINT;
+ALTER TABLE twitch_users ALTER COLUMN id TYPE BIGINT;
+ALTER TABLE twitch_streams ALTER COLUMN id TYPE BIGINT, ALTER COLUMN user_id TYPE BIGINT;
+ALTER TABLE quarantined_twitch_notifications ALTER COLUMN user_id TYPE BIGINT;
+ALTER TABLE quarantined_twitch_streams ALTER COLUMN id TYPE BIGINT, ALTER COLUMN user_id TYPE BIGINT;
@@ -0,0 +1,8 @@
+-- Ids of recently handled eventsub messages, Twitch may deliver a message more than once
+CREATE TABLE eventsub_messages
+(
+    id          TEXT PRIMARY KEY,
+    received_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+CREATE INDEX eventsub_messages_received_at_idx ON eventsub_messages (received_at);
@@ -0,0 +1,9 @@
+-- Notifications that have not been delivered to the bot yet
+CREATE TABLE deliveries
+(
+    id         BIGSERIAL PRIMARY KEY,
+    stream_id  BIGINT      NOT NULL REFERENCES twitch_streams (id) ON DELETE CASCADE,
+    query      TEXT        NOT NULL,
+    attempts   INTEGER     NOT NULL DEFAULT 0,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
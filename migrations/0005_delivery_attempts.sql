@@ -0,0 +1,3 @@
+ALTER TABLE deliveries ADD COLUMN next_attempt_at TIMESTAMPTZ NOT NULL DEFAULT now();
+
+CREATE INDEX deliveries_next_attempt_at_idx ON deliveries (next_attempt_at);
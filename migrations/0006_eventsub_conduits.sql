@@ -0,0 +1,17 @@
+-- Conduit the eventsubs are registered with when using the conduit transport
+CREATE TABLE eventsub_conduits
+(
+    id          TEXT PRIMARY KEY,
+    shard_count INTEGER     NOT NULL,
+    created_at  TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+-- Replicas receiving a shard of the conduit, either with a callback or a websocket session
+CREATE TABLE eventsub_replicas
+(
+    id           TEXT PRIMARY KEY,
+    callback_url TEXT,
+    session_id   TEXT,
+    shard_id     INTEGER,
+    last_seen_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
@@ -0,0 +1,10 @@
+-- Fingerprint of the secret the eventsub of the user was registered with, unknown for
+-- eventsubs registered before secrets were rotated
+ALTER TABLE twitch_users ADD COLUMN eventsub_secret TEXT;
+
+-- Secrets of a rotation no eventsub uses anymore, messages signed with them are rejected
+CREATE TABLE retired_eventsub_secrets
+(
+    fingerprint TEXT PRIMARY KEY,
+    retired_at  TIMESTAMPTZ NOT NULL DEFAULT now()
+);
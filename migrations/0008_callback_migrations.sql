@@ -0,0 +1,9 @@
+-- Replacement eventsubs created for a changed callback url, the old eventsub of the user is
+-- deleted once the replacement is enabled
+CREATE TABLE callback_migrations
+(
+    user_id         BIGINT PRIMARY KEY REFERENCES twitch_users (id) ON DELETE CASCADE,
+    old_eventsub_id TEXT        NOT NULL,
+    new_eventsub_id TEXT        NOT NULL,
+    created_at      TIMESTAMPTZ NOT NULL DEFAULT now()
+);
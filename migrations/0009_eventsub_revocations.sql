@@ -0,0 +1,14 @@
+-- Eventsubs Twitch revoked with the reason it gave, and the eventsub that replaced them if
+-- the revocation could be recovered from
+CREATE TABLE eventsub_revocations
+(
+    id                       BIGSERIAL PRIMARY KEY,
+    eventsub_id              TEXT        NOT NULL,
+    user_id                  BIGINT,
+    status                   TEXT        NOT NULL,
+    resubscribed_eventsub_id TEXT,
+    revoked_at               TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+-- Deliveries without a stream tell the bot about other changes, e.g. removed accounts
+ALTER TABLE deliveries ALTER COLUMN stream_id DROP NOT NULL;
@@ -0,0 +1,12 @@
+-- Last known status of the eventsub each user is tracked with and how often its callback
+-- verification was retried. Written before the user itself, so there is no foreign key
+CREATE TABLE eventsub_statuses
+(
+    user_id     BIGINT PRIMARY KEY,
+    eventsub_id TEXT        NOT NULL,
+    status      TEXT        NOT NULL,
+    attempts    INTEGER     NOT NULL DEFAULT 0,
+    updated_at  TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+CREATE INDEX eventsub_statuses_eventsub_id_idx ON eventsub_statuses (eventsub_id);
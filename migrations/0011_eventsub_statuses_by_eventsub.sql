@@ -0,0 +1,6 @@
+-- Statuses are kept per eventsub, so registering the replacement of a user's eventsub, e.g. in
+-- a callback migration, does not overwrite the status of the one they are still tracked with
+ALTER TABLE eventsub_statuses DROP CONSTRAINT eventsub_statuses_pkey;
+DROP INDEX eventsub_statuses_eventsub_id_idx;
+ALTER TABLE eventsub_statuses ADD PRIMARY KEY (eventsub_id);
+CREATE INDEX eventsub_statuses_user_id_idx ON eventsub_statuses (user_id);
@@ -0,0 +1,4 @@
+-- Endpoint of the bot a delivery is sent to, the go-live announcements keep the bot url.
+-- Deliveries without a stream were the only ones telling the bot about removed accounts.
+ALTER TABLE deliveries ADD COLUMN endpoint TEXT NOT NULL DEFAULT 'stream_online';
+UPDATE deliveries SET endpoint = 'user_removed' WHERE stream_id IS NULL;
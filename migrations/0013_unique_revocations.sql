@@ -0,0 +1,4 @@
+-- Twitch redelivers a revocation if handling it failed, which is recorded only once
+DELETE FROM eventsub_revocations a USING eventsub_revocations b
+    WHERE a.eventsub_id = b.eventsub_id AND a.status = b.status AND a.id > b.id;
+ALTER TABLE eventsub_revocations ADD CONSTRAINT eventsub_revocations_eventsub_id_status_key UNIQUE (eventsub_id, status);
@@ -0,0 +1,176 @@
+use std::sync::{Arc, Mutex};
+
+use log::warn;
+
+use crate::errors::Error;
+use crate::metrics::{EVENTSUB_COST_WARNINGS, EVENTSUB_MAX_TOTAL_COST, EVENTSUB_TOTAL_COST};
+use crate::structs::Result;
+
+/// Cost a new `stream.online` eventsub adds at most. Eventsubs of streamers that authorized
+/// the app through OAuth are free, but the authorization may have been revoked since.
+pub const EVENTSUB_COST: u32 = 1;
+
+/// Cost of the eventsubs and the maximum Twitch allows, as reported by Helix.
+#[derive(Clone, Copy)]
+pub struct EventsubCost {
+    pub total_cost: u32,
+    pub max_total_cost: u32,
+}
+
+impl EventsubCost {
+    fn percent(&self) -> u32 {
+        if self.max_total_cost == 0 {
+            return 100;
+        }
+
+        self.total_cost.saturating_mul(100) / self.max_total_cost
+    }
+}
+
+/// Last cost reported by Helix, shared by all workers. Unknown until the first response.
+#[derive(Clone, Default)]
+pub struct EventsubBudget(Arc<Mutex<BudgetState>>);
+
+#[derive(Default)]
+struct BudgetState {
+    cost: Option<EventsubCost>,
+    /// Cost of the eventsubs being created, which Helix did not report yet.
+    reserved: u32,
+}
+
+/// Cost reserved for an eventsub being created, released once Helix responded.
+pub(crate) struct Reservation(EventsubBudget);
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        if let Ok(mut state) = self.0 .0.lock() {
+            state.reserved = state.reserved.saturating_sub(EVENTSUB_COST);
+        }
+    }
+}
+
+impl EventsubBudget {
+    pub fn cost(&self) -> Option<EventsubCost> {
+        self.0.lock().ok().and_then(|state| state.cost)
+    }
+
+    /// Records the cost reported by Helix and warns about every threshold, in percent of the
+    /// maximum, that has been crossed since the previous report.
+    pub(crate) fn update(&self, cost: EventsubCost, thresholds: &[u8]) {
+        let Ok(mut state) = self.0.lock() else {
+            return;
+        };
+        let previous = state.cost.replace(cost);
+
+        EVENTSUB_TOTAL_COST.set(cost.total_cost.into());
+        EVENTSUB_MAX_TOTAL_COST.set(cost.max_total_cost.into());
+
+        for threshold in crossed_thresholds(previous, cost, thresholds) {
+            warn!(
+                "Eventsubs cost {} of at most {}, more than {threshold}% of the budget",
+                cost.total_cost, cost.max_total_cost
+            );
+            EVENTSUB_COST_WARNINGS
+                .with_label_values(&[&threshold.to_string()])
+                .inc();
+        }
+    }
+
+    /// Reserves the cost of another eventsub until the returned reservation is dropped, so
+    /// concurrent creations can't exceed the maximum cost together. Fails if another eventsub
+    /// would exceed it. Passes while the cost is unknown, Helix rejects the eventsub in that
+    /// case.
+    pub(crate) fn reserve(&self) -> Result<Reservation> {
+        let mut state = self.0.lock().map_err(|_| Error::Mutex)?;
+
+        if let Some(cost) = state.cost {
+            if cost.total_cost + state.reserved + EVENTSUB_COST > cost.max_total_cost {
+                warn!(
+                    "Eventsubs cost {} of at most {} with {} being created, not creating another one",
+                    cost.total_cost, cost.max_total_cost, state.reserved
+                );
+
+                return Err(Error::EventsubBudgetExceeded);
+            }
+        }
+
+        state.reserved += EVENTSUB_COST;
+
+        Ok(Reservation(self.clone()))
+    }
+}
+
+/// Thresholds the cost reached that the previous one was below.
+fn crossed_thresholds(
+    previous: Option<EventsubCost>,
+    current: EventsubCost,
+    thresholds: &[u8],
+) -> Vec<u8> {
+    let previous = previous.map_or(0, |c| c.percent());
+    let current = current.percent();
+
+    thresholds
+        .iter()
+        .copied()
+        .filter(|t| previous < u32::from(*t) && current >= u32::from(*t))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cost(total_cost: u32) -> EventsubCost {
+        EventsubCost {
+            total_cost,
+            max_total_cost: 10_000,
+        }
+    }
+
+    #[test]
+    fn warns_when_crossing_thresholds() {
+        assert_eq!(crossed_thresholds(None, cost(8_500), &[80, 95]), [80]);
+        assert_eq!(
+            crossed_thresholds(Some(cost(7_000)), cost(9_600), &[80, 95]),
+            [80, 95]
+        );
+    }
+
+    #[test]
+    fn warns_only_once_per_threshold() {
+        assert!(crossed_thresholds(Some(cost(8_100)), cost(8_200), &[80, 95]).is_empty());
+        assert!(crossed_thresholds(Some(cost(8_200)), cost(7_900), &[80, 95]).is_empty());
+    }
+
+    #[test]
+    fn rejects_eventsubs_over_the_budget() {
+        let budget = EventsubBudget::default();
+        assert!(budget.reserve().is_ok());
+
+        budget.update(cost(9_999), &[]);
+        assert!(budget.reserve().is_ok());
+
+        budget.update(cost(10_000), &[]);
+        assert!(matches!(
+            budget.reserve(),
+            Err(Error::EventsubBudgetExceeded)
+        ));
+    }
+
+    #[test]
+    fn reserves_cost_of_concurrent_creations() {
+        let budget = EventsubBudget::default();
+        budget.update(cost(9_998), &[]);
+
+        let first = budget.reserve().unwrap();
+        let second = budget.reserve().unwrap();
+        assert!(matches!(
+            budget.reserve(),
+            Err(Error::EventsubBudgetExceeded)
+        ));
+
+        drop(first);
+        drop(second);
+        assert!(budget.reserve().is_ok());
+    }
+}
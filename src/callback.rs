@@ -0,0 +1,275 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use log::{error, info, warn};
+use serde::Serialize;
+use sqlx::Row;
+
+use crate::config::EventsubTransport;
+use crate::structs::{AppState, Result};
+
+const ENABLED_STATUS: &str = "enabled";
+pub(crate) const PENDING_STATUS: &str = "webhook_callback_verification_pending";
+/// Time between the checks whether Twitch verified the replacement eventsubs.
+pub(crate) const ENABLED_POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Warns about eventsubs of tracked users which still point to another callback, e.g. after
+/// the domain of the service changed.
+pub async fn check_callback_url(state: AppState) {
+    let EventsubTransport::Webhook { callback_url } = state.twitch.transport else {
+        return;
+    };
+
+    match state.stale_eventsubs(callback_url).await {
+        Ok(stale) if stale.is_empty() => {}
+        Ok(stale) => warn!(
+            "{} eventsubs point to another callback than {callback_url}, run the migrate-callback command to move them",
+            stale.len()
+        ),
+        Err(e) => error!("Could not check the callback of the eventsubs: {e}"),
+    }
+}
+
+/// Eventsub of a tracked user with another callback than the configured one.
+pub(crate) struct StaleEventsub {
+    pub user_id: i64,
+    pub eventsub_id: String,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum MigrationStatus {
+    /// The replacement is enabled and the old eventsub deleted.
+    Migrated,
+    /// Twitch did not verify the replacement yet, the next run continues with it.
+    Pending,
+    Failed,
+}
+
+#[derive(Serialize)]
+pub(crate) struct CallbackMigration {
+    pub user_id: i64,
+    pub old_eventsub_id: String,
+    pub new_eventsub_id: Option<String>,
+    pub status: MigrationStatus,
+    pub error: Option<String>,
+}
+
+/// Replacement eventsub recorded in the database, so an interrupted migration continues
+/// with it instead of creating another one.
+struct Replacement {
+    user_id: i64,
+    old_eventsub_id: String,
+    new_eventsub_id: String,
+}
+
+/// Moves the eventsubs of the tracked users to the callback. A replacement is created for
+/// every eventsub with another callback, and the old one is only deleted once Twitch enabled
+/// the replacement, so no notification is missed. Replacements not enabled within the timeout
+/// are left for the next run.
+pub(crate) async fn migrate_callbacks(
+    state: &AppState,
+    callback_url: &str,
+    enable_timeout: Duration,
+) -> Result<Vec<CallbackMigration>> {
+    let mut migrations = vec![];
+    let mut pending = sqlx::query(
+        "SELECT user_id, old_eventsub_id, new_eventsub_id FROM callback_migrations ORDER BY user_id",
+    )
+    .fetch_all(&state.db)
+    .await?
+    .iter()
+    .map(|row| Replacement {
+        user_id: row.get("user_id"),
+        old_eventsub_id: row.get("old_eventsub_id"),
+        new_eventsub_id: row.get("new_eventsub_id"),
+    })
+    .collect::<Vec<_>>();
+
+    for stale in state.stale_eventsubs(callback_url).await? {
+        if pending.iter().any(|r| r.user_id == stale.user_id) {
+            continue;
+        }
+
+        // Registering again after a crash returns the replacement created before
+        match create_replacement(state, &stale).await {
+            Ok(replacement) => pending.push(replacement),
+            Err(e) => migrations.push(CallbackMigration {
+                user_id: stale.user_id,
+                old_eventsub_id: stale.eventsub_id,
+                new_eventsub_id: None,
+                status: MigrationStatus::Failed,
+                error: Some(e.to_string()),
+            }),
+        }
+    }
+
+    let deadline = Instant::now() + enable_timeout;
+
+    while !pending.is_empty() {
+        let statuses = state
+            .fetch_eventsubs()
+            .await?
+            .into_iter()
+            .map(|s| (s.id, s.status))
+            .collect::<HashMap<_, _>>();
+
+        let mut waiting = vec![];
+        for replacement in pending {
+            let status = statuses
+                .get(&replacement.new_eventsub_id)
+                .map(String::as_str);
+
+            let (result, error) = match status {
+                Some(ENABLED_STATUS) => match complete(state, &replacement).await {
+                    Ok(()) => (MigrationStatus::Migrated, None),
+                    Err(e) => (MigrationStatus::Failed, Some(e.to_string())),
+                },
+                Some(PENDING_STATUS) => {
+                    waiting.push(replacement);
+                    continue;
+                }
+                status => {
+                    let status = status.unwrap_or("deleted");
+                    let error = match discard(state, &replacement, status).await {
+                        Ok(()) => format!("Replacement has status {status}"),
+                        Err(e) => {
+                            format!("Replacement has status {status}, discarding it failed: {e}")
+                        }
+                    };
+
+                    (MigrationStatus::Failed, Some(error))
+                }
+            };
+
+            migrations.push(CallbackMigration {
+                user_id: replacement.user_id,
+                old_eventsub_id: replacement.old_eventsub_id,
+                new_eventsub_id: Some(replacement.new_eventsub_id),
+                status: result,
+                error,
+            });
+        }
+        pending = waiting;
+
+        if pending.is_empty() || Instant::now() >= deadline {
+            break;
+        }
+
+        actix_web::rt::time::sleep(ENABLED_POLL_INTERVAL).await;
+    }
+
+    migrations.extend(pending.into_iter().map(|r| CallbackMigration {
+        user_id: r.user_id,
+        old_eventsub_id: r.old_eventsub_id,
+        new_eventsub_id: Some(r.new_eventsub_id),
+        status: MigrationStatus::Pending,
+        error: None,
+    }));
+    migrations.sort_by_key(|m| m.user_id);
+
+    Ok(migrations)
+}
+
+async fn create_replacement(state: &AppState, stale: &StaleEventsub) -> Result<Replacement> {
+    let new_eventsub_id = state.register_eventsub(stale.user_id).await?;
+
+    sqlx::query(
+        "INSERT INTO callback_migrations (user_id, old_eventsub_id, new_eventsub_id) VALUES ($1, $2, $3)",
+    )
+    .bind(stale.user_id)
+    .bind(stale.eventsub_id.as_str())
+    .bind(new_eventsub_id.as_str())
+    .execute(&state.db)
+    .await?;
+
+    info!(
+        "Created eventsub {new_eventsub_id} to replace eventsub {} of user {}",
+        stale.eventsub_id, stale.user_id
+    );
+
+    Ok(Replacement {
+        user_id: stale.user_id,
+        old_eventsub_id: stale.eventsub_id.clone(),
+        new_eventsub_id,
+    })
+}
+
+/// Deletes the old eventsub and points the user to the enabled replacement.
+async fn complete(state: &AppState, replacement: &Replacement) -> Result<()> {
+    state.delete_eventsub(&replacement.old_eventsub_id).await?;
+
+    let mut transaction = state.db.begin().await?;
+
+    sqlx::query(
+        "UPDATE twitch_users SET eventsub_id = $2, eventsub_secret = $3 WHERE id = $1 AND eventsub_id = $4",
+    )
+    .bind(replacement.user_id)
+    .bind(replacement.new_eventsub_id.as_str())
+    .bind(state.eventsub_secret_fingerprint())
+    .bind(replacement.old_eventsub_id.as_str())
+    .execute(&mut transaction)
+    .await?;
+
+    sqlx::query("DELETE FROM callback_migrations WHERE user_id = $1")
+        .bind(replacement.user_id)
+        .execute(&mut transaction)
+        .await?;
+
+    transaction.commit().await?;
+
+    info!(
+        "Moved user {} from eventsub {} to eventsub {}",
+        replacement.user_id, replacement.old_eventsub_id, replacement.new_eventsub_id
+    );
+
+    Ok(())
+}
+
+/// Drops a replacement Twitch could not verify, the next run creates a new one. The old
+/// eventsub is kept.
+async fn discard(state: &AppState, replacement: &Replacement, status: &str) -> Result<()> {
+    warn!(
+        "Replacement eventsub {} of user {} has status {status}, discarding it",
+        replacement.new_eventsub_id, replacement.user_id
+    );
+
+    // Deleting an eventsub Twitch already removed is fine
+    state.delete_eventsub(&replacement.new_eventsub_id).await?;
+
+    sqlx::query("DELETE FROM callback_migrations WHERE user_id = $1")
+        .bind(replacement.user_id)
+        .execute(&state.db)
+        .await?;
+
+    Ok(())
+}
+
+impl AppState {
+    /// Eventsubs of tracked users that point to another callback than the given one.
+    pub(crate) async fn stale_eventsubs(&self, callback_url: &str) -> Result<Vec<StaleEventsub>> {
+        let callbacks = self
+            .fetch_eventsubs()
+            .await?
+            .into_iter()
+            .filter(|s| s.transport.method == "webhook")
+            .map(|s| (s.id, s.transport.callback))
+            .collect::<HashMap<_, _>>();
+
+        let users = sqlx::query("SELECT id, eventsub_id FROM twitch_users ORDER BY id")
+            .fetch_all(&self.db)
+            .await?;
+
+        Ok(users
+            .iter()
+            .map(|row| StaleEventsub {
+                user_id: row.get("id"),
+                eventsub_id: row.get("eventsub_id"),
+            })
+            .filter(|s| match callbacks.get(&s.eventsub_id) {
+                Some(callback) => callback.as_deref() != Some(callback_url),
+                None => false,
+            })
+            .collect())
+    }
+}
@@ -0,0 +1,1252 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt::{self, Display, Formatter};
+use std::time::{Duration, Instant};
+
+use chrono::Utc;
+use clap::{Parser, Subcommand};
+use log::{error, info, warn};
+use serde::Serialize;
+use sqlx::postgres::PgPoolOptions;
+use sqlx::Row;
+
+use crate::callback::{
+    self, CallbackMigration, MigrationStatus, ENABLED_POLL_INTERVAL, PENDING_STATUS,
+};
+use crate::conduit;
+use crate::config::{Config, EventsubTransport, ShardTransport};
+use crate::errors::Error;
+use crate::eventsub::secret_fingerprint;
+use crate::routes::twitch::structs::{StreamData, TwitchEventsub};
+use crate::structs::AppState;
+use crate::MIGRATOR;
+
+/// Ended streams are kept this long, so late eventsub retries are not announced again.
+const STREAM_RETENTION_DAYS: i32 = 30;
+const ENABLED_STATUS: &str = "enabled";
+
+type CliResult<T = ()> = std::result::Result<T, Box<dyn std::error::Error>>;
+
+#[derive(Parser)]
+#[command(
+    version,
+    about = "Sends notifications to the bot when Twitch streamers go live"
+)]
+pub struct Cli {
+    /// Print the output as JSON
+    #[arg(long, global = true)]
+    json: bool,
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Run the web server (default)
+    Serve,
+    #[command(flatten)]
+    Operation(Operation),
+}
+
+/// Commands that connect to the database and exit once they are done.
+#[derive(Subcommand)]
+enum Operation {
+    /// Apply pending database migrations
+    Migrate,
+    /// Align the eventsub subscriptions on Twitch with the tracked users
+    Reconcile {
+        /// Only print the changes that would be made
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// List all notifications with the status of their eventsub
+    ListSubscriptions,
+    /// List the shards of the eventsub conduit with the replicas they are assigned to
+    ListShards,
+    /// Create a notification of a Twitch user for a guild
+    Subscribe {
+        /// Login of the Twitch user
+        login: String,
+        /// Id of the guild to notify
+        #[arg(long)]
+        guild: i64,
+    },
+    /// Delete a notification, and the eventsub if it was the last one of its user
+    Unsubscribe { notification_id: i32 },
+    /// Re-register the eventsubs with the current secret and retire the previous ones once
+    /// no eventsub uses them. All replicas must run with the current secret beforehand
+    RotateSecret {
+        /// Eventsubs re-registered at once
+        #[arg(long, default_value_t = 20, value_parser = clap::value_parser!(i64).range(1..))]
+        batch_size: i64,
+        /// Seconds to wait between batches
+        #[arg(long, default_value_t = 1)]
+        batch_delay: u64,
+        /// Seconds to wait for Twitch to enable the new eventsubs of a batch, the old ones
+        /// are kept until then
+        #[arg(long, default_value_t = 60)]
+        enable_timeout: u64,
+    },
+    /// Move the eventsubs that point to another callback to the configured one
+    MigrateCallback {
+        /// Seconds to wait for Twitch to enable the replacements, pending ones are continued
+        /// by the next run
+        #[arg(long, default_value_t = 60)]
+        enable_timeout: u64,
+    },
+    /// Delete unused users, expired eventsub message ids and old streams
+    Gc,
+    /// Send a test notification for the user of a notification to the bot
+    SendTest { notification_id: i32 },
+}
+
+impl Cli {
+    pub async fn run(self, config: &'static Config) -> CliResult {
+        match self.command.unwrap_or(Command::Serve) {
+            Command::Serve => Ok(crate::run(config).await?),
+            Command::Operation(operation) => operation.run(config, self.json).await,
+        }
+    }
+}
+
+impl Operation {
+    async fn run(self, config: &'static Config, json: bool) -> CliResult {
+        let pool = PgPoolOptions::new()
+            .max_connections(config.db_max_connections)
+            .acquire_timeout(config.db_acquire_timeout)
+            .connect(config.postgres_dsn.as_str())
+            .await?;
+        let state = AppState::builder(config, pool).build();
+
+        match self {
+            Operation::Migrate => print(json, &migrate(&state).await?),
+            Operation::Reconcile { dry_run } => {
+                let report = reconcile(&state, dry_run).await?;
+                print(json, &report);
+
+                let failed = report.actions.iter().filter(|a| a.error.is_some()).count();
+                if failed > 0 {
+                    return Err(format!("{failed} changes could not be applied").into());
+                }
+            }
+            Operation::ListSubscriptions => print(json, &list_subscriptions(&state).await?),
+            Operation::ListShards => print(json, &list_shards(&state).await?),
+            Operation::Subscribe { login, guild } => {
+                print(json, &subscribe(&state, &login, guild).await?)
+            }
+            Operation::Unsubscribe { notification_id } => {
+                state.delete_notification(notification_id).await?;
+                print(json, &Unsubscribed { notification_id });
+            }
+            Operation::RotateSecret {
+                batch_size,
+                batch_delay,
+                enable_timeout,
+            } => {
+                let settings = RotationSettings {
+                    batch_size,
+                    batch_delay: Duration::from_secs(batch_delay),
+                    enable_timeout: Duration::from_secs(enable_timeout),
+                };
+                let rotation = rotate_secret(&state, &settings).await?;
+                print(json, &rotation);
+
+                if !rotation.failures.is_empty() {
+                    return Err(format!(
+                        "{} eventsubs could not be rotated",
+                        rotation.failures.len()
+                    )
+                    .into());
+                }
+            }
+            Operation::MigrateCallback { enable_timeout } => {
+                let report = migrate_callback(&state, Duration::from_secs(enable_timeout)).await?;
+                print(json, &report);
+
+                let unfinished = report
+                    .migrations
+                    .iter()
+                    .filter(|m| !matches!(m.status, MigrationStatus::Migrated))
+                    .count();
+                if unfinished > 0 {
+                    return Err(format!("{unfinished} eventsubs are not migrated yet").into());
+                }
+            }
+            Operation::Gc => print(json, &gc(&state).await?),
+            Operation::SendTest { notification_id } => {
+                let report = send_test(&state, notification_id).await?;
+                print(json, &report);
+
+                if !report.delivered {
+                    return Err("The bot did not accept the notification".into());
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn print<T: Serialize + Display>(json: bool, report: &T) {
+    if !json {
+        println!("{report}");
+        return;
+    }
+
+    match serde_json::to_string_pretty(report) {
+        Ok(output) => println!("{output}"),
+        Err(e) => error!("Could not serialize output: {e}"),
+    }
+}
+
+#[derive(Serialize)]
+struct Migrated {
+    version: i64,
+}
+
+impl Display for Migrated {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "Database is migrated to version {}", self.version)
+    }
+}
+
+async fn migrate(state: &AppState) -> CliResult<Migrated> {
+    MIGRATOR.run(&state.db).await?;
+
+    Ok(Migrated {
+        version: MIGRATOR.iter().map(|m| m.version).max().unwrap_or(0),
+    })
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "snake_case")]
+enum ReconcileKind {
+    /// Registers an eventsub for a tracked user without an enabled one.
+    Subscribe,
+    /// Points a tracked user to the enabled eventsub Twitch already has for them.
+    Relink,
+    /// Deletes an eventsub of our callback that no tracked user needs.
+    Delete,
+}
+
+#[derive(Serialize)]
+struct ReconcileAction {
+    action: ReconcileKind,
+    user_id: i64,
+    eventsub_id: Option<String>,
+    error: Option<String>,
+}
+
+#[derive(Serialize)]
+struct ReconcileReport {
+    dry_run: bool,
+    actions: Vec<ReconcileAction>,
+}
+
+impl Display for ReconcileReport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        if self.actions.is_empty() {
+            return write!(f, "Eventsubs are in sync");
+        }
+
+        for (i, a) in self.actions.iter().enumerate() {
+            if i > 0 {
+                writeln!(f)?;
+            }
+
+            let eventsub_id = a.eventsub_id.as_deref().unwrap_or("-");
+            let verb = match (&a.action, self.dry_run) {
+                (ReconcileKind::Subscribe, true) => "Would subscribe",
+                (ReconcileKind::Subscribe, false) => "Subscribed",
+                (ReconcileKind::Relink, true) => "Would relink",
+                (ReconcileKind::Relink, false) => "Relinked",
+                (ReconcileKind::Delete, true) => "Would delete",
+                (ReconcileKind::Delete, false) => "Deleted",
+            };
+
+            match a.action {
+                ReconcileKind::Delete => {
+                    write!(f, "{verb} eventsub {eventsub_id} of user {}", a.user_id)?
+                }
+                _ => write!(f, "{verb} user {} to eventsub {eventsub_id}", a.user_id)?,
+            }
+
+            if let Some(e) = &a.error {
+                write!(f, " failed: {e}")?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Eventsubs delivered to our callback, other environments may share the client id. With
+/// the websocket transport, only the subscriptions of our user are listed by Twitch.
+async fn own_eventsubs(state: &AppState) -> CliResult<Vec<TwitchEventsub>> {
+    let conduit_id = state.conduit_id().await?;
+
+    Ok(state
+        .fetch_eventsubs()
+        .await?
+        .into_iter()
+        .filter(|s| match state.twitch.transport {
+            EventsubTransport::Webhook { callback_url } => {
+                s.transport.callback.as_deref() == Some(callback_url.as_str())
+            }
+            EventsubTransport::Websocket { .. } => s.transport.method == "websocket",
+            EventsubTransport::Conduit { .. } => {
+                conduit_id.is_some() && s.transport.conduit_id == conduit_id
+            }
+        })
+        .collect())
+}
+
+async fn reconcile(state: &AppState, dry_run: bool) -> CliResult<ReconcileReport> {
+    if let EventsubTransport::Websocket { .. } = state.twitch.transport {
+        return Err(
+            "The service subscribes all users on every websocket session, there is nothing to reconcile"
+                .into(),
+        );
+    }
+
+    let eventsubs = own_eventsubs(state).await?;
+    let users = sqlx::query("SELECT id, eventsub_id FROM twitch_users ORDER BY id")
+        .fetch_all(&state.db)
+        .await?
+        .iter()
+        .map(|row| (row.get("id"), row.get("eventsub_id")))
+        .collect::<Vec<_>>();
+
+    let mut actions = plan_reconcile(&eventsubs, &users);
+
+    if !dry_run {
+        for action in actions.iter_mut() {
+            if let Err(e) = apply(state, action).await {
+                action.error = Some(e.to_string());
+            }
+        }
+    }
+
+    Ok(ReconcileReport { dry_run, actions })
+}
+
+/// Returns the actions aligning the eventsubs with the tracked users and the eventsubs they
+/// point to.
+fn plan_reconcile(eventsubs: &[TwitchEventsub], users: &[(i64, String)]) -> Vec<ReconcileAction> {
+    let mut enabled: HashMap<i64, &TwitchEventsub> = HashMap::new();
+    for eventsub in eventsubs.iter().filter(|s| s.status == ENABLED_STATUS) {
+        if let Ok(user_id) = eventsub.condition.broadcaster_user_id.parse() {
+            enabled.entry(user_id).or_insert(eventsub);
+        }
+    }
+
+    let mut kept = HashSet::new();
+    let mut actions = vec![];
+
+    for (user_id, eventsub_id) in users.iter() {
+        match enabled.get(user_id) {
+            Some(eventsub) => {
+                kept.insert(eventsub.id.as_str());
+
+                if &eventsub.id != eventsub_id {
+                    actions.push(ReconcileAction {
+                        action: ReconcileKind::Relink,
+                        user_id: *user_id,
+                        eventsub_id: Some(eventsub.id.clone()),
+                        error: None,
+                    });
+                }
+            }
+            None => actions.push(ReconcileAction {
+                action: ReconcileKind::Subscribe,
+                user_id: *user_id,
+                eventsub_id: None,
+                error: None,
+            }),
+        }
+    }
+
+    // Deleted first, so registering does not conflict with failed eventsubs of the same user
+    let mut deletions = eventsubs
+        .iter()
+        .filter(|s| !kept.contains(s.id.as_str()))
+        .map(|s| ReconcileAction {
+            action: ReconcileKind::Delete,
+            user_id: s.condition.broadcaster_user_id.parse().unwrap_or_default(),
+            eventsub_id: Some(s.id.clone()),
+            error: None,
+        })
+        .collect::<Vec<_>>();
+    deletions.append(&mut actions);
+
+    deletions
+}
+
+async fn apply(state: &AppState, action: &mut ReconcileAction) -> Result<(), Error> {
+    // The secret of an eventsub registered by someone else is not known
+    let mut secret = None;
+
+    match action.action {
+        ReconcileKind::Subscribe => {
+            let eventsub_id = state.register_eventsub(action.user_id).await?;
+            action.eventsub_id = Some(eventsub_id);
+            secret = state.eventsub_secret_fingerprint();
+        }
+        ReconcileKind::Relink => {}
+        ReconcileKind::Delete => {
+            if let Some(id) = &action.eventsub_id {
+                state.delete_eventsub(id).await?;
+            }
+
+            return Ok(());
+        }
+    }
+
+    sqlx::query("UPDATE twitch_users SET eventsub_id = $2, eventsub_secret = $3 WHERE id = $1")
+        .bind(action.user_id)
+        .bind(action.eventsub_id.as_deref())
+        .bind(secret)
+        .execute(&state.db)
+        .await?;
+
+    Ok(())
+}
+
+#[derive(Serialize)]
+struct RotationFailure {
+    target: String,
+    error: String,
+}
+
+#[derive(Serialize)]
+struct Rotation {
+    secret: String,
+    rotated: usize,
+    failures: Vec<RotationFailure>,
+    remaining: i64,
+    retired: Vec<String>,
+}
+
+impl Display for Rotation {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Rotated {} eventsubs to secret {}",
+            self.rotated, self.secret
+        )?;
+
+        for failure in self.failures.iter() {
+            write!(
+                f,
+                "\nCould not rotate {}: {}",
+                failure.target, failure.error
+            )?;
+        }
+
+        if !self.retired.is_empty() {
+            write!(f, "\nRetired secrets {}", self.retired.join(", "))?;
+        } else if self.remaining > 0 {
+            write!(
+                f,
+                "\n{} eventsubs still use an old secret, run the rotation again",
+                self.remaining
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Re-registers the eventsubs that don't use the current secret yet, then retires the
+/// previous secrets no eventsub uses anymore. Progress is kept in the database, so an
+/// interrupted rotation continues where it stopped.
+async fn rotate_secret(state: &AppState, settings: &RotationSettings) -> CliResult<Rotation> {
+    let fingerprint = secret_fingerprint(state.twitch.eventsub_secret);
+
+    // A secret that is current again must not stay retired from an earlier rotation
+    sqlx::query("DELETE FROM retired_eventsub_secrets WHERE fingerprint = $1")
+        .bind(fingerprint.as_str())
+        .execute(&state.db)
+        .await?;
+
+    let (rotated, failures) = match state.twitch.transport {
+        EventsubTransport::Webhook { .. } => {
+            rotate_eventsubs(state, &fingerprint, settings).await?
+        }
+        EventsubTransport::Conduit {
+            shard: ShardTransport::Webhook { .. },
+            ..
+        } => {
+            let (rotated, failed) = conduit::update_shard_secrets(state).await?;
+            let failures = failed
+                .into_iter()
+                .map(|e| RotationFailure {
+                    target: format!("shard {}", e.id),
+                    error: format!("{} ({})", e.message, e.code),
+                })
+                .collect();
+
+            (rotated, failures)
+        }
+        _ => return Err("Websocket messages are not signed, there is no secret to rotate".into()),
+    };
+
+    // Eventsubs of the conduit are not signed themselves, only the shards are
+    let remaining = match state.twitch.transport {
+        EventsubTransport::Webhook { .. } => sqlx::query(
+            "SELECT count(*) AS remaining FROM twitch_users WHERE eventsub_secret IS DISTINCT FROM $1",
+        )
+        .bind(fingerprint.as_str())
+        .fetch_one(&state.db)
+        .await?
+        .get::<i64, &str>("remaining"),
+        _ => failures.len() as i64,
+    };
+
+    let mut retired = vec![];
+    if remaining == 0 {
+        for secret in state.twitch.previous_eventsub_secrets.iter() {
+            let previous = secret_fingerprint(secret);
+            if previous == fingerprint {
+                continue;
+            }
+
+            sqlx::query(
+                "INSERT INTO retired_eventsub_secrets (fingerprint) VALUES ($1) ON CONFLICT DO NOTHING",
+            )
+            .bind(previous.as_str())
+            .execute(&state.db)
+            .await?;
+
+            retired.push(previous);
+        }
+    }
+
+    Ok(Rotation {
+        secret: fingerprint,
+        rotated,
+        failures,
+        remaining,
+        retired,
+    })
+}
+
+struct RotationSettings {
+    batch_size: i64,
+    batch_delay: Duration,
+    enable_timeout: Duration,
+}
+
+/// Re-registers the eventsubs of the users in batches, waiting between them to stay within
+/// the rate limit of Helix. Every user is tried once per run.
+async fn rotate_eventsubs(
+    state: &AppState,
+    fingerprint: &str,
+    settings: &RotationSettings,
+) -> CliResult<(usize, Vec<RotationFailure>)> {
+    let mut rotated = 0;
+    let mut failures = vec![];
+    let mut last_user_id = i64::MIN;
+
+    loop {
+        let users = sqlx::query(
+            "SELECT id, eventsub_id FROM twitch_users WHERE id > $1 AND eventsub_secret IS DISTINCT FROM $2 ORDER BY id LIMIT $3",
+        )
+        .bind(last_user_id)
+        .bind(fingerprint)
+        .bind(settings.batch_size)
+        .fetch_all(&state.db)
+        .await?;
+
+        if users.is_empty() {
+            break;
+        }
+
+        if last_user_id != i64::MIN {
+            actix_web::rt::time::sleep(settings.batch_delay).await;
+        }
+
+        let mut pending = vec![];
+        for user in users.iter() {
+            let user_id = user.get::<i64, &str>("id");
+            let eventsub_id = user.get::<String, &str>("eventsub_id");
+            last_user_id = user_id;
+
+            match start_rotation(state, user_id, eventsub_id.clone()).await {
+                Ok(rotation) => pending.push(rotation),
+                Err(e) => failures.push(RotationFailure {
+                    target: format!("eventsub {eventsub_id} of user {user_id}"),
+                    error: e.to_string(),
+                }),
+            }
+        }
+
+        let (batch_rotated, mut batch_failures) =
+            finish_rotations(state, pending, fingerprint, settings.enable_timeout).await?;
+        rotated += batch_rotated;
+        failures.append(&mut batch_failures);
+
+        info!(
+            "Rotated {rotated} eventsubs so far, {} failed",
+            failures.len()
+        );
+    }
+
+    Ok((rotated, failures))
+}
+
+/// Eventsub registered with the current secret, waiting to replace the old one of the user.
+struct PendingRotation {
+    user_id: i64,
+    old_eventsub_id: String,
+    new_eventsub: TwitchEventsub,
+    /// Set if the old eventsub had to be deleted to register the new one.
+    old_deleted: bool,
+}
+
+impl PendingRotation {
+    fn target(&self) -> String {
+        format!("eventsub {} of user {}", self.old_eventsub_id, self.user_id)
+    }
+}
+
+/// Registers the new eventsub of the user next to the old one. Twitch rejects a second
+/// eventsub with the same callback, in that case the old one is deleted and registered again,
+/// as adopting it would keep the old secret.
+async fn start_rotation(
+    state: &AppState,
+    user_id: i64,
+    old_eventsub_id: String,
+) -> Result<PendingRotation, Error> {
+    if let Some(new_eventsub) = state.create_eventsub(user_id).await? {
+        return Ok(PendingRotation {
+            user_id,
+            old_eventsub_id,
+            new_eventsub,
+            old_deleted: false,
+        });
+    }
+
+    state.delete_eventsub(&old_eventsub_id).await?;
+    let new_eventsub = state.create_eventsub(user_id).await?.ok_or_else(|| {
+        Error::Twitch("Eventsub still conflicts after deleting the old one".to_string())
+    })?;
+
+    Ok(PendingRotation {
+        user_id,
+        old_eventsub_id,
+        new_eventsub,
+        old_deleted: true,
+    })
+}
+
+/// Waits until Twitch enabled the new eventsubs, then points the users to them and deletes
+/// the old ones. New eventsubs that fail or are not enabled in time are deleted again, their
+/// users keep the old eventsub for the next run. Returns the amount of rotated eventsubs and
+/// the failed ones.
+async fn finish_rotations(
+    state: &AppState,
+    mut pending: Vec<PendingRotation>,
+    fingerprint: &str,
+    enable_timeout: Duration,
+) -> CliResult<(usize, Vec<RotationFailure>)> {
+    let mut rotated = 0;
+    let mut failures = vec![];
+    let deadline = Instant::now() + enable_timeout;
+
+    loop {
+        // Without the old eventsub, the new one is tracked like any other right away
+        let statuses = if pending.iter().all(|r| r.old_deleted) {
+            HashMap::new()
+        } else {
+            state
+                .fetch_eventsubs()
+                .await?
+                .into_iter()
+                .map(|s| (s.id, s.status))
+                .collect::<HashMap<_, _>>()
+        };
+
+        let mut waiting = vec![];
+        for rotation in pending {
+            let status = statuses.get(&rotation.new_eventsub.id).map(String::as_str);
+
+            let result = match (rotation.old_deleted, status) {
+                (true, _) => {
+                    switch_eventsub(state, &rotation, &rotation.new_eventsub.status, fingerprint)
+                        .await
+                }
+                (false, Some(ENABLED_STATUS)) => {
+                    switch_eventsub(state, &rotation, ENABLED_STATUS, fingerprint).await
+                }
+                (false, Some(PENDING_STATUS)) if Instant::now() < deadline => {
+                    waiting.push(rotation);
+                    continue;
+                }
+                (false, status) => {
+                    let reason = match status {
+                        Some(PENDING_STATUS) => "New eventsub was not enabled in time".to_string(),
+                        status => {
+                            format!("New eventsub has status {}", status.unwrap_or("deleted"))
+                        }
+                    };
+
+                    match state.delete_eventsub(&rotation.new_eventsub.id).await {
+                        Ok(()) => Err(Error::Twitch(reason)),
+                        Err(e) => Err(Error::Twitch(format!("{reason}, deleting it failed: {e}"))),
+                    }
+                }
+            };
+
+            match result {
+                Ok(()) => rotated += 1,
+                Err(e) => failures.push(RotationFailure {
+                    target: rotation.target(),
+                    error: e.to_string(),
+                }),
+            }
+        }
+
+        if waiting.is_empty() {
+            return Ok((rotated, failures));
+        }
+
+        pending = waiting;
+        actix_web::rt::time::sleep(ENABLED_POLL_INTERVAL).await;
+    }
+}
+
+/// Points the user to the new eventsub and deletes the old one.
+async fn switch_eventsub(
+    state: &AppState,
+    rotation: &PendingRotation,
+    status: &str,
+    fingerprint: &str,
+) -> Result<(), Error> {
+    let new_eventsub_id = rotation.new_eventsub.id.as_str();
+
+    let updated = sqlx::query(
+        "UPDATE twitch_users SET eventsub_id = $2, eventsub_secret = $3 WHERE id = $1 AND eventsub_id = $4",
+    )
+    .bind(rotation.user_id)
+    .bind(new_eventsub_id)
+    .bind(fingerprint)
+    .bind(rotation.old_eventsub_id.as_str())
+    .execute(&state.db)
+    .await?
+    .rows_affected();
+
+    // The user was removed or moved to another eventsub meanwhile
+    if updated == 0 {
+        state.delete_eventsub(new_eventsub_id).await?;
+        return Ok(());
+    }
+
+    state
+        .record_eventsub_status(rotation.user_id, new_eventsub_id, status)
+        .await?;
+
+    if !rotation.old_deleted {
+        state.delete_eventsub(&rotation.old_eventsub_id).await?;
+    }
+
+    Ok(())
+}
+
+#[derive(Serialize)]
+struct CallbackReport {
+    callback_url: String,
+    migrations: Vec<CallbackMigration>,
+}
+
+impl Display for CallbackReport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        if self.migrations.is_empty() {
+            return write!(f, "All eventsubs use the callback {}", self.callback_url);
+        }
+
+        for (i, m) in self.migrations.iter().enumerate() {
+            if i > 0 {
+                writeln!(f)?;
+            }
+
+            let new_eventsub_id = m.new_eventsub_id.as_deref().unwrap_or("-");
+            match m.status {
+                MigrationStatus::Migrated => write!(
+                    f,
+                    "Moved user {} from eventsub {} to {new_eventsub_id}",
+                    m.user_id, m.old_eventsub_id
+                )?,
+                MigrationStatus::Pending => write!(
+                    f,
+                    "Eventsub {new_eventsub_id} of user {} is not enabled yet, run the migration again",
+                    m.user_id
+                )?,
+                MigrationStatus::Failed => write!(
+                    f,
+                    "Could not move user {} from eventsub {}: {}",
+                    m.user_id,
+                    m.old_eventsub_id,
+                    m.error.as_deref().unwrap_or("unknown error")
+                )?,
+            }
+        }
+
+        Ok(())
+    }
+}
+
+async fn migrate_callback(state: &AppState, enable_timeout: Duration) -> CliResult<CallbackReport> {
+    let EventsubTransport::Webhook { callback_url } = state.twitch.transport else {
+        return Err("Only eventsubs with the webhook transport have a callback".into());
+    };
+
+    Ok(CallbackReport {
+        callback_url: callback_url.clone(),
+        migrations: callback::migrate_callbacks(state, callback_url, enable_timeout).await?,
+    })
+}
+
+#[derive(Serialize)]
+struct Subscription {
+    notification_id: i32,
+    guild_id: i64,
+    user_id: i64,
+    username: String,
+    eventsub_id: String,
+    eventsub_status: String,
+}
+
+#[derive(Serialize)]
+#[serde(transparent)]
+struct Subscriptions(Vec<Subscription>);
+
+impl Display for Subscriptions {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:<8} {:<20} {:<12} {:<26} EVENTSUB",
+            "ID", "GUILD", "USER", "USERNAME"
+        )?;
+
+        for s in self.0.iter() {
+            write!(
+                f,
+                "\n{:<8} {:<20} {:<12} {:<26} {} ({})",
+                s.notification_id,
+                s.guild_id,
+                s.user_id,
+                s.username,
+                s.eventsub_id,
+                s.eventsub_status
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+async fn list_subscriptions(state: &AppState) -> CliResult<Subscriptions> {
+    let statuses = own_eventsubs(state)
+        .await?
+        .into_iter()
+        .map(|s| (s.id, s.status))
+        .collect::<HashMap<_, _>>();
+
+    let rows = sqlx::query(
+        "SELECT tn.id, tn.guild_id, tu.id AS user_id, tu.username, tu.eventsub_id FROM twitch_notifications tn INNER JOIN twitch_users tu ON tu.id = tn.user_id ORDER BY tn.id",
+    )
+    .fetch_all(&state.db)
+    .await?;
+
+    Ok(Subscriptions(
+        rows.iter()
+            .map(|row| {
+                let eventsub_id = row.get::<String, &str>("eventsub_id");
+
+                Subscription {
+                    notification_id: row.get("id"),
+                    guild_id: row.get("guild_id"),
+                    user_id: row.get("user_id"),
+                    username: row.get("username"),
+                    eventsub_status: statuses
+                        .get(&eventsub_id)
+                        .cloned()
+                        .unwrap_or_else(|| "missing".to_string()),
+                    eventsub_id,
+                }
+            })
+            .collect(),
+    ))
+}
+
+#[derive(Serialize)]
+struct Shard {
+    shard_id: String,
+    status: String,
+    method: String,
+    replica_id: Option<String>,
+}
+
+#[derive(Serialize)]
+struct Shards {
+    conduit_id: String,
+    shards: Vec<Shard>,
+}
+
+impl Display for Shards {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Conduit {}", self.conduit_id)?;
+        write!(
+            f,
+            "{:<8} {:<30} {:<10} REPLICA",
+            "SHARD", "STATUS", "METHOD"
+        )?;
+
+        for s in self.shards.iter() {
+            write!(
+                f,
+                "\n{:<8} {:<30} {:<10} {}",
+                s.shard_id,
+                s.status,
+                s.method,
+                s.replica_id.as_deref().unwrap_or("-")
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+async fn list_shards(state: &AppState) -> CliResult<Shards> {
+    let conduit_id = state
+        .conduit_id()
+        .await?
+        .ok_or("No conduit has been created yet")?;
+
+    let replicas =
+        sqlx::query("SELECT id, shard_id FROM eventsub_replicas WHERE shard_id IS NOT NULL")
+            .fetch_all(&state.db)
+            .await?
+            .iter()
+            .map(|row| {
+                (
+                    row.get::<i32, &str>("shard_id").to_string(),
+                    row.get::<String, &str>("id"),
+                )
+            })
+            .collect::<HashMap<_, _>>();
+
+    let shards = state
+        .fetch_conduit_shards(&conduit_id)
+        .await?
+        .into_iter()
+        .map(|s| Shard {
+            replica_id: replicas.get(&s.id).cloned(),
+            shard_id: s.id,
+            status: s.status,
+            method: s.transport.method,
+        })
+        .collect();
+
+    Ok(Shards { conduit_id, shards })
+}
+
+#[derive(Serialize)]
+struct Subscribed {
+    notification_id: i32,
+    user_id: i64,
+    login: String,
+    guild_id: i64,
+}
+
+impl Display for Subscribed {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Created notification {} for {} ({}) in guild {}",
+            self.notification_id, self.login, self.user_id, self.guild_id
+        )
+    }
+}
+
+async fn subscribe(state: &AppState, login: &str, guild_id: i64) -> CliResult<Subscribed> {
+    let user = state
+        .fetch_user_by_login(login)
+        .await?
+        .ok_or_else(|| format!("Twitch user {login} does not exist"))?;
+
+    let notification_id = state.create_notification(&user, guild_id).await?;
+
+    Ok(Subscribed {
+        notification_id,
+        user_id: user.id,
+        login: user.login,
+        guild_id,
+    })
+}
+
+#[derive(Serialize)]
+struct Unsubscribed {
+    notification_id: i32,
+}
+
+impl Display for Unsubscribed {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "Deleted notification {}", self.notification_id)
+    }
+}
+
+#[derive(Serialize)]
+struct Collected {
+    users: usize,
+    eventsub_messages: u64,
+    streams: u64,
+}
+
+impl Display for Collected {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Deleted {} unused users, {} expired eventsub message ids and {} old streams",
+            self.users, self.eventsub_messages, self.streams
+        )
+    }
+}
+
+async fn gc(state: &AppState) -> CliResult<Collected> {
+    let users = state.delete_unused_users().await?;
+    let eventsub_messages = state.delete_expired_eventsub_messages().await?;
+
+    let streams = sqlx::query(
+        "DELETE FROM twitch_streams ts WHERE ended_at < now() - make_interval(days => $1) AND NOT EXISTS (SELECT 1 FROM deliveries d WHERE d.stream_id = ts.id)",
+    )
+    .bind(STREAM_RETENTION_DAYS)
+    .execute(&state.db)
+    .await?
+    .rows_affected();
+
+    Ok(Collected {
+        users,
+        eventsub_messages,
+        streams,
+    })
+}
+
+#[derive(Serialize)]
+struct TestSent {
+    notification_id: i32,
+    user_id: i64,
+    live: bool,
+    delivered: bool,
+}
+
+impl Display for TestSent {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let stream = if self.live { "live" } else { "test" };
+        let result = if self.delivered {
+            "accepted"
+        } else {
+            "rejected"
+        };
+
+        write!(
+            f,
+            "Sent {stream} stream of user {} to the bot, it was {result}",
+            self.user_id
+        )
+    }
+}
+
+async fn send_test(state: &AppState, notification_id: i32) -> CliResult<TestSent> {
+    let user = sqlx::query(
+        "SELECT tu.id, tu.username FROM twitch_notifications tn INNER JOIN twitch_users tu ON tu.id = tn.user_id WHERE tn.id = $1",
+    )
+    .bind(notification_id)
+    .fetch_optional(&state.db)
+    .await?
+    .ok_or(Error::UnknownNotification)?;
+
+    let user_id = user.get::<i64, &str>("id");
+    let username = user.get::<String, &str>("username");
+
+    // The current stream is used if the user is live, so the bot shows real data
+    let live_stream = match state.fetch_streams(&[user_id]).await {
+        Ok(mut streams) => streams.remove(&user_id).flatten(),
+        Err(e) => {
+            warn!("Could not fetch the stream of user {user_id}, sending test data: {e}");
+            None
+        }
+    };
+    let live = live_stream.is_some();
+    let stream = live_stream.unwrap_or_else(|| StreamData {
+        id: 0,
+        user_id,
+        user_login: username.to_lowercase(),
+        user_name: username,
+        game_id: None,
+        game_name: String::new(),
+        title: "Test notification".to_string(),
+        viewer_count: 0,
+        started_at: Utc::now(),
+        thumbnail_url: String::new(),
+    });
+
+    let delivered = state
+        .send_to_bot(state.bot_url, &stream.bot_query(), None)
+        .await;
+
+    Ok(TestSent {
+        notification_id,
+        user_id,
+        live,
+        delivered,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn parse(args: &[&str]) -> Cli {
+        Cli::try_parse_from([&["notificator"], args].concat()).unwrap()
+    }
+
+    fn eventsub(id: &str, user_id: i64, status: &str) -> TwitchEventsub {
+        serde_json::from_value(json!({
+            "id": id,
+            "status": status,
+            "type": "stream.online",
+            "condition": { "broadcaster_user_id": user_id.to_string() },
+            "created_at": "2020-11-10T14:32:18.730260295Z",
+            "transport": { "method": "webhook", "callback": "https://example.com/_notify/twitch" },
+            "cost": 1,
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn serves_without_command() {
+        assert!(parse(&[]).command.is_none());
+        assert!(matches!(parse(&["serve"]).command, Some(Command::Serve)));
+    }
+
+    #[test]
+    fn parses_operations() {
+        let cli = parse(&["reconcile", "--dry-run", "--json"]);
+        assert!(cli.json);
+        assert!(matches!(
+            cli.command,
+            Some(Command::Operation(Operation::Reconcile { dry_run: true }))
+        ));
+
+        assert!(matches!(
+            parse(&["subscribe", "streamer", "--guild", "42"]).command,
+            Some(Command::Operation(Operation::Subscribe { login, guild: 42 })) if login == "streamer"
+        ));
+        assert!(matches!(
+            parse(&["unsubscribe", "7"]).command,
+            Some(Command::Operation(Operation::Unsubscribe {
+                notification_id: 7
+            }))
+        ));
+        assert!(matches!(
+            parse(&["send-test", "7"]).command,
+            Some(Command::Operation(Operation::SendTest {
+                notification_id: 7
+            }))
+        ));
+        assert!(matches!(
+            parse(&["rotate-secret"]).command,
+            Some(Command::Operation(Operation::RotateSecret {
+                batch_size: 20,
+                batch_delay: 1,
+                enable_timeout: 60
+            }))
+        ));
+    }
+
+    #[test]
+    fn rejects_invalid_arguments() {
+        assert!(Cli::try_parse_from(["notificator", "subscribe", "streamer"]).is_err());
+        assert!(Cli::try_parse_from(["notificator", "unsubscribe", "abc"]).is_err());
+        assert!(
+            Cli::try_parse_from(["notificator", "rotate-secret", "--batch-size", "0"]).is_err()
+        );
+    }
+
+    #[test]
+    fn plans_nothing_when_in_sync() {
+        let eventsubs = [eventsub("a", 1, ENABLED_STATUS)];
+        let users = [(1, "a".to_string())];
+
+        assert!(plan_reconcile(&eventsubs, &users).is_empty());
+    }
+
+    #[test]
+    fn plans_reconciliation() {
+        let eventsubs = [
+            eventsub("relinked", 1, ENABLED_STATUS),
+            eventsub("failed", 2, "webhook_callback_verification_failed"),
+            eventsub("unused", 3, ENABLED_STATUS),
+        ];
+        let users = [(1, "old".to_string()), (2, "failed".to_string())];
+
+        let actions = plan_reconcile(&eventsubs, &users)
+            .into_iter()
+            .map(|a| (a.action, a.user_id, a.eventsub_id))
+            .collect::<Vec<_>>();
+
+        assert!(matches!(
+            actions.as_slice(),
+            [
+                (ReconcileKind::Delete, 2, Some(failed)),
+                (ReconcileKind::Delete, 3, Some(unused)),
+                (ReconcileKind::Relink, 1, Some(relinked)),
+                (ReconcileKind::Subscribe, 2, None),
+            ] if failed == "failed" && unused == "unused" && relinked == "relinked"
+        ));
+    }
+
+    #[test]
+    fn prints_dry_run() {
+        let report = ReconcileReport {
+            dry_run: true,
+            actions: vec![ReconcileAction {
+                action: ReconcileKind::Subscribe,
+                user_id: 1,
+                eventsub_id: None,
+                error: None,
+            }],
+        };
+
+        assert_eq!(report.to_string(), "Would subscribe user 1 to eventsub -");
+        assert_eq!(
+            serde_json::to_value(&report).unwrap(),
+            json!({
+                "dry_run": true,
+                "actions": [{ "action": "subscribe", "user_id": 1, "eventsub_id": null, "error": null }],
+            })
+        );
+    }
+
+    #[test]
+    fn prints_remaining_rotation() {
+        let rotation = Rotation {
+            secret: "abc".to_string(),
+            rotated: 1,
+            failures: vec![RotationFailure {
+                target: "eventsub a of user 1".to_string(),
+                error: "Twitch is unavailable".to_string(),
+            }],
+            remaining: 1,
+            retired: vec![],
+        };
+
+        assert_eq!(
+            rotation.to_string(),
+            "Rotated 1 eventsubs to secret abc\nCould not rotate eventsub a of user 1: Twitch is unavailable\n1 eventsubs still use an old secret, run the rotation again"
+        );
+    }
+}
@@ -0,0 +1,441 @@
+use std::collections::HashSet;
+use std::time::Duration;
+
+use log::{error, info};
+use sqlx::pool::PoolConnection;
+use sqlx::{Connection, PgConnection, Postgres, Row};
+
+use crate::config::{EventsubTransport, ShardTransport};
+use crate::routes::twitch::structs::{
+    ConduitShardError, ConduitShardUpdate, EventsubTransportData,
+};
+use crate::structs::{AppState, Result};
+
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(10);
+/// Replicas without a heartbeat for this long are removed and their shard is reassigned.
+const REPLICA_TIMEOUT: Duration = Duration::from_secs(30);
+/// Advisory lock serializing the changes of the conduit between the replicas.
+const CONDUIT_LOCK_ID: i64 = 0x6e6f_7469_6669;
+
+/// Keeps the replica registered for a shard of the conduit. Every heartbeat also rebalances
+/// the shards, so replicas that come and go are picked up by the others.
+pub async fn run_conduit(state: AppState, replica_id: &'static str) {
+    info!("Joining eventsub conduit as replica {replica_id}");
+
+    let mut interval = actix_web::rt::time::interval(HEARTBEAT_INTERVAL);
+
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {}
+            _ = state.shutdown.triggered() => break,
+        }
+
+        if let Err(e) = sync_shards(&state, replica_id).await {
+            error!("Could not sync conduit shards: {e}");
+        }
+    }
+
+    // Hands the shard to the remaining replicas right away instead of after the timeout
+    if let Err(e) = leave(&state, replica_id).await {
+        error!("Could not leave conduit: {e}");
+    }
+
+    info!("Left eventsub conduit as replica {replica_id}");
+}
+
+/// Records the current transport of the replica and assigns the shards.
+pub(crate) async fn sync_shards(state: &AppState, replica_id: &str) -> Result<()> {
+    heartbeat(state, replica_id).await?;
+    rebalance(state).await
+}
+
+async fn heartbeat(state: &AppState, replica_id: &str) -> Result<()> {
+    let (callback_url, session_id) = match state.twitch.transport {
+        EventsubTransport::Conduit {
+            shard: ShardTransport::Webhook { callback_url },
+            ..
+        } => (Some(callback_url.as_str()), None),
+        _ => (None, state.websocket_session.id()),
+    };
+
+    // A replica with a new transport needs its shard to be pointed to it again
+    sqlx::query(
+        "INSERT INTO eventsub_replicas (id, callback_url, session_id) VALUES ($1, $2, $3) ON CONFLICT (id) DO UPDATE SET callback_url = $2, session_id = $3, last_seen_at = now(), shard_id = CASE WHEN eventsub_replicas.callback_url IS NOT DISTINCT FROM $2 AND eventsub_replicas.session_id IS NOT DISTINCT FROM $3 THEN eventsub_replicas.shard_id END",
+    )
+    .bind(replica_id)
+    .bind(callback_url)
+    .bind(session_id)
+    .execute(&state.db)
+    .await?;
+
+    Ok(())
+}
+
+async fn leave(state: &AppState, replica_id: &str) -> Result<()> {
+    sqlx::query("DELETE FROM eventsub_replicas WHERE id = $1")
+        .bind(replica_id)
+        .execute(&state.db)
+        .await?;
+
+    rebalance(state).await
+}
+
+struct Replica {
+    id: String,
+    callback_url: Option<String>,
+    session_id: Option<String>,
+    shard_id: Option<i32>,
+}
+
+impl Replica {
+    fn transport(&self, state: &AppState) -> EventsubTransportData {
+        match &self.callback_url {
+            Some(callback_url) => EventsubTransportData {
+                method: "webhook".to_owned(),
+                callback: Some(callback_url.clone()),
+                secret: Some(state.twitch.eventsub_secret.to_owned()),
+                ..EventsubTransportData::default()
+            },
+            None => EventsubTransportData {
+                method: "websocket".to_owned(),
+                session_id: self.session_id.clone(),
+                ..EventsubTransportData::default()
+            },
+        }
+    }
+}
+
+/// Session-level lock serializing the changes of the conduit. Unlike the lock of a
+/// transaction, it can be held across the Helix calls without keeping a transaction open.
+async fn lock_conduit(state: &AppState) -> Result<PoolConnection<Postgres>> {
+    let mut connection = state.db.acquire().await?;
+
+    sqlx::query("SELECT pg_advisory_lock($1)")
+        .bind(CONDUIT_LOCK_ID)
+        .execute(&mut connection)
+        .await?;
+
+    Ok(connection)
+}
+
+async fn unlock_conduit(mut connection: PoolConnection<Postgres>) {
+    let unlocked = sqlx::query("SELECT pg_advisory_unlock($1)")
+        .bind(CONDUIT_LOCK_ID)
+        .execute(&mut connection)
+        .await;
+
+    if let Err(e) = unlocked {
+        // Closing the session releases its locks
+        error!("Could not release the conduit lock, closing the connection: {e}");
+        drop(connection.detach());
+    }
+}
+
+/// Replicas that sent a heartbeat recently and have a transport.
+async fn live_replicas(connection: &mut PgConnection) -> Result<Vec<Replica>> {
+    let replicas = sqlx::query(
+        "SELECT id, callback_url, session_id, shard_id FROM eventsub_replicas WHERE last_seen_at >= now() - make_interval(secs => $1) AND (callback_url IS NOT NULL OR session_id IS NOT NULL) ORDER BY id",
+    )
+    .bind(REPLICA_TIMEOUT.as_secs_f64())
+    .fetch_all(connection)
+    .await?
+    .iter()
+    .map(|row| Replica {
+        id: row.get("id"),
+        callback_url: row.get("callback_url"),
+        session_id: row.get("session_id"),
+        shard_id: row.get("shard_id"),
+    })
+    .collect();
+
+    Ok(replicas)
+}
+
+/// Conduit that was created or resized on Twitch.
+struct ConduitChange {
+    id: String,
+    shard_count: i32,
+    created: bool,
+}
+
+/// Changes made on Twitch during a rebalance, which are recorded together afterwards.
+#[derive(Default)]
+struct Rebalance<'a> {
+    conduit: Option<ConduitChange>,
+    assignments: Vec<(&'a Replica, i32)>,
+}
+
+/// Resizes the conduit to one shard per live replica, creating it if needed, and points the
+/// shards of replicas that left or changed their transport to the others.
+async fn rebalance(state: &AppState) -> Result<()> {
+    let mut connection = lock_conduit(state).await?;
+    let result = rebalance_locked(state, &mut connection).await;
+    unlock_conduit(connection).await;
+
+    result
+}
+
+async fn rebalance_locked(state: &AppState, connection: &mut PgConnection) -> Result<()> {
+    let replicas = live_replicas(connection).await?;
+    let mut changes = Rebalance::default();
+
+    // The shards are kept without replicas, the next one to join takes them over
+    let result = if replicas.is_empty() {
+        Ok(())
+    } else {
+        update_conduit(state, connection, &replicas, &mut changes).await
+    };
+
+    // Also recorded if a later Helix call failed, Twitch keeps the changes made before
+    record_rebalance(connection, &changes).await?;
+
+    result
+}
+
+/// Applies the rebalance on Twitch, collecting the changes that succeeded.
+async fn update_conduit<'a>(
+    state: &AppState,
+    connection: &mut PgConnection,
+    replicas: &'a [Replica],
+    changes: &mut Rebalance<'a>,
+) -> Result<()> {
+    let shard_count = replicas.len() as i32;
+    let conduit = sqlx::query("SELECT id, shard_count FROM eventsub_conduits LIMIT 1")
+        .fetch_optional(connection)
+        .await?;
+
+    let conduit_id = match conduit {
+        Some(row) => {
+            let id = row.get::<String, &str>("id");
+
+            if row.get::<i32, &str>("shard_count") != shard_count {
+                state.update_conduit(&id, shard_count).await?;
+                changes.conduit = Some(ConduitChange {
+                    id: id.clone(),
+                    shard_count,
+                    created: false,
+                });
+
+                info!("Resized conduit {id} to {shard_count} shards");
+            }
+
+            id
+        }
+        None => {
+            let conduit = state.create_conduit(shard_count).await?;
+            changes.conduit = Some(ConduitChange {
+                id: conduit.id.clone(),
+                shard_count: conduit.shard_count,
+                created: true,
+            });
+
+            info!("Created conduit {} with {shard_count} shards", conduit.id);
+            conduit.id
+        }
+    };
+
+    let assignments = assign_shards(replicas, shard_count);
+    if assignments.is_empty() {
+        return Ok(());
+    }
+
+    let updates = assignments
+        .iter()
+        .map(|(replica, shard_id)| ConduitShardUpdate {
+            id: shard_id.to_string(),
+            transport: replica.transport(state),
+        })
+        .collect();
+    let failed = state.update_conduit_shards(&conduit_id, updates).await?;
+
+    for (replica, shard_id) in assignments {
+        if let Some(e) = failed.iter().find(|e| e.id == shard_id.to_string()) {
+            error!(
+                "Could not assign shard {shard_id} to replica {}: {} ({})",
+                replica.id, e.message, e.code
+            );
+            continue;
+        }
+
+        info!(
+            "Assigned shard {shard_id} of conduit {conduit_id} to replica {}",
+            replica.id
+        );
+        changes.assignments.push((replica, shard_id));
+    }
+
+    Ok(())
+}
+
+async fn record_rebalance(connection: &mut PgConnection, changes: &Rebalance<'_>) -> Result<()> {
+    let mut transaction = connection.begin().await?;
+
+    sqlx::query(
+        "DELETE FROM eventsub_replicas WHERE last_seen_at < now() - make_interval(secs => $1)",
+    )
+    .bind(REPLICA_TIMEOUT.as_secs_f64())
+    .execute(&mut transaction)
+    .await?;
+
+    match &changes.conduit {
+        Some(conduit) if conduit.created => {
+            sqlx::query("INSERT INTO eventsub_conduits (id, shard_count) VALUES ($1, $2)")
+                .bind(conduit.id.as_str())
+                .bind(conduit.shard_count)
+                .execute(&mut transaction)
+                .await?;
+        }
+        Some(conduit) => {
+            sqlx::query("UPDATE eventsub_conduits SET shard_count = $2 WHERE id = $1")
+                .bind(conduit.id.as_str())
+                .bind(conduit.shard_count)
+                .execute(&mut transaction)
+                .await?;
+        }
+        None => {}
+    }
+
+    for (replica, shard_id) in changes.assignments.iter() {
+        // A replica that changed its transport meanwhile is assigned again by the next rebalance
+        sqlx::query(
+            "UPDATE eventsub_replicas SET shard_id = $2 WHERE id = $1 AND callback_url IS NOT DISTINCT FROM $3 AND session_id IS NOT DISTINCT FROM $4",
+        )
+        .bind(replica.id.as_str())
+        .bind(shard_id)
+        .bind(replica.callback_url.as_deref())
+        .bind(replica.session_id.as_deref())
+        .execute(&mut transaction)
+        .await?;
+    }
+
+    transaction.commit().await?;
+
+    Ok(())
+}
+
+/// Points the webhook shards to their replicas again, so Twitch signs their messages with
+/// the current secret. Returns the amount of updated shards and the failed ones.
+pub(crate) async fn update_shard_secrets(
+    state: &AppState,
+) -> Result<(usize, Vec<ConduitShardError>)> {
+    let Some(conduit_id) = state.conduit_id().await? else {
+        return Ok((0, vec![]));
+    };
+
+    // Keeps the replicas from reassigning shards in between
+    let mut connection = lock_conduit(state).await?;
+    let result = update_shard_secrets_locked(state, &mut connection, &conduit_id).await;
+    unlock_conduit(connection).await;
+
+    result
+}
+
+async fn update_shard_secrets_locked(
+    state: &AppState,
+    connection: &mut PgConnection,
+    conduit_id: &str,
+) -> Result<(usize, Vec<ConduitShardError>)> {
+    let updates = live_replicas(connection)
+        .await?
+        .into_iter()
+        .filter(|replica| replica.callback_url.is_some())
+        .filter_map(|replica| {
+            Some(ConduitShardUpdate {
+                id: replica.shard_id?.to_string(),
+                transport: replica.transport(state),
+            })
+        })
+        .collect::<Vec<_>>();
+
+    let count = updates.len();
+    let failed = if count > 0 {
+        state.update_conduit_shards(conduit_id, updates).await?
+    } else {
+        vec![]
+    };
+
+    Ok((count - failed.len(), failed))
+}
+
+/// Keeps the shards of replicas that still fit into the conduit and hands the free ones to
+/// the others. Returns the replicas that need a new shard.
+fn assign_shards(replicas: &[Replica], shard_count: i32) -> Vec<(&Replica, i32)> {
+    let mut taken = HashSet::new();
+    let mut unassigned = vec![];
+
+    for replica in replicas {
+        match replica.shard_id {
+            Some(id) if id < shard_count && taken.insert(id) => {}
+            _ => unassigned.push(replica),
+        }
+    }
+
+    let free = (0..shard_count).filter(|id| !taken.contains(id));
+
+    unassigned.into_iter().zip(free).collect()
+}
+
+impl AppState {
+    /// Returns the conduit the eventsubs are registered with, once a replica created it.
+    pub(crate) async fn conduit_id(&self) -> Result<Option<String>> {
+        let row = sqlx::query("SELECT id FROM eventsub_conduits LIMIT 1")
+            .fetch_optional(&self.db)
+            .await?;
+
+        Ok(row.map(|row| row.get::<String, &str>("id")))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn replica(id: &str, shard_id: Option<i32>) -> Replica {
+        Replica {
+            id: id.to_string(),
+            callback_url: Some("https://localhost/_notify/twitch".to_string()),
+            session_id: None,
+            shard_id,
+        }
+    }
+
+    fn assigned(replicas: &[Replica], shard_count: i32) -> Vec<(&str, i32)> {
+        assign_shards(replicas, shard_count)
+            .into_iter()
+            .map(|(r, shard_id)| (r.id.as_str(), shard_id))
+            .collect()
+    }
+
+    #[test]
+    fn assigns_shards_to_new_replicas() {
+        let replicas = [replica("a", None), replica("b", None)];
+
+        assert_eq!(assigned(&replicas, 2), [("a", 0), ("b", 1)]);
+    }
+
+    #[test]
+    fn keeps_shards_of_existing_replicas() {
+        let replicas = [
+            replica("a", Some(1)),
+            replica("b", None),
+            replica("c", Some(0)),
+        ];
+
+        assert_eq!(assigned(&replicas, 3), [("b", 2)]);
+    }
+
+    #[test]
+    fn moves_replicas_off_removed_shards() {
+        // The replica of shard 0 left, the conduit shrinks to one shard
+        let replicas = [replica("b", Some(1))];
+
+        assert_eq!(assigned(&replicas, 1), [("b", 0)]);
+    }
+
+    #[test]
+    fn reassigns_duplicate_shards() {
+        let replicas = [replica("a", Some(0)), replica("b", Some(0))];
+
+        assert_eq!(assigned(&replicas, 2), [("b", 1)]);
+    }
+}
@@ -0,0 +1,593 @@
+use std::env;
+use std::fmt::Display;
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::time::Duration;
+
+use rand::Rng;
+use serde::Deserialize;
+use url::Url;
+
+use crate::logging;
+
+/// Environment variable pointing to an optional TOML config file.
+const CONFIG_FILE_VAR: &str = "NOTIFICATOR_CONFIG";
+const DEFAULT_WEBSOCKET_URL: &str = "wss://eventsub.wss.twitch.tv/ws";
+
+pub struct Config {
+    pub postgres_dsn: String,
+    pub twitch: TwitchConfig,
+    pub bot_url: String,
+    /// Endpoint of the bot told about the removed accounts of users guilds are notified
+    /// about. Removed users are only logged without it.
+    pub bot_user_removed_url: Option<String>,
+    pub bind_address: SocketAddr,
+    pub workers: usize,
+    pub db_max_connections: u32,
+    pub db_acquire_timeout: Duration,
+    pub http_timeout: Duration,
+    pub poll_interval: Option<Duration>,
+    pub shutdown_timeout: Duration,
+    pub delivery_concurrency: usize,
+    pub delivery_queue_size: usize,
+    pub delivery_rate_limit: u32,
+}
+
+pub struct TwitchConfig {
+    pub client_id: String,
+    pub client_secret: String,
+    pub eventsub_secret: String,
+    /// Secrets of a rotation that are still accepted, until they are retired.
+    pub previous_eventsub_secrets: Vec<String>,
+    pub transport: EventsubTransport,
+    pub redirect_url: String,
+    /// Percentages of the maximum eventsub cost to warn at.
+    pub eventsub_cost_thresholds: Vec<u8>,
+    /// Sends a signed challenge through the public callback at startup.
+    pub callback_self_test: bool,
+}
+
+/// How Twitch delivers the eventsub messages to us.
+pub enum EventsubTransport {
+    /// Twitch posts the messages to the public callback route.
+    Webhook { callback_url: String },
+    /// We keep a websocket connection open to Twitch, for setups without a public callback.
+    /// Twitch only accepts websocket subscriptions created with a user access token, which
+    /// is refreshed with the refresh token.
+    Websocket { url: String, refresh_token: String },
+    /// Subscriptions are registered with a conduit, which spreads the messages over one shard
+    /// per replica. Replicas without an id get a random one on every start.
+    Conduit {
+        replica_id: String,
+        shard: ShardTransport,
+    },
+}
+
+/// How a replica receives the messages of its conduit shard.
+pub enum ShardTransport {
+    Webhook { callback_url: String },
+    Websocket { url: String },
+}
+
+/// Config as read from the file, every key is named like its environment variable in lowercase.
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct RawConfig {
+    postgres_dsn: Option<String>,
+    twitch_client_id: Option<String>,
+    twitch_client_secret: Option<String>,
+    twitch_eventsub_secret: Option<String>,
+    twitch_eventsub_previous_secrets: Option<String>,
+    twitch_eventsub_transport: Option<String>,
+    twitch_callback_url: Option<String>,
+    twitch_eventsub_websocket_url: Option<String>,
+    twitch_user_refresh_token: Option<String>,
+    twitch_conduit_shard_transport: Option<String>,
+    replica_id: Option<String>,
+    twitch_redirect_url: Option<String>,
+    twitch_eventsub_cost_thresholds: Option<String>,
+    twitch_callback_self_test: Option<bool>,
+    bot_url: Option<String>,
+    bot_user_removed_url: Option<String>,
+    bind_address: Option<String>,
+    workers: Option<usize>,
+    db_max_connections: Option<u32>,
+    db_acquire_timeout: Option<u64>,
+    http_timeout: Option<u64>,
+    twitch_poll_interval: Option<u64>,
+    shutdown_timeout: Option<u64>,
+    delivery_concurrency: Option<usize>,
+    delivery_queue_size: Option<usize>,
+    delivery_rate_limit: Option<u32>,
+}
+
+impl Config {
+    /// Loads the config from the file in `NOTIFICATOR_CONFIG`, if set, and the environment.
+    /// Environment variables take precedence. All problems are collected and returned at once.
+    pub fn load() -> Result<Self, Vec<String>> {
+        let mut errors = vec![];
+
+        let raw = match env::var(CONFIG_FILE_VAR) {
+            Ok(path) => match std::fs::read_to_string(&path) {
+                Ok(content) => toml::from_str::<RawConfig>(&content).unwrap_or_else(|e| {
+                    errors.push(format!("Could not parse config file {path}: {e}"));
+                    RawConfig::default()
+                }),
+                Err(e) => {
+                    errors.push(format!("Could not read config file {path}: {e}"));
+                    RawConfig::default()
+                }
+            },
+            Err(_) => RawConfig::default(),
+        };
+
+        let config = Self::from_sources(raw, errors, &|name| env::var(name).ok())?;
+        config.register_secrets();
+
+        Ok(config)
+    }
+
+    /// Keeps the credentials of the config out of the logs of the service and the commands.
+    fn register_secrets(&self) {
+        logging::register_secret(&self.twitch.client_secret);
+        logging::register_secret(&self.twitch.eventsub_secret);
+        for secret in self.twitch.previous_eventsub_secrets.iter() {
+            logging::register_secret(secret);
+        }
+        if let EventsubTransport::Websocket { refresh_token, .. } = &self.twitch.transport {
+            logging::register_secret(refresh_token);
+        }
+        if let Some(password) = Url::parse(&self.postgres_dsn)
+            .ok()
+            .and_then(|url| url.password().map(str::to_string))
+        {
+            logging::register_secret(&password);
+        }
+    }
+
+    /// Builds the config from the file values and the environment, given as a lookup by
+    /// variable name.
+    fn from_sources(
+        raw: RawConfig,
+        errors: Vec<String>,
+        env: &dyn Fn(&str) -> Option<String>,
+    ) -> Result<Self, Vec<String>> {
+        let mut loader = Loader { errors, env };
+
+        let postgres_dsn = loader.required("POSTGRES_DSN", raw.postgres_dsn);
+        let client_id = loader.required("TWITCH_CLIENT_ID", raw.twitch_client_id);
+        let client_secret = loader.required("TWITCH_CLIENT_SECRET", raw.twitch_client_secret);
+        let eventsub_secret = loader.required("TWITCH_EVENTSUB_SECRET", raw.twitch_eventsub_secret);
+        let previous_eventsub_secrets = loader
+            .optional(
+                "TWITCH_EVENTSUB_PREVIOUS_SECRETS",
+                raw.twitch_eventsub_previous_secrets,
+            )
+            .map(|v| {
+                v.split(',')
+                    .map(|s| s.trim().to_string())
+                    .filter(|s| !s.is_empty())
+                    .collect::<Vec<_>>()
+            })
+            .unwrap_or_default();
+        let method = loader
+            .optional("TWITCH_EVENTSUB_TRANSPORT", raw.twitch_eventsub_transport)
+            .unwrap_or_else(|| "webhook".to_string());
+        let shard_method = loader
+            .optional(
+                "TWITCH_CONDUIT_SHARD_TRANSPORT",
+                raw.twitch_conduit_shard_transport,
+            )
+            .unwrap_or_else(|| "webhook".to_string());
+        if !["webhook", "websocket", "conduit"].contains(&method.as_str()) {
+            loader.error("TWITCH_EVENTSUB_TRANSPORT must be one of webhook, websocket, conduit");
+        }
+        if !["webhook", "websocket"].contains(&shard_method.as_str()) {
+            loader.error("TWITCH_CONDUIT_SHARD_TRANSPORT must be either webhook or websocket");
+        }
+
+        let conduit = method == "conduit";
+        let callback_url = if method == "webhook" || (conduit && shard_method == "webhook") {
+            loader.required("TWITCH_CALLBACK_URL", raw.twitch_callback_url)
+        } else {
+            None
+        };
+        let websocket_url = if method == "websocket" || (conduit && shard_method == "websocket") {
+            let url = loader
+                .optional(
+                    "TWITCH_EVENTSUB_WEBSOCKET_URL",
+                    raw.twitch_eventsub_websocket_url,
+                )
+                .unwrap_or_else(|| DEFAULT_WEBSOCKET_URL.to_string());
+
+            Some(url)
+        } else {
+            None
+        };
+        let refresh_token = if method == "websocket" {
+            loader.required("TWITCH_USER_REFRESH_TOKEN", raw.twitch_user_refresh_token)
+        } else {
+            None
+        };
+        let replica_id = loader.optional("REPLICA_ID", raw.replica_id);
+        let redirect_url = loader.required("TWITCH_REDIRECT_URL", raw.twitch_redirect_url);
+        let eventsub_cost_thresholds = loader
+            .optional(
+                "TWITCH_EVENTSUB_COST_THRESHOLDS",
+                raw.twitch_eventsub_cost_thresholds,
+            )
+            .map(|v| {
+                v.split(',')
+                    .map(|t| {
+                        t.trim()
+                            .parse::<u8>()
+                            .ok()
+                            .filter(|t| (1..=100).contains(t))
+                    })
+                    .collect::<Option<Vec<_>>>()
+            });
+        let callback_self_test = loader.parsed(
+            "TWITCH_CALLBACK_SELF_TEST",
+            raw.twitch_callback_self_test.map(Ok),
+        );
+        let bot_url = loader.required("BOT_URL", raw.bot_url);
+        let bot_user_removed_url =
+            loader.optional("BOT_USER_REMOVED_URL", raw.bot_user_removed_url);
+        let bind_address = loader.parsed(
+            "BIND_ADDRESS",
+            raw.bind_address
+                .map(|v| SocketAddr::from_str(&v).map_err(|e| e.to_string())),
+        );
+        let workers = loader.number("WORKERS", raw.workers);
+        let db_max_connections = loader.number("DB_MAX_CONNECTIONS", raw.db_max_connections);
+        let db_acquire_timeout = loader.number("DB_ACQUIRE_TIMEOUT", raw.db_acquire_timeout);
+        let http_timeout = loader.number("HTTP_TIMEOUT", raw.http_timeout);
+        let poll_interval = loader.number("TWITCH_POLL_INTERVAL", raw.twitch_poll_interval);
+        let shutdown_timeout = loader.number("SHUTDOWN_TIMEOUT", raw.shutdown_timeout);
+        let delivery_concurrency = loader.number("DELIVERY_CONCURRENCY", raw.delivery_concurrency);
+        let delivery_queue_size = loader.number("DELIVERY_QUEUE_SIZE", raw.delivery_queue_size);
+        let delivery_rate_limit = loader.number("DELIVERY_RATE_LIMIT", raw.delivery_rate_limit);
+
+        if let Some(secret) = &eventsub_secret {
+            if !(10..=100).contains(&secret.len()) {
+                loader.error("TWITCH_EVENTSUB_SECRET must be between 10 and 100 characters");
+            }
+        }
+        if previous_eventsub_secrets
+            .iter()
+            .any(|s| !(10..=100).contains(&s.len()))
+        {
+            loader.error(
+                "TWITCH_EVENTSUB_PREVIOUS_SECRETS must only contain secrets between 10 and 100 characters",
+            );
+        }
+
+        loader.url("TWITCH_CALLBACK_URL", callback_url.as_deref(), &["https"]);
+        loader.url(
+            "TWITCH_EVENTSUB_WEBSOCKET_URL",
+            websocket_url.as_deref(),
+            &["ws", "wss"],
+        );
+        loader.url(
+            "TWITCH_REDIRECT_URL",
+            redirect_url.as_deref(),
+            &["http", "https"],
+        );
+        loader.url("BOT_URL", bot_url.as_deref(), &["http", "https"]);
+        loader.url(
+            "BOT_USER_REMOVED_URL",
+            bot_user_removed_url.as_deref(),
+            &["http", "https"],
+        );
+
+        if workers == Some(0) {
+            loader.error("WORKERS must be at least 1");
+        }
+        if db_max_connections == Some(0) {
+            loader.error("DB_MAX_CONNECTIONS must be at least 1");
+        }
+        if delivery_concurrency == Some(0) {
+            loader.error("DELIVERY_CONCURRENCY must be at least 1");
+        }
+        if delivery_queue_size == Some(0) {
+            loader.error("DELIVERY_QUEUE_SIZE must be at least 1");
+        }
+        if delivery_rate_limit == Some(0) {
+            loader.error("DELIVERY_RATE_LIMIT must be at least 1 per second");
+        }
+        if poll_interval == Some(0) {
+            loader.error("TWITCH_POLL_INTERVAL must be at least 1 second");
+        }
+
+        if eventsub_cost_thresholds == Some(None) {
+            loader.error(
+                "TWITCH_EVENTSUB_COST_THRESHOLDS must be a comma separated list of percentages between 1 and 100",
+            );
+        }
+
+        if !loader.errors.is_empty() {
+            return Err(loader.errors);
+        }
+
+        let transport = match method.as_str() {
+            "websocket" => EventsubTransport::Websocket {
+                url: websocket_url.unwrap(),
+                refresh_token: refresh_token.unwrap(),
+            },
+            "conduit" => EventsubTransport::Conduit {
+                replica_id: replica_id
+                    .unwrap_or_else(|| hex::encode(rand::thread_rng().gen::<[u8; 8]>())),
+                shard: match websocket_url {
+                    Some(url) => ShardTransport::Websocket { url },
+                    None => ShardTransport::Webhook {
+                        callback_url: callback_url.unwrap(),
+                    },
+                },
+            },
+            _ => EventsubTransport::Webhook {
+                callback_url: callback_url.unwrap(),
+            },
+        };
+
+        Ok(Self {
+            postgres_dsn: postgres_dsn.unwrap(),
+            twitch: TwitchConfig {
+                client_id: client_id.unwrap(),
+                client_secret: client_secret.unwrap(),
+                eventsub_secret: eventsub_secret.unwrap(),
+                previous_eventsub_secrets,
+                transport,
+                redirect_url: redirect_url.unwrap(),
+                eventsub_cost_thresholds: eventsub_cost_thresholds
+                    .flatten()
+                    .unwrap_or_else(|| vec![80, 95]),
+                callback_self_test: callback_self_test.unwrap_or(false),
+            },
+            bot_url: bot_url.unwrap(),
+            bot_user_removed_url,
+            bind_address: bind_address.unwrap_or_else(|| SocketAddr::from(([0, 0, 0, 0], 3000))),
+            workers: workers.unwrap_or(2),
+            db_max_connections: db_max_connections.unwrap_or(5),
+            db_acquire_timeout: Duration::from_secs(db_acquire_timeout.unwrap_or(30)),
+            http_timeout: Duration::from_secs(http_timeout.unwrap_or(10)),
+            poll_interval: poll_interval.map(Duration::from_secs),
+            shutdown_timeout: Duration::from_secs(shutdown_timeout.unwrap_or(30)),
+            delivery_concurrency: delivery_concurrency.unwrap_or(8),
+            delivery_queue_size: delivery_queue_size.unwrap_or(100),
+            delivery_rate_limit: delivery_rate_limit.unwrap_or(10),
+        })
+    }
+}
+
+struct Loader<'a> {
+    errors: Vec<String>,
+    env: &'a dyn Fn(&str) -> Option<String>,
+}
+
+impl Loader<'_> {
+    fn error(&mut self, message: &str) {
+        self.errors.push(message.to_string());
+    }
+
+    fn optional(&self, name: &str, file_value: Option<String>) -> Option<String> {
+        (self.env)(name).or(file_value)
+    }
+
+    fn required(&mut self, name: &str, file_value: Option<String>) -> Option<String> {
+        let value = self.optional(name, file_value);
+
+        if value.is_none() {
+            self.errors.push(format!("{name} is not set but required"));
+        }
+
+        value
+    }
+
+    fn parsed<T>(&mut self, name: &str, file_value: Option<Result<T, String>>) -> Option<T>
+    where
+        T: FromStr,
+        T::Err: Display,
+    {
+        let value = match (self.env)(name) {
+            Some(v) => Some(v.parse::<T>().map_err(|e| e.to_string())),
+            None => file_value,
+        };
+
+        match value {
+            Some(Ok(v)) => Some(v),
+            Some(Err(e)) => {
+                self.errors.push(format!("{name} is invalid: {e}"));
+                None
+            }
+            None => None,
+        }
+    }
+
+    fn number<T>(&mut self, name: &str, file_value: Option<T>) -> Option<T>
+    where
+        T: FromStr,
+        T::Err: Display,
+    {
+        self.parsed(name, file_value.map(Ok))
+    }
+
+    fn url(&mut self, name: &str, value: Option<&str>, schemes: &[&str]) {
+        let Some(value) = value else {
+            return;
+        };
+
+        match Url::parse(value) {
+            Ok(url) if !schemes.contains(&url.scheme()) => self.errors.push(format!(
+                "{name} must use one of the schemes {}",
+                schemes.join(", ")
+            )),
+            Ok(url) if url.host().is_none() => {
+                self.errors.push(format!("{name} must contain a host"))
+            }
+            Ok(_) => {}
+            Err(e) => self.errors.push(format!("{name} is not a valid url: {e}")),
+        }
+    }
+}
+
+#[cfg(test)]
+impl Config {
+    /// Config of the webhook transport with short timeouts, for state that never reaches the
+    /// database or Twitch.
+    pub(crate) fn test() -> Self {
+        Self {
+            postgres_dsn: "postgres://localhost:1/notificator".to_string(),
+            twitch: TwitchConfig {
+                client_id: "client-id".to_string(),
+                client_secret: "client-secret".to_string(),
+                eventsub_secret: "s3cRe7s3cRe7".to_string(),
+                previous_eventsub_secrets: vec![],
+                transport: EventsubTransport::Webhook {
+                    callback_url: "https://localhost/_notify/twitch".to_string(),
+                },
+                redirect_url: "https://localhost/".to_string(),
+                eventsub_cost_thresholds: vec![80, 95],
+                callback_self_test: false,
+            },
+            bot_url: "http://localhost/bot".to_string(),
+            bot_user_removed_url: None,
+            bind_address: ([127, 0, 0, 1], 3000).into(),
+            workers: 1,
+            db_max_connections: 1,
+            db_acquire_timeout: Duration::from_secs(1),
+            http_timeout: Duration::from_secs(1),
+            poll_interval: None,
+            shutdown_timeout: Duration::from_secs(1),
+            delivery_concurrency: 1,
+            delivery_queue_size: 1,
+            delivery_rate_limit: 1,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+
+    const REQUIRED: [(&str, &str); 7] = [
+        ("POSTGRES_DSN", "postgres://localhost/notificator"),
+        ("TWITCH_CLIENT_ID", "client-id"),
+        ("TWITCH_CLIENT_SECRET", "client-secret"),
+        ("TWITCH_EVENTSUB_SECRET", "s3cRe7s3cRe7"),
+        ("TWITCH_CALLBACK_URL", "https://localhost/_notify/twitch"),
+        ("TWITCH_REDIRECT_URL", "https://localhost/"),
+        ("BOT_URL", "http://localhost/bot"),
+    ];
+
+    /// Loads the config from the given environment only, with the required variables set
+    /// unless they are overridden.
+    fn load(vars: &[(&str, &str)]) -> Result<Config, Vec<String>> {
+        let mut env = REQUIRED
+            .iter()
+            .chain(vars)
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect::<HashMap<_, _>>();
+        // Empty values unset the variable
+        env.retain(|_, v| !v.is_empty());
+
+        Config::from_sources(RawConfig::default(), vec![], &|name| env.get(name).cloned())
+    }
+
+    fn errors(vars: &[(&str, &str)]) -> Vec<String> {
+        load(vars).err().expect("config should be invalid")
+    }
+
+    #[test]
+    fn loads_defaults() {
+        let config = load(&[]).ok().unwrap();
+
+        assert_eq!(config.workers, 2);
+        assert_eq!(config.bot_user_removed_url, None);
+        assert_eq!(config.twitch.eventsub_cost_thresholds, [80, 95]);
+        assert!(!config.twitch.callback_self_test);
+        assert!(matches!(
+            config.twitch.transport,
+            EventsubTransport::Webhook { .. }
+        ));
+    }
+
+    #[test]
+    fn prefers_environment_over_file() {
+        let raw = RawConfig {
+            workers: Some(4),
+            bot_url: Some("http://file/bot".to_string()),
+            ..RawConfig::default()
+        };
+        let env = REQUIRED
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .chain([("WORKERS".to_string(), "8".to_string())])
+            .collect::<HashMap<_, _>>();
+
+        let config = Config::from_sources(raw, vec![], &|name| env.get(name).cloned())
+            .ok()
+            .unwrap();
+
+        assert_eq!(config.workers, 8);
+        assert_eq!(config.bot_url, "http://localhost/bot");
+    }
+
+    #[test]
+    fn reports_all_errors_at_once() {
+        let errors = errors(&[
+            ("POSTGRES_DSN", ""),
+            ("BOT_URL", ""),
+            ("WORKERS", "many"),
+            ("DELIVERY_CONCURRENCY", "0"),
+        ]);
+
+        assert_eq!(
+            errors,
+            [
+                "POSTGRES_DSN is not set but required",
+                "BOT_URL is not set but required",
+                "WORKERS is invalid: invalid digit found in string",
+                "DELIVERY_CONCURRENCY must be at least 1",
+            ]
+        );
+    }
+
+    #[test]
+    fn checks_secret_length() {
+        let too_short = "s".repeat(9);
+        let too_long = "s".repeat(101);
+
+        assert!(load(&[("TWITCH_EVENTSUB_SECRET", &"s".repeat(10))]).is_ok());
+        assert!(load(&[("TWITCH_EVENTSUB_SECRET", &"s".repeat(100))]).is_ok());
+        for secret in [too_short, too_long] {
+            assert_eq!(
+                errors(&[("TWITCH_EVENTSUB_SECRET", &secret)]),
+                ["TWITCH_EVENTSUB_SECRET must be between 10 and 100 characters"]
+            );
+        }
+        assert_eq!(
+            errors(&[("TWITCH_EVENTSUB_PREVIOUS_SECRETS", "s3cRe7s3cRe7, short")]),
+            ["TWITCH_EVENTSUB_PREVIOUS_SECRETS must only contain secrets between 10 and 100 characters"]
+        );
+    }
+
+    #[test]
+    fn checks_url_schemes() {
+        assert_eq!(
+            errors(&[
+                ("TWITCH_CALLBACK_URL", "http://localhost/_notify/twitch"),
+                ("BOT_URL", "ftp://localhost/bot"),
+                ("TWITCH_REDIRECT_URL", "not a url"),
+                ("BOT_USER_REMOVED_URL", "ws://localhost/removed"),
+            ]),
+            [
+                "TWITCH_CALLBACK_URL must use one of the schemes https",
+                "TWITCH_REDIRECT_URL is not a valid url: relative URL without a base",
+                "BOT_URL must use one of the schemes http, https",
+                "BOT_USER_REMOVED_URL must use one of the schemes http, https",
+            ]
+        );
+    }
+}
@@ -0,0 +1,260 @@
+use std::time::Duration;
+
+use actix_web::web::Data;
+use async_trait::async_trait;
+use chrono::SecondsFormat;
+use log::{info, warn};
+use sqlx::Row;
+use url::form_urlencoded;
+
+use crate::dispatcher::{BotEndpoint, Delivery, DELIVERY_LEASE};
+use crate::eventsub::EventHandler;
+use crate::logging;
+use crate::metrics::DELIVERIES_SPILLED;
+use crate::routes::twitch::structs::{StreamData, StreamOnlineEvent, TwitchNotificationPayload};
+use crate::structs::{AppState, Result};
+
+/// Delay before the first retry when Helix does not list a stream yet, doubled every attempt.
+const STREAM_DATA_RETRY_DELAY: Duration = Duration::from_secs(2);
+/// Total time to wait for Helix before falling back to a notification built from the event.
+const STREAM_DATA_MAX_WAIT: Duration = Duration::from_secs(120);
+
+impl StreamData {
+    /// Query string the bot expects for a go-live, with all values percent-encoded.
+    pub fn bot_query(&self) -> String {
+        form_urlencoded::Serializer::new(String::new())
+            .append_pair("user_id", &self.user_id.to_string())
+            .append_pair("user_name", &self.user_login)
+            .append_pair("game_name", &self.game_name)
+            .append_pair("viewer_count", &self.viewer_count.to_string())
+            .append_pair(
+                "started_at",
+                &self.started_at.to_rfc3339_opts(SecondsFormat::Secs, true),
+            )
+            .append_pair("thumbnail_url", &self.thumbnail_url)
+            .append_pair("title", &self.title)
+            .finish()
+    }
+}
+
+/// Delays between the lookups of a stream, doubling from the initial one until the total
+/// reaches the maximum wait.
+fn backoff_delays(initial: Duration, max_wait: Duration) -> Vec<Duration> {
+    let mut delays = vec![];
+    let mut delay = initial;
+    let mut waited = Duration::ZERO;
+
+    while waited < max_wait {
+        delays.push(delay);
+        waited += delay;
+        delay *= 2;
+    }
+
+    delays
+}
+
+/// Announces the streams that went online.
+pub struct StreamOnlineHandler;
+
+#[async_trait(?Send)]
+impl EventHandler for StreamOnlineHandler {
+    type Event = StreamOnlineEvent;
+
+    async fn handle(
+        &self,
+        state: &Data<AppState>,
+        notification: TwitchNotificationPayload<StreamOnlineEvent>,
+    ) -> Result<()> {
+        state.announce_online_event(&notification.event).await
+    }
+}
+
+impl AppState {
+    /// Announces a `stream.online` event. Helix often lists a stream only some time after
+    /// the event has been sent, so the lookup is retried with backoff while the stream is
+    /// missing or Helix fails temporarily. Otherwise a degraded notification is sent from
+    /// the event data.
+    pub async fn announce_online_event(&self, event: &StreamOnlineEvent) -> Result<()> {
+        let user_id = event.broadcaster_user_id;
+        let mut delays = backoff_delays(STREAM_DATA_RETRY_DELAY, STREAM_DATA_MAX_WAIT).into_iter();
+
+        loop {
+            match self.fetch_stream_data(user_id).await {
+                Ok(Some(stream_data)) => return self.announce_stream(&stream_data).await,
+                Ok(None) => info!("Helix does not list the stream of user {user_id} yet"),
+                Err(e) if e.is_transient() => {
+                    info!("Stream data for user {user_id} is not available yet: {e}")
+                }
+                Err(e) => {
+                    warn!("Could not fetch stream data for user {user_id}: {e}");
+                    break;
+                }
+            }
+
+            let Some(delay) = delays.next() else {
+                break;
+            };
+
+            // On shutdown send what is known right away, so the delivery is persisted
+            tokio::select! {
+                _ = actix_web::rt::time::sleep(delay) => {}
+                _ = self.shutdown.triggered() => break,
+            }
+        }
+
+        warn!("Sending degraded notification for user {user_id}, Helix did not return the stream");
+
+        self.announce_stream(&StreamData::from(event)).await
+    }
+
+    /// Announces a stream to the bot. Every stream is only announced once, no matter
+    /// if it was noticed through an eventsub notification or the poller.
+    pub async fn announce_stream(&self, stream_data: &StreamData) -> Result<()> {
+        let query = stream_data.bot_query();
+
+        match self
+            .open_stream_session(stream_data, query.as_str())
+            .await?
+        {
+            Some(delivery_id) => {
+                self.queue_delivery(delivery_id, BotEndpoint::StreamOnline, query)
+                    .await?
+            }
+            None => info!(
+                "Stream {} of user {} was already announced",
+                stream_data.id, stream_data.user_id
+            ),
+        }
+
+        Ok(())
+    }
+
+    /// Hands a persisted delivery to the dispatcher. If its queue is full, the delivery is
+    /// left in the database for the next claim.
+    pub(crate) async fn queue_delivery(
+        &self,
+        delivery_id: i64,
+        endpoint: BotEndpoint,
+        query: String,
+    ) -> Result<()> {
+        let delivery = Delivery {
+            id: delivery_id,
+            endpoint,
+            query,
+            context: logging::current_context(),
+        };
+
+        if !self.deliveries.try_enqueue(delivery) {
+            // The delivery is picked up from the database once a dispatcher has capacity
+            warn!("Delivery queue is full, delivery {delivery_id} is sent later");
+            DELIVERIES_SPILLED.inc();
+
+            sqlx::query("UPDATE deliveries SET next_attempt_at = now() WHERE id = $1")
+                .bind(delivery_id)
+                .execute(&self.db)
+                .await?;
+        }
+
+        Ok(())
+    }
+
+    /// Records the stream as live and persists its delivery. Returns the id of the delivery,
+    /// or `None` if the stream has already been recorded.
+    async fn open_stream_session(
+        &self,
+        stream_data: &StreamData,
+        query: &str,
+    ) -> Result<Option<i64>> {
+        let mut transaction = self.db.begin().await?;
+
+        let inserted = sqlx::query(
+            "INSERT INTO twitch_streams (id, user_id) VALUES ($1, $2) ON CONFLICT (id) DO NOTHING RETURNING id",
+        )
+        .bind(stream_data.id)
+        .bind(stream_data.user_id)
+        .fetch_optional(&mut transaction)
+        .await?;
+
+        if inserted.is_none() {
+            return Ok(None);
+        }
+
+        // A new stream implies the previous one of this user has ended
+        sqlx::query(
+            "UPDATE twitch_streams SET ended_at = now() WHERE user_id = $1 AND id <> $2 AND ended_at IS NULL",
+        )
+        .bind(stream_data.user_id)
+        .bind(stream_data.id)
+        .execute(&mut transaction)
+        .await?;
+
+        // Leased right away, as it is handed to the queue directly
+        let delivery_id = sqlx::query(
+            "INSERT INTO deliveries (stream_id, query, next_attempt_at) VALUES ($1, $2, now() + make_interval(secs => $3)) RETURNING id",
+        )
+        .bind(stream_data.id)
+        .bind(query)
+        .bind(DELIVERY_LEASE.as_secs_f64())
+        .fetch_one(&mut transaction)
+        .await?
+        .get::<i64, &str>("id");
+
+        transaction.commit().await?;
+
+        Ok(Some(delivery_id))
+    }
+
+    /// Ends the open stream sessions of the given users that were opened longer than `grace`
+    /// ago.
+    pub async fn close_stream_sessions(&self, user_ids: &[i64], grace: Duration) -> Result<()> {
+        if user_ids.is_empty() {
+            return Ok(());
+        }
+
+        sqlx::query(
+            "UPDATE twitch_streams SET ended_at = now() WHERE user_id = ANY($1) AND ended_at IS NULL AND created_at < now() - make_interval(secs => $2)",
+        )
+        .bind(user_ids)
+        .bind(grace.as_secs_f64())
+        .execute(&self.db)
+        .await?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{TimeZone, Utc};
+
+    use super::*;
+
+    #[test]
+    fn doubles_delays_until_max_wait() {
+        let delays = backoff_delays(Duration::from_secs(2), Duration::from_secs(120))
+            .iter()
+            .map(Duration::as_secs)
+            .collect::<Vec<_>>();
+
+        assert_eq!(delays, [2, 4, 8, 16, 32, 64]);
+    }
+
+    #[test]
+    fn builds_degraded_stream_from_event() {
+        let started_at = Utc.with_ymd_and_hms(2020, 10, 11, 10, 11, 12).unwrap();
+        let event = StreamOnlineEvent {
+            id: 9001,
+            broadcaster_user_id: 1337,
+            broadcaster_user_login: "cool_user".to_string(),
+            broadcaster_user_name: "Cool_User".to_string(),
+            started_at,
+        };
+
+        let query = StreamData::from(&event).bot_query();
+
+        assert_eq!(
+            query,
+            "user_id=1337&user_name=cool_user&game_name=&viewer_count=0&started_at=2020-10-11T10%3A11%3A12Z&thumbnail_url=&title="
+        );
+    }
+}
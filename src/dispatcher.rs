@@ -0,0 +1,469 @@
+use std::cell::Cell;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::time::Duration;
+
+use actix_web::rt::time::{self, Instant};
+use log::{error, info, warn};
+use sqlx::{PgPool, Row};
+use tokio::sync::{mpsc, Semaphore};
+
+use crate::logging::{self, LogContext};
+use crate::metrics::{
+    BOT_DELIVERIES, BOT_DELIVERY_ATTEMPTS, DELIVERY_QUEUE_LENGTH, RESULT_FAILURE, RESULT_SUCCESS,
+};
+use crate::structs::{AppState, Result};
+
+/// How long a delivery is reserved for the process sending it before others may pick it up.
+pub const DELIVERY_LEASE: Duration = Duration::from_secs(300);
+/// Interval in which deliveries that did not fit into the queue or failed are picked up.
+const DRAIN_INTERVAL: Duration = Duration::from_secs(10);
+/// Deliveries failing this often are given up.
+const MAX_DELIVERY_ATTEMPTS: i32 = 10;
+
+/// A persisted notification waiting to be sent to the bot.
+pub struct Delivery {
+    pub id: i64,
+    pub endpoint: BotEndpoint,
+    pub query: String,
+    pub context: LogContext,
+}
+
+/// Endpoint of the bot a delivery is sent to. Go-lives are announced to the bot url, other
+/// changes have their own endpoint, so a bot that does not know them can't take them for
+/// go-lives.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BotEndpoint {
+    StreamOnline,
+    UserRemoved,
+}
+
+impl BotEndpoint {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            BotEndpoint::StreamOnline => "stream_online",
+            BotEndpoint::UserRemoved => "user_removed",
+        }
+    }
+
+    fn parse(endpoint: &str) -> Option<Self> {
+        [BotEndpoint::StreamOnline, BotEndpoint::UserRemoved]
+            .into_iter()
+            .find(|e| e.as_str() == endpoint)
+    }
+}
+
+pub struct DispatcherSettings {
+    pub concurrency: usize,
+    pub rate_limit: u32,
+}
+
+/// Bounded queue of deliveries shared by all workers.
+#[derive(Clone)]
+pub struct DeliveryQueue {
+    sender: mpsc::Sender<Delivery>,
+}
+
+impl DeliveryQueue {
+    pub fn new(size: usize) -> (Self, mpsc::Receiver<Delivery>) {
+        let (sender, receiver) = mpsc::channel(size);
+
+        (Self { sender }, receiver)
+    }
+
+    /// Hands the delivery to the dispatcher. Returns `false` if the queue is full or no
+    /// dispatcher is running.
+    pub fn try_enqueue(&self, delivery: Delivery) -> bool {
+        let queued = self.sender.try_send(delivery).is_ok();
+        DELIVERY_QUEUE_LENGTH.set(self.queued() as i64);
+
+        queued
+    }
+
+    /// Amount of deliveries waiting in the queue.
+    pub fn queued(&self) -> usize {
+        self.sender.max_capacity() - self.sender.capacity()
+    }
+}
+
+/// Limits the rate of requests to the bot. All deliveries go to the one bot URL, so a single
+/// limiter covers every request of the worker.
+struct RateLimiter {
+    interval: Duration,
+    next_slot: Cell<Option<Instant>>,
+}
+
+impl RateLimiter {
+    fn new(per_second: u32) -> Self {
+        Self {
+            interval: Duration::from_secs(1) / per_second,
+            next_slot: Cell::new(None),
+        }
+    }
+
+    /// Waits until the next request may be sent.
+    async fn wait(&self) {
+        let now = Instant::now();
+        let slot = self.next_slot.get().map_or(now, |s| s.max(now));
+        self.next_slot.set(Some(slot + self.interval));
+
+        time::sleep_until(slot).await;
+    }
+}
+
+/// Sends the queued deliveries to the bot with a bounded concurrency. Deliveries which did
+/// not fit into the queue or failed are picked up from the database.
+pub async fn run_dispatcher(
+    state: AppState,
+    mut receiver: mpsc::Receiver<Delivery>,
+    settings: DispatcherSettings,
+) {
+    let state = Rc::new(state);
+    let permits = Arc::new(Semaphore::new(settings.concurrency));
+    let limiter = Rc::new(RateLimiter::new(settings.rate_limit));
+    let mut drain = time::interval(DRAIN_INTERVAL);
+    // Deliveries taken from the queue or the database that were not sent before the shutdown
+    let mut released = vec![];
+
+    'dispatching: loop {
+        tokio::select! {
+            Some(delivery) = receiver.recv() => {
+                DELIVERY_QUEUE_LENGTH.set(state.deliveries.queued() as i64);
+                if let Some(delivery) = dispatch(&state, &permits, &limiter, delivery).await {
+                    released.push(delivery.id);
+                    break;
+                }
+            }
+            _ = drain.tick() => {
+                let available = permits.available_permits();
+                if available == 0 {
+                    continue;
+                }
+
+                match claim_deliveries(&state.db, available as i64).await {
+                    Ok(deliveries) => {
+                        let mut deliveries = deliveries.into_iter();
+                        while let Some(delivery) = deliveries.next() {
+                            if let Some(delivery) = dispatch(&state, &permits, &limiter, delivery).await {
+                                released.push(delivery.id);
+                                released.extend(deliveries.map(|d| d.id));
+                                break 'dispatching;
+                            }
+                        }
+                    }
+                    Err(e) => error!("Could not load pending deliveries: {e}"),
+                }
+            }
+            _ = state.shutdown.triggered() => break,
+        }
+    }
+
+    // Queued deliveries are released, so the next process sends them right away
+    receiver.close();
+    while let Ok(delivery) = receiver.try_recv() {
+        released.push(delivery.id);
+    }
+
+    if !released.is_empty() {
+        info!("Releasing {} unsent deliveries", released.len());
+
+        let released =
+            sqlx::query("UPDATE deliveries SET next_attempt_at = now() WHERE id = ANY($1)")
+                .bind(&released)
+                .execute(&state.db)
+                .await;
+
+        if let Err(e) = released {
+            error!("Could not release queued deliveries: {e}");
+        }
+    }
+
+    info!("Delivery dispatcher stopped");
+}
+
+/// Sends the delivery once a permit is free. Returns the delivery if the shutdown was
+/// triggered before, so it can be released.
+async fn dispatch(
+    state: &Rc<AppState>,
+    permits: &Arc<Semaphore>,
+    limiter: &Rc<RateLimiter>,
+    delivery: Delivery,
+) -> Option<Delivery> {
+    // Waiting here keeps further deliveries in the queue, which spills once it is full
+    let permit = tokio::select! {
+        permit = permits.clone().acquire_owned() => permit.ok()?,
+        _ = state.shutdown.triggered() => return Some(delivery),
+    };
+
+    let state = state.clone();
+    let limiter = limiter.clone();
+    let context = delivery.context.clone();
+
+    state
+        .clone()
+        .shutdown
+        .spawn(logging::with_context(context, async move {
+            limiter.wait().await;
+
+            if let Err(e) = send_delivery(&state, &delivery).await {
+                error!("Could not update delivery {}: {e}", delivery.id);
+            }
+
+            drop(permit);
+        }));
+
+    None
+}
+
+impl AppState {
+    /// Url of the endpoint, `None` if it is not configured.
+    pub fn bot_endpoint_url(&self, endpoint: BotEndpoint) -> Option<&'static str> {
+        match endpoint {
+            BotEndpoint::StreamOnline => Some(self.bot_url),
+            BotEndpoint::UserRemoved => self.bot_user_removed_url,
+        }
+    }
+
+    /// Sends a notification to an endpoint of the bot. Returns `true` if the bot accepted it.
+    pub async fn send_to_bot(&self, url: &str, query: &str, request_id: Option<&str>) -> bool {
+        let mut req = self.client.get(format!("{url}?{query}"));
+        if let Some(request_id) = request_id {
+            req = req.insert_header((logging::REQUEST_ID_HEADER, request_id));
+        }
+
+        BOT_DELIVERY_ATTEMPTS.inc();
+
+        matches!(req.send().await, Ok(res) if res.status().is_success())
+    }
+}
+
+async fn send_delivery(state: &AppState, delivery: &Delivery) -> Result<()> {
+    let request_id = delivery.context.request_id.as_deref();
+
+    let Some(url) = state.bot_endpoint_url(delivery.endpoint) else {
+        warn!(
+            "Dropping delivery {}, the {} endpoint of the bot is not configured anymore",
+            delivery.id,
+            delivery.endpoint.as_str()
+        );
+
+        sqlx::query("DELETE FROM deliveries WHERE id = $1")
+            .bind(delivery.id)
+            .execute(&state.db)
+            .await?;
+
+        return Ok(());
+    };
+
+    if state.send_to_bot(url, &delivery.query, request_id).await {
+        BOT_DELIVERIES.with_label_values(&[RESULT_SUCCESS]).inc();
+
+        sqlx::query("DELETE FROM deliveries WHERE id = $1")
+            .bind(delivery.id)
+            .execute(&state.db)
+            .await?;
+
+        return Ok(());
+    }
+
+    BOT_DELIVERIES.with_label_values(&[RESULT_FAILURE]).inc();
+
+    let attempts = sqlx::query(
+        "UPDATE deliveries SET attempts = attempts + 1, next_attempt_at = now() + (attempts + 1) * interval '1 minute' WHERE id = $1 RETURNING attempts",
+    )
+    .bind(delivery.id)
+    .fetch_one(&state.db)
+    .await?
+    .get::<i32, &str>("attempts");
+
+    if gives_up(attempts) {
+        error!(
+            "Giving up delivery {} after {attempts} attempts",
+            delivery.id
+        );
+
+        sqlx::query("DELETE FROM deliveries WHERE id = $1")
+            .bind(delivery.id)
+            .execute(&state.db)
+            .await?;
+    } else {
+        warn!("An error occurred while sending twitch notification to bot, attempt {attempts}");
+    }
+
+    Ok(())
+}
+
+fn gives_up(attempts: i32) -> bool {
+    attempts >= MAX_DELIVERY_ATTEMPTS
+}
+
+/// Leases deliveries that are due, skipping the ones other processes are working on.
+async fn claim_deliveries(db: &PgPool, limit: i64) -> Result<Vec<Delivery>> {
+    let rows = sqlx::query(
+        "UPDATE deliveries SET next_attempt_at = now() + make_interval(secs => $2) WHERE id IN (SELECT id FROM deliveries WHERE next_attempt_at <= now() ORDER BY id LIMIT $1 FOR UPDATE SKIP LOCKED) RETURNING id, endpoint, query",
+    )
+    .bind(limit)
+    .bind(DELIVERY_LEASE.as_secs_f64())
+    .fetch_all(db)
+    .await?;
+
+    let mut deliveries = vec![];
+    for row in rows {
+        let id = row.get::<i64, &str>("id");
+        let endpoint = row.get::<String, &str>("endpoint");
+
+        // Only written by us, an unknown endpoint is left for a newer version
+        let Some(endpoint) = BotEndpoint::parse(&endpoint) else {
+            warn!("Delivery {id} is for the unknown bot endpoint {endpoint}");
+            continue;
+        };
+
+        deliveries.push(Delivery {
+            id,
+            endpoint,
+            query: row.get("query"),
+            context: LogContext::default(),
+        });
+    }
+
+    Ok(deliveries)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use crate::config::Config;
+    use crate::test_utils::test_state_with;
+    use actix_web::{web, App, HttpResponse, HttpServer};
+
+    use super::*;
+
+    fn delivery(id: i64) -> Delivery {
+        Delivery {
+            id,
+            endpoint: BotEndpoint::StreamOnline,
+            query: format!("id={id}"),
+            context: LogContext::default(),
+        }
+    }
+
+    fn state(bot_url: String) -> Rc<AppState> {
+        Rc::new(test_state_with(Config {
+            bot_url,
+            ..Config::test()
+        }))
+    }
+
+    #[actix_web::test]
+    async fn sends_other_changes_to_their_own_endpoint() {
+        let state = state("http://localhost/bot".to_string());
+        assert_eq!(
+            state.bot_endpoint_url(BotEndpoint::StreamOnline),
+            Some("http://localhost/bot")
+        );
+        assert_eq!(state.bot_endpoint_url(BotEndpoint::UserRemoved), None);
+
+        let state = test_state_with(Config {
+            bot_user_removed_url: Some("http://localhost/removed".to_string()),
+            ..Config::test()
+        });
+        assert_eq!(
+            state.bot_endpoint_url(BotEndpoint::UserRemoved),
+            Some("http://localhost/removed")
+        );
+        assert_eq!(
+            BotEndpoint::parse(BotEndpoint::UserRemoved.as_str()),
+            Some(BotEndpoint::UserRemoved)
+        );
+    }
+
+    #[test]
+    fn spills_when_queue_is_full() {
+        let (queue, _receiver) = DeliveryQueue::new(1);
+
+        assert!(queue.try_enqueue(delivery(1)));
+        assert!(!queue.try_enqueue(delivery(2)));
+        assert_eq!(queue.queued(), 1);
+    }
+
+    #[test]
+    fn spills_without_dispatcher() {
+        let (queue, receiver) = DeliveryQueue::new(1);
+        drop(receiver);
+
+        assert!(!queue.try_enqueue(delivery(1)));
+    }
+
+    #[test]
+    fn gives_up_after_max_attempts() {
+        assert!(!gives_up(MAX_DELIVERY_ATTEMPTS - 1));
+        assert!(gives_up(MAX_DELIVERY_ATTEMPTS));
+    }
+
+    #[actix_web::test]
+    async fn limits_request_rate() {
+        let limiter = RateLimiter::new(100);
+        let start = Instant::now();
+
+        for _ in 0..5 {
+            limiter.wait().await;
+        }
+
+        assert!(start.elapsed() >= Duration::from_millis(40));
+    }
+
+    #[actix_web::test]
+    async fn releases_delivery_waiting_for_permit_on_shutdown() {
+        let state = state("http://localhost/bot".to_string());
+        let permits = Arc::new(Semaphore::new(0));
+        let limiter = Rc::new(RateLimiter::new(10));
+        state.shutdown.trigger();
+
+        let released = dispatch(&state, &permits, &limiter, delivery(1)).await;
+
+        assert_eq!(released.map(|d| d.id), Some(1));
+    }
+
+    #[actix_web::test]
+    async fn caps_concurrent_deliveries() {
+        static RUNNING: AtomicUsize = AtomicUsize::new(0);
+        static MAX_RUNNING: AtomicUsize = AtomicUsize::new(0);
+        static RECEIVED: AtomicUsize = AtomicUsize::new(0);
+
+        async fn bot() -> HttpResponse {
+            let running = RUNNING.fetch_add(1, Ordering::SeqCst) + 1;
+            MAX_RUNNING.fetch_max(running, Ordering::SeqCst);
+            time::sleep(Duration::from_millis(50)).await;
+            RUNNING.fetch_sub(1, Ordering::SeqCst);
+            RECEIVED.fetch_add(1, Ordering::SeqCst);
+
+            HttpResponse::Ok().finish()
+        }
+
+        let server = HttpServer::new(|| App::new().route("/bot", web::get().to(bot)))
+            .bind(("127.0.0.1", 0))
+            .unwrap()
+            .workers(1)
+            .disable_signals();
+        let addr = server.addrs()[0];
+        let server = server.run();
+        let handle = server.handle();
+        actix_web::rt::spawn(server);
+
+        let state = state(format!("http://{addr}/bot"));
+        let permits = Arc::new(Semaphore::new(2));
+        let limiter = Rc::new(RateLimiter::new(1000));
+
+        for id in 0..6 {
+            assert!(dispatch(&state, &permits, &limiter, delivery(id))
+                .await
+                .is_none());
+        }
+        assert!(state.shutdown.wait_idle(Duration::from_secs(5)).await);
+        handle.stop(false).await;
+
+        assert_eq!(RECEIVED.load(Ordering::SeqCst), 6);
+        assert_eq!(MAX_RUNNING.load(Ordering::SeqCst), 2);
+    }
+}
@@ -1,15 +1,41 @@
 use actix_web::dev::ServiceResponse;
 use actix_web::http::{header, StatusCode};
-use actix_web::middleware::ErrorHandlerResponse;
+use actix_web::middleware::{ErrorHandlerResponse, ErrorHandlers};
 
 use crate::structs::ErrorResponse;
 
-pub fn not_found_handler<B>(
+/// Replaces the plain text bodies actix uses for routing and payload errors with an
+/// [`ErrorResponse`], so clients get the same JSON as for errors of the handlers.
+pub fn error_handlers<B: 'static>() -> ErrorHandlers<B> {
+    ErrorHandlers::new()
+        .handler(StatusCode::NOT_FOUND, default_error_handler)
+        .handler(StatusCode::METHOD_NOT_ALLOWED, default_error_handler)
+        .handler(StatusCode::PAYLOAD_TOO_LARGE, default_error_handler)
+}
+
+fn default_error_handler<B>(
     mut res: ServiceResponse<B>,
 ) -> actix_web::Result<ErrorHandlerResponse<B>> {
+    let is_json = res
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .is_some_and(|v| v.as_bytes().starts_with(b"application/json"));
+    if is_json {
+        return Ok(ErrorHandlerResponse::Response(res.map_into_left_body()));
+    }
+
+    let code = res.status();
+    let (error_code, message) = match code {
+        StatusCode::NOT_FOUND => ("not_found", "Cannot find this path"),
+        StatusCode::METHOD_NOT_ALLOWED => ("method_not_allowed", "Method is not allowed"),
+        StatusCode::PAYLOAD_TOO_LARGE => ("payload_too_large", "Request body is too large"),
+        _ => ("internal_error", "Internal server error"),
+    };
+
     let response_struct = ErrorResponse {
-        code: StatusCode::NOT_FOUND,
-        message: "Cannot find this path or this method".to_string(),
+        code,
+        error_code,
+        message: message.to_string(),
     };
 
     res.headers_mut().insert(
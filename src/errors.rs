@@ -1,5 +1,6 @@
 use std::fmt::Debug;
 
+use awc::error::SendRequestError;
 use log::warn;
 
 #[derive(Debug, derive_more::Display)]
@@ -8,6 +9,11 @@ pub enum Error {
     Awc(awc::error::SendRequestError),
     #[display(fmt = "Twitch API returned an error: {}", _0)]
     Twitch(String),
+    #[display(
+        fmt = "Twitch API is temporarily unavailable, it responded with {}",
+        _0
+    )]
+    TwitchUnavailable(u16),
     #[display(fmt = "{}", _0)]
     InternalServer(String),
     #[display(fmt = "Could not lock mutex")]
@@ -15,10 +21,80 @@ pub enum Error {
     #[display(fmt = "Error executing database query: {:?}", _0)]
     SQLx(sqlx::Error),
     BadRequest(String),
+    #[display(fmt = "{}", _0)]
+    InvalidBody(String),
+    #[display(fmt = "Request body is too large")]
+    PayloadTooLarge,
+    #[display(fmt = "Invalid header {}", _0)]
+    InvalidHeader(&'static str),
+    #[display(fmt = "Invalid signature provided")]
+    InvalidSignature,
+    #[display(fmt = "Message is too old to be handled")]
+    ExpiredMessage,
+    #[display(fmt = "Invalid OAuth authorization code")]
+    InvalidOauthCode,
+    #[display(fmt = "Notification not found")]
+    UnknownNotification,
+    #[display(fmt = "Method is not allowed")]
+    MethodNotAllowed,
     #[display(fmt = "Notification already exists")]
-    Conflict
+    Conflict,
+    #[display(fmt = "Server is shutting down")]
+    ShuttingDown,
+    #[display(fmt = "The eventsub budget on Twitch is exhausted")]
+    EventsubBudgetExceeded,
+}
+
+impl Error {
+    /// Stable identifier of the error returned to clients as `error_code`.
+    pub fn error_code(&self) -> &'static str {
+        match self {
+            Error::Awc(_) | Error::Twitch(_) | Error::TwitchUnavailable(_) => "twitch_unavailable",
+            Error::InternalServer(_) | Error::Mutex | Error::SQLx(_) => "internal_error",
+            Error::BadRequest(_) => "bad_request",
+            Error::InvalidBody(_) => "invalid_body",
+            Error::PayloadTooLarge => "payload_too_large",
+            Error::InvalidHeader(_) => "invalid_header",
+            Error::InvalidSignature => "invalid_signature",
+            Error::ExpiredMessage => "expired_message",
+            Error::InvalidOauthCode => "invalid_oauth_code",
+            Error::UnknownNotification => "unknown_notification",
+            Error::MethodNotAllowed => "method_not_allowed",
+            Error::Conflict => "notification_exists",
+            Error::ShuttingDown => "shutting_down",
+            Error::EventsubBudgetExceeded => "eventsub_budget_exceeded",
+        }
+    }
+
+    /// Message returned to clients. Details of upstream and internal errors are only logged.
+    pub fn public_message(&self) -> String {
+        match self {
+            Error::Awc(_) | Error::Twitch(_) | Error::TwitchUnavailable(_) => {
+                "The Twitch API is not available".to_string()
+            }
+            Error::InternalServer(_) | Error::Mutex | Error::SQLx(_) => {
+                "Internal server error".to_string()
+            }
+            _ => self.to_string(),
+        }
+    }
+
+    /// Whether the request may succeed when it is sent again, e.g. after a timeout or while
+    /// Twitch is rate limiting or failing.
+    pub fn is_transient(&self) -> bool {
+        matches!(
+            self,
+            Error::Awc(
+                SendRequestError::Timeout
+                    | SendRequestError::Connect(_)
+                    | SendRequestError::Send(_)
+            ) | Error::TwitchUnavailable(_)
+        )
+    }
 }
 
+impl std::error::Error for Error {}
+
 impl From<awc::error::SendRequestError> for Error {
     fn from(value: awc::error::SendRequestError) -> Self {
         warn!(target: "http", "Error sending http request: {value:?}");
@@ -36,6 +112,47 @@ impl From<sqlx::Error> for Error {
 impl From<serde_json::Error> for Error {
     fn from(value: serde_json::Error) -> Self {
         warn!("Could not parse JSON body: {}", value.to_string());
-        Self::BadRequest("Cannot parse given body.".to_string())
+        Self::InvalidBody("Cannot parse given body.".to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn retries_only_transient_errors() {
+        assert!(Error::Awc(SendRequestError::Timeout).is_transient());
+        assert!(Error::TwitchUnavailable(503).is_transient());
+        assert!(Error::TwitchUnavailable(429).is_transient());
+        assert!(!Error::Twitch("Unexpected response body".to_string()).is_transient());
+        assert!(!Error::InvalidBody("Cannot parse given body.".to_string()).is_transient());
+    }
+
+    #[test]
+    fn hides_details_of_internal_errors() {
+        let error = Error::SQLx(sqlx::Error::PoolTimedOut);
+        assert_eq!(error.error_code(), "internal_error");
+        assert_eq!(error.public_message(), "Internal server error");
+
+        let error = Error::Twitch("client id is invalid".to_string());
+        assert_eq!(error.error_code(), "twitch_unavailable");
+        assert_eq!(error.public_message(), "The Twitch API is not available");
+    }
+
+    #[test]
+    fn returns_messages_of_client_errors() {
+        let error = Error::BadRequest("Invalid id".to_string());
+        assert_eq!(error.error_code(), "bad_request");
+        assert_eq!(error.public_message(), "Invalid id");
+
+        let error = Error::InvalidHeader("twitch-eventsub-message-id");
+        assert_eq!(error.error_code(), "invalid_header");
+        assert_eq!(
+            error.public_message(),
+            "Invalid header twitch-eventsub-message-id"
+        );
+
+        assert_eq!(Error::Conflict.error_code(), "notification_exists");
     }
 }
@@ -0,0 +1,639 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+
+use actix_web::dev::Payload;
+use actix_web::web::{Bytes, Data};
+use actix_web::{FromRequest, HttpRequest};
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use futures_util::future::LocalBoxFuture;
+use hmac::{Hmac, Mac};
+use log::{error, warn};
+use serde::de::DeserializeOwned;
+use sha2::{Digest, Sha256};
+
+use crate::config::EventsubTransport;
+use crate::delivery::StreamOnlineHandler;
+use crate::errors::Error;
+use crate::logging;
+use crate::metrics::{
+    EVENTSUB_MESSAGES, OUTCOME_BAD_SIGNATURE, OUTCOME_DUPLICATE, OUTCOME_EXPIRED, OUTCOME_VERIFIED,
+};
+use crate::routes::twitch::structs::{TwitchNotificationPayload, TwitchSubscriptionStatus};
+use crate::structs::{AppState, Result};
+
+const SIGNATURE_PREFIX: &str = "sha256=";
+/// Twitch doesn't retry messages older than this, older ones are rejected as replays.
+const MESSAGE_MAX_AGE: chrono::Duration = chrono::Duration::minutes(10);
+const MESSAGE_CLEANUP_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+/// Body of the notifications of a subscription type and version.
+pub trait EventsubEvent: DeserializeOwned + 'static {
+    const TYPE: &'static str;
+    const VERSION: &'static str;
+}
+
+/// Handles the verified notifications of one subscription type and version. The
+/// notification is already recorded as handled when the handler runs.
+#[async_trait(?Send)]
+pub trait EventHandler: Send + Sync + 'static {
+    type Event: EventsubEvent;
+
+    async fn handle(
+        &self,
+        state: &Data<AppState>,
+        notification: TwitchNotificationPayload<Self::Event>,
+    ) -> Result<()>;
+}
+
+/// Parses the raw event for the typed handler, so a handler can be picked by the type of the
+/// subscription.
+trait RawEventHandler: Send + Sync {
+    fn prepare(
+        self: Arc<Self>,
+        state: Data<AppState>,
+        notification: TwitchNotificationPayload,
+    ) -> Result<LocalBoxFuture<'static, Result<()>>>;
+}
+
+impl<H: EventHandler> RawEventHandler for H {
+    fn prepare(
+        self: Arc<Self>,
+        state: Data<AppState>,
+        notification: TwitchNotificationPayload,
+    ) -> Result<LocalBoxFuture<'static, Result<()>>> {
+        let notification = TwitchNotificationPayload {
+            event: serde_json::from_str::<H::Event>(notification.event.get())?,
+            subscription: notification.subscription,
+        };
+
+        Ok(Box::pin(
+            async move { self.handle(&state, notification).await },
+        ))
+    }
+}
+
+/// Handlers of the notifications, keyed by subscription type and version.
+#[derive(Default)]
+pub struct EventHandlers {
+    handlers: HashMap<(&'static str, &'static str), Arc<dyn RawEventHandler>>,
+}
+
+impl EventHandlers {
+    /// Registry with the handlers of the events the service subscribes to.
+    pub fn builtin() -> Self {
+        Self::default().register(StreamOnlineHandler)
+    }
+
+    /// Registers the handler, replacing the one of the same type and version.
+    pub fn register<H: EventHandler>(mut self, handler: H) -> Self {
+        self.handlers
+            .insert((H::Event::TYPE, H::Event::VERSION), Arc::new(handler));
+        self
+    }
+
+    /// Parses the event of the notification and returns the future of its handler. Returns
+    /// `None` for types without a handler, those are only acknowledged.
+    pub(crate) fn prepare(
+        &self,
+        state: &Data<AppState>,
+        notification: TwitchNotificationPayload,
+    ) -> Result<Option<LocalBoxFuture<'static, Result<()>>>> {
+        let subscription = &notification.subscription;
+        let key = (subscription.kind.as_str(), subscription.version.as_str());
+
+        let Some(handler) = self.handlers.get(&key) else {
+            warn!(
+                "No handler for {} version {} notifications, acknowledging without handling",
+                subscription.kind, subscription.version
+            );
+            return Ok(None);
+        };
+
+        handler
+            .clone()
+            .prepare(state.clone(), notification)
+            .map(Some)
+    }
+}
+
+/// Metadata of a verified eventsub message, taken from its headers.
+pub struct EventsubMetadata {
+    pub message_id: String,
+    pub message_type: String,
+    pub timestamp: DateTime<Utc>,
+}
+
+/// Body of an eventsub message, parsed according to the message type.
+pub trait EventsubPayload: Sized {
+    fn parse(state: &Data<AppState>, message_type: &str, body: &[u8]) -> Result<Self>;
+}
+
+impl<T: DeserializeOwned> EventsubPayload for T {
+    fn parse(_state: &Data<AppState>, _message_type: &str, body: &[u8]) -> Result<Self> {
+        Ok(serde_json::from_slice(body)?)
+    }
+}
+
+/// Extracts an eventsub webhook message after verifying its headers, timestamp and signature.
+/// The payload is only handed out by [`VerifiedEventsub::once`], so no route can process the
+/// retries of a message twice.
+pub struct VerifiedEventsub<T> {
+    pub metadata: EventsubMetadata,
+    payload: T,
+}
+
+impl<T> VerifiedEventsub<T> {
+    /// Processes the payload unless the message has been handled before, see [`handle_once`].
+    /// Returns `None` for messages that have been handled before. Verification challenges are
+    /// processed every time, as Twitch expects every one of them to be answered.
+    pub async fn once<F, R>(
+        self,
+        log: &impl MessageLog,
+        process: impl FnOnce(T) -> F,
+    ) -> Result<Option<R>>
+    where
+        F: Future<Output = Result<R>>,
+    {
+        let type_label = message_type_label(&self.metadata.message_type);
+        if type_label == TwitchSubscriptionStatus::WebhookCallbackVerification.as_str() {
+            return process(self.payload).await.map(Some);
+        }
+
+        handle_once(
+            log,
+            &self.metadata.message_id,
+            type_label,
+            process(self.payload),
+        )
+        .await
+    }
+}
+
+impl<T: EventsubPayload + 'static> FromRequest for VerifiedEventsub<T> {
+    type Error = actix_web::Error;
+    type Future = LocalBoxFuture<'static, actix_web::Result<Self>>;
+
+    fn from_request(req: &HttpRequest, payload: &mut Payload) -> Self::Future {
+        let req = req.clone();
+        let body = Bytes::from_request(&req, payload);
+
+        Box::pin(async move {
+            let state = req
+                .app_data::<Data<AppState>>()
+                .ok_or_else(|| Error::InternalServer("App state is not registered".to_string()))?;
+
+            // Let Twitch retry the message, it will be handled by the next process
+            if state.shutdown.is_triggered() {
+                return Err(Error::ShuttingDown.into());
+            }
+
+            let message_id = header(&req, "twitch-eventsub-message-id")?;
+            let signature = header(&req, "twitch-eventsub-message-signature")?;
+            let timestamp = header(&req, "twitch-eventsub-message-timestamp")?;
+            let message_type = header(&req, "twitch-eventsub-message-type")?;
+
+            logging::set_eventsub_message_id(message_id);
+
+            let type_label = message_type_label(message_type);
+            let body = body.await?;
+
+            let verified = verify_message(
+                state.eventsub_secrets(),
+                state.get_ref(),
+                message_id,
+                timestamp,
+                &body,
+                signature,
+            )
+            .await?;
+            if !verified {
+                EVENTSUB_MESSAGES
+                    .with_label_values(&[type_label, OUTCOME_BAD_SIGNATURE])
+                    .inc();
+
+                return Err(Error::InvalidSignature.into());
+            }
+
+            let timestamp = DateTime::parse_from_rfc3339(timestamp)
+                .map_err(|_| Error::InvalidHeader("twitch-eventsub-message-timestamp"))?
+                .with_timezone(&Utc);
+            if (Utc::now() - timestamp).abs() > MESSAGE_MAX_AGE {
+                EVENTSUB_MESSAGES
+                    .with_label_values(&[type_label, OUTCOME_EXPIRED])
+                    .inc();
+
+                return Err(Error::ExpiredMessage.into());
+            }
+
+            let payload = T::parse(state, message_type, &body)?;
+
+            Ok(VerifiedEventsub {
+                metadata: EventsubMetadata {
+                    message_id: message_id.to_string(),
+                    message_type: message_type.to_string(),
+                    timestamp,
+                },
+                payload,
+            })
+        })
+    }
+}
+
+/// Fingerprints of the previous secrets that no eventsub uses anymore.
+#[async_trait(?Send)]
+pub(crate) trait RetiredSecrets {
+    async fn is_retired(&self, fingerprint: &str) -> Result<bool>;
+}
+
+/// Verifies the signature of a message against the secrets, the current one first. Messages
+/// signed with a previous secret are only accepted until it is retired.
+pub(crate) async fn verify_message(
+    mut secrets: impl Iterator<Item = &str>,
+    retired: &impl RetiredSecrets,
+    message_id: &str,
+    timestamp: &str,
+    body: &[u8],
+    signature: &str,
+) -> Result<bool> {
+    let Some(current) = secrets.next() else {
+        return Ok(false);
+    };
+    if verify_signature(current, message_id, timestamp, body, signature) {
+        return Ok(true);
+    }
+
+    match secrets.find(|secret| verify_signature(secret, message_id, timestamp, body, signature)) {
+        Some(previous) => Ok(!retired.is_retired(&secret_fingerprint(previous)).await?),
+        None => Ok(false),
+    }
+}
+
+/// Ids of the handled eventsub messages, so the retries of Twitch are only acknowledged.
+#[async_trait(?Send)]
+pub trait MessageLog {
+    /// Records the message as handled. Returns `false` if it has been handled before.
+    async fn record(&self, message_id: &str, type_label: &'static str) -> Result<bool>;
+
+    /// Removes the message again, so its retry is handled.
+    async fn forget(&self, message_id: &str) -> Result<()>;
+}
+
+/// Processes the message unless it has been handled before, regardless of the transport it
+/// was received with. The message is recorded first, so concurrent retries are not processed
+/// twice, and forgotten if processing fails, so the retry of Twitch is processed instead of
+/// acknowledged. Returns `None` for messages that have been handled before.
+pub(crate) async fn handle_once<R>(
+    log: &impl MessageLog,
+    message_id: &str,
+    type_label: &'static str,
+    process: impl Future<Output = Result<R>>,
+) -> Result<Option<R>> {
+    if !log.record(message_id, type_label).await? {
+        return Ok(None);
+    }
+
+    match process.await {
+        Ok(result) => Ok(Some(result)),
+        Err(e) => {
+            if let Err(forget_error) = log.forget(message_id).await {
+                error!("Could not forget eventsub message {message_id}: {forget_error}");
+            }
+
+            Err(e)
+        }
+    }
+}
+
+/// Periodically deletes the ids of the handled messages Twitch can't retry anymore.
+pub async fn run_message_cleanup(state: AppState) {
+    let mut interval = actix_web::rt::time::interval(MESSAGE_CLEANUP_INTERVAL);
+
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {}
+            _ = state.shutdown.triggered() => break,
+        }
+
+        if let Err(e) = state.delete_expired_eventsub_messages().await {
+            error!("Could not delete expired eventsub message ids: {e}");
+        }
+    }
+}
+
+/// Reads a header of the message. Values with characters other than visible ASCII are
+/// rejected.
+fn header<'a>(request: &'a HttpRequest, name: &'static str) -> Result<&'a str> {
+    request
+        .headers()
+        .get(name)
+        .and_then(|h| h.to_str().ok())
+        .ok_or(Error::InvalidHeader(name))
+}
+
+/// Maps the message type to a metric label, the type is not trusted at this point.
+pub(crate) fn message_type_label(message_type: &str) -> &'static str {
+    [
+        TwitchSubscriptionStatus::Notification,
+        TwitchSubscriptionStatus::WebhookCallbackVerification,
+        TwitchSubscriptionStatus::Revocation,
+    ]
+    .iter()
+    .map(|s| s.as_str())
+    .find(|s| *s == message_type)
+    .unwrap_or("unknown")
+}
+
+/// Identifies a secret in the database without storing the secret itself.
+pub(crate) fn secret_fingerprint(secret: &str) -> String {
+    hex::encode(&Sha256::digest(secret.as_bytes())[..8])
+}
+
+impl AppState {
+    /// Secrets messages may be signed with, the current one first.
+    fn eventsub_secrets(&self) -> impl Iterator<Item = &'static str> {
+        std::iter::once(self.twitch.eventsub_secret).chain(
+            self.twitch
+                .previous_eventsub_secrets
+                .iter()
+                .map(String::as_str),
+        )
+    }
+
+    /// Fingerprint of the secret new eventsubs are registered with. Only eventsubs with the
+    /// webhook transport have a secret.
+    pub(crate) fn eventsub_secret_fingerprint(&self) -> Option<String> {
+        match self.twitch.transport {
+            EventsubTransport::Webhook { .. } => {
+                Some(secret_fingerprint(self.twitch.eventsub_secret))
+            }
+            _ => None,
+        }
+    }
+
+    /// Deletes the recorded message ids which can't be retried anymore. Returns the amount of
+    /// deleted ids.
+    pub async fn delete_expired_eventsub_messages(&self) -> Result<u64> {
+        // Twitch rejects messages older than 10 minutes, so retries can't happen afterwards
+        let deleted = sqlx::query(
+            "DELETE FROM eventsub_messages WHERE received_at < now() - interval '10 minutes'",
+        )
+        .execute(&self.db)
+        .await?;
+
+        Ok(deleted.rows_affected())
+    }
+}
+
+#[async_trait(?Send)]
+impl RetiredSecrets for AppState {
+    async fn is_retired(&self, fingerprint: &str) -> Result<bool> {
+        let row = sqlx::query("SELECT 1 FROM retired_eventsub_secrets WHERE fingerprint = $1")
+            .bind(fingerprint)
+            .fetch_optional(&self.db)
+            .await?;
+
+        Ok(row.is_some())
+    }
+}
+
+#[async_trait(?Send)]
+impl MessageLog for AppState {
+    async fn record(&self, message_id: &str, type_label: &'static str) -> Result<bool> {
+        let inserted = sqlx::query(
+            "INSERT INTO eventsub_messages (id) VALUES ($1) ON CONFLICT (id) DO NOTHING RETURNING id",
+        )
+        .bind(message_id)
+        .fetch_optional(&self.db)
+        .await?;
+
+        let outcome = match inserted {
+            Some(_) => OUTCOME_VERIFIED,
+            None => OUTCOME_DUPLICATE,
+        };
+        EVENTSUB_MESSAGES
+            .with_label_values(&[type_label, outcome])
+            .inc();
+
+        Ok(inserted.is_some())
+    }
+
+    async fn forget(&self, message_id: &str) -> Result<()> {
+        sqlx::query("DELETE FROM eventsub_messages WHERE id = $1")
+            .bind(message_id)
+            .execute(&self.db)
+            .await?;
+
+        Ok(())
+    }
+}
+
+/// Verifies the `Twitch-Eventsub-Message-Signature` header of an eventsub message against
+/// the secret the subscription was created with.
+pub fn verify_signature(
+    secret: &str,
+    message_id: &str,
+    message_timestamp: &str,
+    body: &[u8],
+    signature: &str,
+) -> bool {
+    let Some(signature) = signature.strip_prefix(SIGNATURE_PREFIX) else {
+        return false;
+    };
+    let Ok(signature) = hex::decode(signature) else {
+        return false;
+    };
+    let Ok(mut mac) = Hmac::<Sha256>::new_from_slice(secret.as_bytes()) else {
+        return false;
+    };
+
+    mac.update(message_id.as_bytes());
+    mac.update(message_timestamp.as_bytes());
+    mac.update(body);
+
+    mac.verify_slice(&signature).is_ok()
+}
+
+/// Signs a message with the secret the way Twitch does.
+pub(crate) fn sign_message(
+    secret: &str,
+    message_id: &str,
+    message_timestamp: &str,
+    body: &[u8],
+) -> String {
+    // Any key length is valid for HMAC
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).unwrap();
+    mac.update(message_id.as_bytes());
+    mac.update(message_timestamp.as_bytes());
+    mac.update(body);
+
+    format!(
+        "{SIGNATURE_PREFIX}{}",
+        hex::encode(mac.finalize().into_bytes())
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::{Cell, RefCell};
+    use std::collections::HashSet;
+
+    use super::*;
+
+    const SECRET: &str = "s3cRe7s3cRe7";
+    const MESSAGE_ID: &str = "e76c6bd4-55c9-4987-8304-da1588d8988b";
+    const TIMESTAMP: &str = "2019-11-16T10:11:12.634234626Z";
+    const BODY: &[u8] = br#"{"event":"online"}"#;
+    const SIGNATURE: &str =
+        "sha256=8f2b7777302963269680e5cc16d6fd56997449bd02fe691a6f9a70b37cc3c450";
+
+    fn verify(body: &[u8], signature: &str) -> bool {
+        verify_signature(SECRET, MESSAGE_ID, TIMESTAMP, body, signature)
+    }
+
+    #[test]
+    fn accepts_valid_signature() {
+        assert!(verify(BODY, SIGNATURE));
+    }
+
+    #[test]
+    fn rejects_modified_body() {
+        assert!(!verify(br#"{"event":"offline"}"#, SIGNATURE));
+    }
+
+    #[test]
+    fn rejects_signature_without_prefix() {
+        assert!(!verify(BODY, &SIGNATURE[7..]));
+        assert!(!verify(BODY, "sha1=8f2b"));
+        assert!(!verify(BODY, "sha"));
+        assert!(!verify(BODY, ""));
+    }
+
+    #[test]
+    fn rejects_non_hex_signature() {
+        assert!(!verify(BODY, "sha256=not-hex"));
+        assert!(!verify(BODY, "sha256=8f2"));
+        assert!(!verify(BODY, "sha256=ü"));
+        assert!(!verify(BODY, "sha256="));
+    }
+
+    #[derive(Default)]
+    struct FakeLog(RefCell<HashSet<String>>);
+
+    #[async_trait(?Send)]
+    impl MessageLog for FakeLog {
+        async fn record(&self, message_id: &str, _type_label: &'static str) -> Result<bool> {
+            Ok(self.0.borrow_mut().insert(message_id.to_string()))
+        }
+
+        async fn forget(&self, message_id: &str) -> Result<()> {
+            self.0.borrow_mut().remove(message_id);
+            Ok(())
+        }
+    }
+
+    #[actix_web::test]
+    async fn processes_retry_of_failed_message() {
+        let log = FakeLog::default();
+        let processed = Cell::new(0);
+        let process = |result: Result<()>| async {
+            processed.set(processed.get() + 1);
+            result
+        };
+
+        let first = handle_once(&log, MESSAGE_ID, "revocation", process(Err(Error::Mutex))).await;
+        assert!(matches!(first, Err(Error::Mutex)));
+
+        let retry = handle_once(&log, MESSAGE_ID, "revocation", process(Ok(()))).await;
+        assert!(matches!(retry, Ok(Some(()))));
+
+        let duplicate = handle_once(&log, MESSAGE_ID, "revocation", process(Ok(()))).await;
+        assert!(matches!(duplicate, Ok(None)));
+        assert_eq!(processed.get(), 2);
+    }
+
+    fn verified(message_type: &str) -> VerifiedEventsub<&'static str> {
+        VerifiedEventsub {
+            metadata: EventsubMetadata {
+                message_id: MESSAGE_ID.to_string(),
+                message_type: message_type.to_string(),
+                timestamp: Utc::now(),
+            },
+            payload: "payload",
+        }
+    }
+
+    #[actix_web::test]
+    async fn processes_verified_messages_once() {
+        let log = FakeLog::default();
+        let process = |payload: &'static str| async move { Ok(payload.len()) };
+
+        let first = verified("notification").once(&log, process).await;
+        assert!(matches!(first, Ok(Some(7))));
+
+        let retry = verified("notification").once(&log, process).await;
+        assert!(matches!(retry, Ok(None)));
+    }
+
+    #[actix_web::test]
+    async fn answers_every_challenge() {
+        let log = FakeLog::default();
+        let process = |payload: &'static str| async move { Ok(payload) };
+
+        for _ in 0..2 {
+            let answer = verified("webhook_callback_verification")
+                .once(&log, process)
+                .await;
+            assert!(matches!(answer, Ok(Some("payload"))));
+        }
+    }
+
+    const PREVIOUS_SECRET: &str = "pr3v10usS3cRe7";
+
+    #[async_trait(?Send)]
+    impl RetiredSecrets for HashSet<String> {
+        async fn is_retired(&self, fingerprint: &str) -> Result<bool> {
+            Ok(self.contains(fingerprint))
+        }
+    }
+
+    async fn verify_with(secret: &str, retired: &HashSet<String>) -> bool {
+        let signature = sign_message(secret, MESSAGE_ID, TIMESTAMP, b"{}");
+
+        verify_message(
+            [SECRET, PREVIOUS_SECRET].into_iter(),
+            retired,
+            MESSAGE_ID,
+            TIMESTAMP,
+            b"{}",
+            &signature,
+        )
+        .await
+        .unwrap()
+    }
+
+    #[actix_web::test]
+    async fn verifies_current_and_previous_secrets() {
+        let retired = HashSet::new();
+
+        assert!(verify_with(SECRET, &retired).await);
+        assert!(verify_with(PREVIOUS_SECRET, &retired).await);
+        assert!(!verify_with("unknownS3cRe7", &retired).await);
+    }
+
+    #[actix_web::test]
+    async fn rejects_retired_secrets() {
+        let retired = HashSet::from([secret_fingerprint(PREVIOUS_SECRET)]);
+
+        assert!(!verify_with(PREVIOUS_SECRET, &retired).await);
+    }
+
+    #[actix_web::test]
+    async fn accepts_current_secret_regardless_of_retirement() {
+        let retired = HashSet::from([secret_fingerprint(SECRET)]);
+
+        assert!(verify_with(SECRET, &retired).await);
+    }
+}
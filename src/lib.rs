@@ -0,0 +1,229 @@
+//! Sends notifications to the bot when Twitch streamers go live.
+//!
+//! Besides running the service through [`run`], the routes can be embedded into another
+//! actix app. They expect a [`web::Data<AppState>`](actix_web::web::Data) built with
+//! [`AppState::builder`]:
+//!
+//! ```no_run
+//! # async fn example(config: &'static notificator::config::Config, pool: sqlx::PgPool) {
+//! use actix_web::{web, App, HttpServer};
+//! use notificator::{init_service_routes, init_twitch_routes, AppState};
+//!
+//! HttpServer::new(move || {
+//!     App::new()
+//!         .app_data(web::Data::new(AppState::builder(config, pool.clone()).build()))
+//!         .wrap(notificator::error_handler::error_handlers())
+//!         .configure(init_service_routes)
+//!         .configure(init_twitch_routes)
+//! });
+//! # }
+//! ```
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use actix_web::middleware::Logger;
+use actix_web::web::Data;
+use actix_web::{App, HttpServer};
+use log::{info, warn};
+use sqlx::migrate::Migrator;
+use sqlx::postgres::PgPoolOptions;
+use sqlx::PgPool;
+
+pub use crate::routes::twitch::structs as models;
+pub use crate::routes::{
+    init_auth_routes, init_health_routes, init_metrics_routes, init_service_routes,
+    init_twitch_routes,
+};
+pub use crate::structs::{AppState, AppStateBuilder};
+
+use crate::budget::EventsubBudget;
+use crate::config::{Config, EventsubTransport, ShardTransport};
+use crate::dispatcher::{DeliveryQueue, DispatcherSettings};
+use crate::eventsub::EventHandlers;
+use crate::shutdown::Shutdown;
+use crate::websocket::WebsocketSession;
+
+pub mod budget;
+mod callback;
+pub mod cli;
+mod conduit;
+pub mod config;
+mod delivery;
+pub mod dispatcher;
+pub mod error_handler;
+pub mod errors;
+pub mod eventsub;
+pub mod logging;
+pub mod metrics;
+mod poller;
+mod revocations;
+pub mod routes;
+mod self_test;
+pub mod shutdown;
+pub mod structs;
+mod subscriptions;
+#[cfg(test)]
+mod test_utils;
+mod utils;
+mod verification;
+pub mod websocket;
+
+const DB_CONNECT_RETRY_DELAY: Duration = Duration::from_secs(5);
+
+/// Migrations of the database schema, embedded from `migrations/`.
+pub static MIGRATOR: Migrator = sqlx::migrate!();
+
+/// Connects to the database, retrying until it is reachable instead of failing the startup.
+pub async fn connect_database(config: &Config) -> PgPool {
+    loop {
+        let pool = PgPoolOptions::new()
+            .max_connections(config.db_max_connections)
+            .acquire_timeout(config.db_acquire_timeout)
+            .connect(config.postgres_dsn.as_str())
+            .await;
+
+        match pool {
+            Ok(pool) => return pool,
+            Err(e) => {
+                warn!("Could not connect to database, retrying in {DB_CONNECT_RETRY_DELAY:?}: {e}");
+                actix_web::rt::time::sleep(DB_CONNECT_RETRY_DELAY).await;
+            }
+        }
+    }
+}
+
+/// Runs the service until it receives SIGTERM or Ctrl-C.
+pub async fn run(config: &'static Config) -> std::io::Result<()> {
+    let pool = connect_database(config).await;
+
+    MIGRATOR
+        .run(&pool)
+        .await
+        .expect("Error running database migrations");
+
+    let shutdown = Shutdown::new();
+    let (deliveries, delivery_receiver) = DeliveryQueue::new(config.delivery_queue_size);
+    let websocket_session = WebsocketSession::default();
+    let handlers = Arc::new(EventHandlers::builtin());
+    let eventsub_budget = EventsubBudget::default();
+    let state = || {
+        AppState::builder(config, pool.clone())
+            .shutdown(shutdown.clone())
+            .deliveries(deliveries.clone())
+            .websocket_session(websocket_session.clone())
+            .handlers(handlers.clone())
+            .eventsub_budget(eventsub_budget.clone())
+    };
+
+    shutdown.spawn(dispatcher::run_dispatcher(
+        state().build(),
+        delivery_receiver,
+        DispatcherSettings {
+            concurrency: config.delivery_concurrency,
+            rate_limit: config.delivery_rate_limit,
+        },
+    ));
+
+    shutdown.spawn(eventsub::run_message_cleanup(state().build()));
+
+    if let Some(interval) = config.poll_interval {
+        shutdown.spawn(poller::run_poller(state().build(), interval));
+    }
+
+    match &config.twitch.transport {
+        EventsubTransport::Webhook { .. } => {
+            shutdown.spawn(callback::check_callback_url(state().build()));
+            shutdown.spawn(verification::run_verification_tracker(state().build()));
+        }
+        EventsubTransport::Websocket { url, .. } => {
+            shutdown.spawn(websocket::run_websocket(Data::new(state().build()), url));
+        }
+        EventsubTransport::Conduit { replica_id, shard } => {
+            if let ShardTransport::Websocket { url } = shard {
+                shutdown.spawn(websocket::run_websocket(Data::new(state().build()), url));
+            }
+
+            shutdown.spawn(conduit::run_conduit(state().build(), replica_id));
+        }
+    }
+
+    if config.twitch.callback_self_test {
+        match self_test::callback_url(&config.twitch.transport) {
+            Some(callback_url) => {
+                shutdown.spawn(self_test::run_self_test(state().build(), callback_url));
+            }
+            None => warn!("The callback self-test only applies to webhook transports"),
+        }
+    }
+
+    info!("Starting webserver...");
+
+    let worker_pool = pool.clone();
+    let worker_shutdown = shutdown.clone();
+    let server = HttpServer::new(move || {
+        App::new()
+            .app_data(Data::new(
+                AppState::builder(config, worker_pool.clone())
+                    .shutdown(worker_shutdown.clone())
+                    .deliveries(deliveries.clone())
+                    .websocket_session(websocket_session.clone())
+                    .handlers(handlers.clone())
+                    .eventsub_budget(eventsub_budget.clone())
+                    .build(),
+            ))
+            .wrap_fn(logging::request_id_middleware)
+            .wrap(Logger::new(
+                r#"%a "%r" %s %b "%{Referer}i" "%{User-Agent}i" %T request_id=%{x-request-id}o"#,
+            ))
+            .wrap(error_handler::error_handlers())
+            .configure(init_health_routes)
+            .configure(init_metrics_routes)
+            .configure(init_service_routes)
+            .configure(init_twitch_routes)
+            .configure(init_auth_routes)
+    })
+    .bind(config.bind_address)?
+    .workers(config.workers)
+    .disable_signals()
+    .run();
+
+    let handle = server.handle();
+    actix_web::rt::spawn(async move {
+        wait_for_signal().await;
+        info!(
+            "Shutting down, waiting for {} running tasks...",
+            shutdown.running_tasks()
+        );
+
+        shutdown.trigger();
+        handle.pause().await;
+
+        if !shutdown.wait_idle(config.shutdown_timeout).await {
+            warn!(
+                "{} tasks did not finish in time, pending deliveries are resent on the next start",
+                shutdown.running_tasks()
+            );
+        }
+
+        handle.stop(true).await;
+    });
+
+    server.await
+}
+
+async fn wait_for_signal() {
+    #[cfg(unix)]
+    {
+        use actix_web::rt::signal::unix::{signal, SignalKind};
+
+        let mut terminate = signal(SignalKind::terminate()).expect("Could not listen for SIGTERM");
+        tokio::select! {
+            _ = terminate.recv() => {}
+            _ = actix_web::rt::signal::ctrl_c() => {}
+        }
+    }
+
+    #[cfg(not(unix))]
+    let _ = actix_web::rt::signal::ctrl_c().await;
+}
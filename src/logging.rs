@@ -0,0 +1,257 @@
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::env;
+use std::future::Future;
+use std::io::Write;
+use std::sync::RwLock;
+
+use actix_web::dev::{Service, ServiceRequest, ServiceResponse};
+use actix_web::http::header::{HeaderName, HeaderValue};
+use chrono::{SecondsFormat, Utc};
+use lazy_static::lazy_static;
+use rand::Rng;
+use regex::Regex;
+use serde_json::json;
+
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+
+const REDACTED: &str = "[redacted]";
+
+lazy_static! {
+    /// Values that must never show up in logs, e.g. the configured client secret.
+    static ref SECRETS: RwLock<Vec<String>> = RwLock::new(vec![]);
+    /// Credentials inside urls, forms, headers and JSON bodies.
+    static ref SENSITIVE_PATTERN: Regex = Regex::new(
+        r#"(?i)(\b(?:client_secret|access_token|refresh_token|code|secret)(?:=|"\s*:\s*")|bearer\s+)[^&\s"]+"#
+    )
+    .unwrap();
+}
+
+tokio::task_local! {
+    static CONTEXT: RefCell<LogContext>;
+}
+
+/// Identifies the work a log line belongs to.
+#[derive(Clone, Default)]
+pub struct LogContext {
+    pub request_id: Option<String>,
+    pub eventsub_message_id: Option<String>,
+}
+
+/// Initializes the logger. Setting `LOG_FORMAT=json` writes one JSON object per line.
+pub fn init() {
+    let json = env::var("LOG_FORMAT").is_ok_and(|f| f.eq_ignore_ascii_case("json"));
+
+    let env = env_logger::Env::default()
+        .default_filter_or("INFO")
+        .default_write_style_or("always");
+    let mut builder = env_logger::Builder::from_env(env);
+
+    if json {
+        builder.format(|buf, record| {
+            let context = current_context();
+            let line = json!({
+                "timestamp": Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true),
+                "level": record.level().as_str(),
+                "target": record.target(),
+                "message": redact(&record.args().to_string()),
+                "request_id": context.request_id,
+                "eventsub_message_id": context.eventsub_message_id,
+            });
+
+            writeln!(buf, "{line}")
+        });
+    } else {
+        builder.format(|buf, record| {
+            let context = current_context();
+            let mut ids = String::new();
+            if let Some(id) = context.request_id {
+                ids.push_str(&format!(" request_id={id}"));
+            }
+            if let Some(id) = context.eventsub_message_id {
+                ids.push_str(&format!(" eventsub_message_id={id}"));
+            }
+
+            writeln!(
+                buf,
+                "[{} {} {}{ids}] {}",
+                buf.timestamp(),
+                buf.default_styled_level(record.level()),
+                record.target(),
+                redact(&record.args().to_string())
+            )
+        });
+    }
+
+    builder.init();
+}
+
+/// Registers a value that is replaced in every log line.
+pub fn register_secret(secret: &str) {
+    if let Ok(mut secrets) = SECRETS.write() {
+        secrets.push(secret.to_string());
+    }
+}
+
+fn redact(message: &str) -> Cow<'_, str> {
+    let mut message = SENSITIVE_PATTERN.replace_all(message, format!("${{1}}{REDACTED}"));
+
+    if let Ok(secrets) = SECRETS.read() {
+        for secret in secrets.iter() {
+            if message.contains(secret.as_str()) {
+                message = Cow::Owned(message.replace(secret.as_str(), REDACTED));
+            }
+        }
+    }
+
+    message
+}
+
+pub fn current_context() -> LogContext {
+    CONTEXT.try_with(|c| c.borrow().clone()).unwrap_or_default()
+}
+
+/// Attaches the eventsub message id to all following log lines of the current request.
+pub fn set_eventsub_message_id(message_id: &str) {
+    let _ = CONTEXT.try_with(|c| {
+        c.borrow_mut().eventsub_message_id = Some(message_id.to_string());
+    });
+}
+
+/// Runs the future with the given log context.
+pub fn with_context<F>(context: LogContext, future: F) -> impl Future<Output = F::Output>
+where
+    F: Future,
+{
+    CONTEXT.scope(RefCell::new(context), future)
+}
+
+/// Spawns the future on the current runtime, keeping the log context of the caller.
+pub fn spawn<F>(future: F)
+where
+    F: Future + 'static,
+{
+    actix_web::rt::spawn(with_context(current_context(), future));
+}
+
+fn generate_request_id() -> String {
+    hex::encode(rand::thread_rng().gen::<[u8; 8]>())
+}
+
+/// Middleware assigning every request an id, taken from the `x-request-id` header if present.
+/// The id is attached to all log lines of the request and returned in the response.
+pub fn request_id_middleware<S, B>(
+    req: ServiceRequest,
+    srv: &S,
+) -> impl Future<Output = Result<ServiceResponse<B>, actix_web::Error>>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error>,
+{
+    let request_id = req
+        .headers()
+        .get(REQUEST_ID_HEADER)
+        .and_then(|h| h.to_str().ok())
+        .filter(|h| {
+            !h.is_empty()
+                && h.len() <= 64
+                && h.chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        })
+        .map(str::to_string)
+        .unwrap_or_else(generate_request_id);
+
+    let context = LogContext {
+        request_id: Some(request_id.clone()),
+        eventsub_message_id: None,
+    };
+    let fut = CONTEXT.sync_scope(RefCell::new(context.clone()), || srv.call(req));
+
+    with_context(context, async move {
+        let mut res = fut.await?;
+
+        if let Ok(value) = HeaderValue::from_str(&request_id) {
+            res.headers_mut()
+                .insert(HeaderName::from_static(REQUEST_ID_HEADER), value);
+        }
+
+        Ok(res)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::test::{call_service, init_service, read_body, TestRequest};
+    use actix_web::{web, App, HttpResponse};
+
+    use super::*;
+
+    #[test]
+    fn redacts_credentials() {
+        assert_eq!(
+            redact("POST /oauth2/token?client_id=abc&client_secret=s3cRe7&grant_type=x"),
+            "POST /oauth2/token?client_id=abc&client_secret=[redacted]&grant_type=x"
+        );
+        assert_eq!(
+            redact(r#"{"access_token": "abc123", "expires_in": 10}"#),
+            r#"{"access_token": "[redacted]", "expires_in": 10}"#
+        );
+        assert_eq!(
+            redact("Authorization: Bearer abc123"),
+            "Authorization: Bearer [redacted]"
+        );
+    }
+
+    #[test]
+    fn redacts_registered_secrets() {
+        register_secret("registered-s3cRe7");
+
+        assert_eq!(
+            redact("Could not connect with registered-s3cRe7 twice: registered-s3cRe7"),
+            "Could not connect with [redacted] twice: [redacted]"
+        );
+        assert_eq!(redact("Nothing to hide"), "Nothing to hide");
+    }
+
+    async fn send(request_id: Option<&str>) -> (String, String) {
+        let app = init_service(App::new().wrap_fn(request_id_middleware).route(
+            "/",
+            web::get().to(|| async {
+                HttpResponse::Ok().body(current_context().request_id.unwrap_or_default())
+            }),
+        ))
+        .await;
+
+        let mut req = TestRequest::get().uri("/");
+        if let Some(id) = request_id {
+            req = req.insert_header((REQUEST_ID_HEADER, id));
+        }
+        let res = call_service(&app, req.to_request()).await;
+        let header = res
+            .headers()
+            .get(REQUEST_ID_HEADER)
+            .unwrap()
+            .to_str()
+            .unwrap()
+            .to_string();
+        let body = read_body(res).await;
+
+        (header, String::from_utf8(body.to_vec()).unwrap())
+    }
+
+    #[actix_web::test]
+    async fn propagates_request_id() {
+        let (header, context) = send(Some("abc-123_x")).await;
+
+        assert_eq!(header, "abc-123_x");
+        assert_eq!(context, "abc-123_x");
+    }
+
+    #[actix_web::test]
+    async fn replaces_invalid_request_id() {
+        let (header, context) = send(Some("not valid!")).await;
+
+        assert_ne!(header, "not valid!");
+        assert_eq!(header.len(), 16);
+        assert_eq!(context, header);
+    }
+}
@@ -0,0 +1,190 @@
+use std::future::Future;
+use std::time::Instant;
+
+use awc::error::SendRequestError;
+use awc::ClientResponse;
+use lazy_static::lazy_static;
+use prometheus::{
+    register_histogram_vec, register_int_counter, register_int_counter_vec, register_int_gauge,
+    HistogramVec, IntCounter, IntCounterVec, IntGauge,
+};
+
+lazy_static! {
+    pub static ref EVENTSUB_MESSAGES: IntCounterVec = register_int_counter_vec!(
+        "notificator_eventsub_messages_total",
+        "Received eventsub messages by message type and outcome",
+        &["type", "outcome"]
+    )
+    .unwrap();
+    static ref HELIX_REQUESTS: IntCounterVec = register_int_counter_vec!(
+        "notificator_helix_requests_total",
+        "Requests sent to the Twitch API by endpoint and response status",
+        &["endpoint", "status"]
+    )
+    .unwrap();
+    static ref HELIX_REQUEST_DURATION: HistogramVec = register_histogram_vec!(
+        "notificator_helix_request_duration_seconds",
+        "Latency of requests sent to the Twitch API by endpoint",
+        &["endpoint"]
+    )
+    .unwrap();
+    static ref AUTH_REQUESTS: IntCounterVec = register_int_counter_vec!(
+        "notificator_twitch_auth_requests_total",
+        "Requests sent to the Twitch authentication server by endpoint and response status",
+        &["endpoint", "status"]
+    )
+    .unwrap();
+    static ref AUTH_REQUEST_DURATION: HistogramVec = register_histogram_vec!(
+        "notificator_twitch_auth_request_duration_seconds",
+        "Latency of requests sent to the Twitch authentication server by endpoint",
+        &["endpoint"]
+    )
+    .unwrap();
+    pub static ref BOT_DELIVERY_ATTEMPTS: IntCounter = register_int_counter!(
+        "notificator_bot_delivery_attempts_total",
+        "Attempts to deliver a notification to the bot"
+    )
+    .unwrap();
+    pub static ref BOT_DELIVERIES: IntCounterVec = register_int_counter_vec!(
+        "notificator_bot_deliveries_total",
+        "Finished deliveries to the bot by result",
+        &["result"]
+    )
+    .unwrap();
+    pub static ref DELIVERY_QUEUE_LENGTH: IntGauge = register_int_gauge!(
+        "notificator_delivery_queue_length",
+        "Deliveries waiting in the queue of the dispatcher"
+    )
+    .unwrap();
+    pub static ref DELIVERIES_SPILLED: IntCounter = register_int_counter!(
+        "notificator_deliveries_spilled_total",
+        "Deliveries left in the database because the queue was full"
+    )
+    .unwrap();
+    pub static ref TOKEN_REFRESHES: IntCounterVec = register_int_counter_vec!(
+        "notificator_token_refreshes_total",
+        "Refreshes of the Twitch app access token by result",
+        &["result"]
+    )
+    .unwrap();
+    pub static ref DB_POOL_CONNECTIONS: IntGauge = register_int_gauge!(
+        "notificator_db_pool_connections",
+        "Open connections of the database pool"
+    )
+    .unwrap();
+    pub static ref DB_POOL_IDLE_CONNECTIONS: IntGauge = register_int_gauge!(
+        "notificator_db_pool_idle_connections",
+        "Idle connections of the database pool"
+    )
+    .unwrap();
+    pub static ref TRACKED_STREAMERS: IntGauge = register_int_gauge!(
+        "notificator_tracked_streamers",
+        "Streamers at least one notification exists for"
+    )
+    .unwrap();
+    pub static ref ACTIVE_SUBSCRIPTIONS: IntGauge = register_int_gauge!(
+        "notificator_active_subscriptions",
+        "Eventsub subscriptions referenced by tracked streamers"
+    )
+    .unwrap();
+    pub static ref EVENTSUB_TOTAL_COST: IntGauge = register_int_gauge!(
+        "notificator_eventsub_total_cost",
+        "Cost of the eventsubs of the app as last reported by Twitch"
+    )
+    .unwrap();
+    pub static ref EVENTSUB_MAX_TOTAL_COST: IntGauge = register_int_gauge!(
+        "notificator_eventsub_max_total_cost",
+        "Maximum cost of the eventsubs Twitch allows for the app"
+    )
+    .unwrap();
+    pub static ref EVENTSUB_COST_WARNINGS: IntCounterVec = register_int_counter_vec!(
+        "notificator_eventsub_cost_warnings_total",
+        "Times the eventsub cost crossed a warning threshold, by threshold in percent",
+        &["threshold"]
+    )
+    .unwrap();
+    pub static ref LIVE_STREAMS: IntGauge = register_int_gauge!(
+        "notificator_live_streams",
+        "Streams that have been announced and not ended yet"
+    )
+    .unwrap();
+}
+
+pub const OUTCOME_VERIFIED: &str = "verified";
+pub const OUTCOME_BAD_SIGNATURE: &str = "bad_signature";
+pub const OUTCOME_DUPLICATE: &str = "duplicate";
+pub const OUTCOME_EXPIRED: &str = "expired";
+
+pub const RESULT_SUCCESS: &str = "success";
+pub const RESULT_FAILURE: &str = "failure";
+
+/// Sends a request to the Twitch API while recording its status and latency.
+pub async fn track_helix<F, S>(
+    endpoint: &str,
+    request: F,
+) -> Result<ClientResponse<S>, SendRequestError>
+where
+    F: Future<Output = Result<ClientResponse<S>, SendRequestError>>,
+{
+    track(&HELIX_REQUESTS, &HELIX_REQUEST_DURATION, endpoint, request).await
+}
+
+/// Sends a request to the Twitch authentication server (`id.twitch.tv`) while recording its
+/// status and latency apart from the Helix requests.
+pub async fn track_auth<F, S>(
+    endpoint: &str,
+    request: F,
+) -> Result<ClientResponse<S>, SendRequestError>
+where
+    F: Future<Output = Result<ClientResponse<S>, SendRequestError>>,
+{
+    track(&AUTH_REQUESTS, &AUTH_REQUEST_DURATION, endpoint, request).await
+}
+
+async fn track<F, S>(
+    requests: &IntCounterVec,
+    duration: &HistogramVec,
+    endpoint: &str,
+    request: F,
+) -> Result<ClientResponse<S>, SendRequestError>
+where
+    F: Future<Output = Result<ClientResponse<S>, SendRequestError>>,
+{
+    let started = Instant::now();
+    let res = request.await;
+
+    let status = match &res {
+        Ok(res) => res.status().as_u16().to_string(),
+        Err(_) => "error".to_string(),
+    };
+
+    duration
+        .with_label_values(&[endpoint])
+        .observe(started.elapsed().as_secs_f64());
+    requests
+        .with_label_values(&[endpoint, status.as_str()])
+        .inc();
+
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use awc::test::TestResponse;
+
+    use super::*;
+
+    #[actix_web::test]
+    async fn tracks_auth_requests_apart_from_helix() {
+        let endpoint = "oauth2/test";
+        track_auth(endpoint, async { Ok(TestResponse::default().finish()) })
+            .await
+            .unwrap();
+
+        assert_eq!(AUTH_REQUESTS.with_label_values(&[endpoint, "200"]).get(), 1);
+        assert_eq!(
+            HELIX_REQUESTS.with_label_values(&[endpoint, "200"]).get(),
+            0
+        );
+    }
+}
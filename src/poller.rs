@@ -0,0 +1,149 @@
+use std::collections::HashMap;
+use std::time::Duration;
+
+use log::{error, info};
+use sqlx::Row;
+
+use crate::routes::twitch::structs::StreamData;
+use crate::structs::{AppState, Result};
+
+/// Helix often lists a stream only some time after `stream.online` has been sent, so a
+/// session is only closed once its user was missing from this many polls in a row, and it is
+/// older than these polls take.
+const OFFLINE_POLLS: u32 = 3;
+
+/// Periodically checks all tracked users against Helix to catch go-lives the eventsub
+/// notification was missed for, e.g. while the webhook was unreachable.
+pub async fn run_poller(state: AppState, interval: Duration) {
+    info!(
+        "Starting stream poller with an interval of {}s",
+        interval.as_secs()
+    );
+
+    let grace = interval * OFFLINE_POLLS;
+    let mut interval = actix_web::rt::time::interval(interval);
+    let mut misses = HashMap::new();
+
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {}
+            _ = state.shutdown.triggered() => break,
+        }
+
+        if let Err(e) = poll_streams(&state, &mut misses, grace).await {
+            error!("Could not poll streams: {e}");
+        }
+    }
+
+    info!("Stream poller stopped");
+}
+
+/// Polls the streams of all tracked users. `misses` counts the polls in a row every offline
+/// user was missing from, sessions younger than `grace` are kept open.
+async fn poll_streams(
+    state: &AppState,
+    misses: &mut HashMap<i64, u32>,
+    grace: Duration,
+) -> Result<()> {
+    let user_ids = sqlx::query("SELECT id FROM twitch_users")
+        .fetch_all(&state.db)
+        .await?
+        .iter()
+        .map(|row| row.get::<i64, &str>("id"))
+        .collect::<Vec<_>>();
+
+    let (live_streams, offline_users) = split_streams(state.fetch_streams(&user_ids).await?);
+
+    // One failed announcement must not keep the other streams from being announced
+    for stream in live_streams {
+        if let Err(e) = state.announce_stream(&stream).await {
+            error!(
+                "Could not announce stream {} of user {}: {e}",
+                stream.id, stream.user_id
+            );
+        }
+    }
+
+    let ended_users = count_misses(misses, offline_users);
+
+    state.close_stream_sessions(&ended_users, grace).await
+}
+
+/// Counts another miss of the offline users, forgetting users that are live again or not
+/// tracked anymore. Returns the users that were missing for [`OFFLINE_POLLS`] polls.
+fn count_misses(misses: &mut HashMap<i64, u32>, offline_users: Vec<i64>) -> Vec<i64> {
+    let previous = std::mem::take(misses);
+
+    for user_id in offline_users {
+        misses.insert(user_id, previous.get(&user_id).copied().unwrap_or(0) + 1);
+    }
+
+    misses
+        .iter()
+        .filter(|(_, count)| **count >= OFFLINE_POLLS)
+        .map(|(user_id, _)| *user_id)
+        .collect()
+}
+
+/// Splits the polled users into the streams that are live and the users that are offline.
+fn split_streams(streams: HashMap<i64, Option<StreamData>>) -> (Vec<StreamData>, Vec<i64>) {
+    let mut live_streams = vec![];
+    let mut offline_users = vec![];
+
+    for (user_id, stream) in streams {
+        match stream {
+            Some(stream) => live_streams.push(stream),
+            None => offline_users.push(user_id),
+        }
+    }
+
+    (live_streams, offline_users)
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Utc;
+
+    use super::*;
+
+    fn stream(user_id: i64) -> StreamData {
+        StreamData {
+            id: user_id * 10,
+            user_id,
+            user_login: format!("user{user_id}"),
+            user_name: format!("User{user_id}"),
+            game_id: None,
+            game_name: String::new(),
+            title: String::new(),
+            viewer_count: 0,
+            started_at: Utc::now(),
+            thumbnail_url: String::new(),
+        }
+    }
+
+    #[test]
+    fn splits_live_streams_from_offline_users() {
+        let streams = HashMap::from([(1, Some(stream(1))), (2, None), (3, Some(stream(3)))]);
+
+        let (live_streams, offline_users) = split_streams(streams);
+        let mut live_users = live_streams.iter().map(|s| s.user_id).collect::<Vec<_>>();
+        live_users.sort();
+
+        assert_eq!(live_users, [1, 3]);
+        assert_eq!(offline_users, [2]);
+    }
+
+    #[test]
+    fn closes_sessions_after_consecutive_misses() {
+        let mut misses = HashMap::new();
+
+        for _ in 1..OFFLINE_POLLS {
+            assert!(count_misses(&mut misses, vec![1, 2]).is_empty());
+        }
+
+        // User 2 went live meanwhile, so their count starts over
+        assert_eq!(count_misses(&mut misses, vec![1]), [1]);
+        assert!(count_misses(&mut misses, vec![2]).is_empty());
+        assert_eq!(misses, HashMap::from([(2, 1)]));
+    }
+}
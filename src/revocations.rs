@@ -0,0 +1,271 @@
+use actix_web::web::Data;
+use log::{error, info, warn};
+use sqlx::Row;
+use url::form_urlencoded;
+
+use crate::dispatcher::{BotEndpoint, DELIVERY_LEASE};
+use crate::routes::twitch::structs::{EventsubStatus, TwitchSubscriptionData};
+use crate::structs::{AppState, Result};
+
+/// Records a revoked eventsub and recovers from it where possible. Eventsubs a new one can
+/// replace are registered again in the background. Users whose account was removed are
+/// dropped and their guilds are told through the bot, if it has an endpoint for removed
+/// users. Revocations are recorded once, so a redelivery after handling failed is handled
+/// again without another record. Other revocations, like a revoked
+/// authorization, are left to be reconciled once their cause is fixed.
+pub(crate) async fn handle_revocation(
+    state: &Data<AppState>,
+    subscription: TwitchSubscriptionData,
+) -> Result<()> {
+    let status = subscription.status;
+    let user_id = subscription.condition.broadcaster_user_id;
+
+    warn!(
+        "Eventsub {} of user {} was revoked with status {}",
+        subscription.id,
+        user_id.map_or_else(|| "-".to_string(), |id| id.to_string()),
+        status.as_str()
+    );
+
+    sqlx::query(
+        "INSERT INTO eventsub_revocations (eventsub_id, user_id, status) VALUES ($1, $2, $3) ON CONFLICT (eventsub_id, status) DO NOTHING",
+    )
+    .bind(subscription.id.as_str())
+    .bind(user_id)
+    .bind(status.as_str())
+    .execute(&state.db)
+    .await?;
+
+    let revocation_id =
+        sqlx::query("SELECT id FROM eventsub_revocations WHERE eventsub_id = $1 AND status = $2")
+            .bind(subscription.id.as_str())
+            .bind(status.as_str())
+            .fetch_one(&state.db)
+            .await?
+            .get::<i64, &str>("id");
+
+    sqlx::query(
+        "UPDATE eventsub_statuses SET status = $2, updated_at = now() WHERE eventsub_id = $1",
+    )
+    .bind(subscription.id.as_str())
+    .bind(status.as_str())
+    .execute(&state.db)
+    .await?;
+
+    // Only the eventsub a user is tracked with matters, not e.g. one replaced in a migration
+    let Some(user_id) = user_id else {
+        return Ok(());
+    };
+    let tracked = sqlx::query("SELECT 1 FROM twitch_users WHERE id = $1 AND eventsub_id = $2")
+        .bind(user_id)
+        .bind(subscription.id.as_str())
+        .fetch_optional(&state.db)
+        .await?;
+    if tracked.is_none() {
+        return Ok(());
+    }
+
+    match recovery(&status) {
+        Recovery::RemoveUser => state.remove_user(user_id).await?,
+        Recovery::Resubscribe => {
+            // Registering triggers a verification request, which must not wait on this one
+            let task_state = state.clone();
+            state.shutdown.spawn(async move {
+                if let Err(e) = task_state
+                    .resubscribe(user_id, &subscription.id, revocation_id)
+                    .await
+                {
+                    error!("Could not resubscribe user {user_id} after the revocation: {e}");
+                }
+            });
+        }
+        Recovery::Reconcile => warn!(
+            "Eventsub of user {user_id} can't be replaced automatically, reconcile the eventsubs once the cause is fixed"
+        ),
+    }
+
+    Ok(())
+}
+
+#[derive(Debug, PartialEq)]
+enum Recovery {
+    /// The account is gone, the user is dropped.
+    RemoveUser,
+    /// A new eventsub replaces the revoked one.
+    Resubscribe,
+    /// The cause has to be fixed first, e.g. the user revoking the authorization of the app,
+    /// as a new eventsub would be revoked for the same reason.
+    Reconcile,
+}
+
+fn recovery(status: &EventsubStatus) -> Recovery {
+    match status {
+        EventsubStatus::UserRemoved => Recovery::RemoveUser,
+        EventsubStatus::NotificationFailuresExceeded | EventsubStatus::VersionRemoved => {
+            Recovery::Resubscribe
+        }
+        _ => Recovery::Reconcile,
+    }
+}
+
+/// Queries telling the guilds that were notified about a user that the account is gone.
+fn user_removed_queries(user_id: i64, user_name: &str, guild_ids: &[i64]) -> Vec<String> {
+    guild_ids
+        .iter()
+        .map(|guild_id| user_removed_query(user_id, user_name, *guild_id))
+        .collect()
+}
+
+/// Query string telling the removed users endpoint of the bot that the account of a user the
+/// guild is notified about is gone.
+fn user_removed_query(user_id: i64, user_name: &str, guild_id: i64) -> String {
+    form_urlencoded::Serializer::new(String::new())
+        .append_pair("user_id", &user_id.to_string())
+        .append_pair("user_name", user_name)
+        .append_pair("guild_id", &guild_id.to_string())
+        .finish()
+}
+
+impl AppState {
+    /// Registers a new eventsub for a user whose eventsub was revoked.
+    async fn resubscribe(&self, user_id: i64, eventsub_id: &str, revocation_id: i64) -> Result<()> {
+        let new_eventsub_id = self.register_eventsub(user_id).await?;
+
+        sqlx::query(
+            "UPDATE twitch_users SET eventsub_id = $2, eventsub_secret = $3 WHERE id = $1 AND eventsub_id = $4",
+        )
+        .bind(user_id)
+        .bind(new_eventsub_id.as_str())
+        .bind(self.eventsub_secret_fingerprint())
+        .bind(eventsub_id)
+        .execute(&self.db)
+        .await?;
+
+        sqlx::query("UPDATE eventsub_revocations SET resubscribed_eventsub_id = $2 WHERE id = $1")
+            .bind(revocation_id)
+            .bind(new_eventsub_id.as_str())
+            .execute(&self.db)
+            .await?;
+
+        info!("Replaced revoked eventsub {eventsub_id} of user {user_id} with {new_eventsub_id}");
+
+        Ok(())
+    }
+
+    /// Deletes a user whose account was removed along with their notifications, and tells
+    /// every guild that was notified about them through the removed users endpoint of the
+    /// bot, if it is configured.
+    async fn remove_user(&self, user_id: i64) -> Result<()> {
+        let mut transaction = self.db.begin().await?;
+
+        let guild_ids = sqlx::query("SELECT guild_id FROM twitch_notifications WHERE user_id = $1")
+            .bind(user_id)
+            .fetch_all(&mut transaction)
+            .await?
+            .iter()
+            .map(|row| row.get::<i64, &str>("guild_id"))
+            .collect::<Vec<_>>();
+
+        // The notifications of the user are deleted along with them
+        let Some(user) = sqlx::query("DELETE FROM twitch_users WHERE id = $1 RETURNING username")
+            .bind(user_id)
+            .fetch_optional(&mut transaction)
+            .await?
+        else {
+            return Ok(());
+        };
+        let user_name = user.get::<String, &str>("username");
+
+        let queries = if self.bot_endpoint_url(BotEndpoint::UserRemoved).is_some() {
+            user_removed_queries(user_id, &user_name, &guild_ids)
+        } else {
+            info!("Not telling the guilds about removed user {user_id}, BOT_USER_REMOVED_URL is not set");
+            vec![]
+        };
+
+        let mut deliveries = vec![];
+        for query in queries {
+            // Leased right away, as it is handed to the queue directly
+            let delivery_id = sqlx::query(
+                "INSERT INTO deliveries (endpoint, query, next_attempt_at) VALUES ($1, $2, now() + make_interval(secs => $3)) RETURNING id",
+            )
+            .bind(BotEndpoint::UserRemoved.as_str())
+            .bind(query.as_str())
+            .bind(DELIVERY_LEASE.as_secs_f64())
+            .fetch_one(&mut transaction)
+            .await?
+            .get::<i64, &str>("id");
+
+            deliveries.push((delivery_id, query));
+        }
+
+        transaction.commit().await?;
+
+        info!(
+            "Removed user {user_id} whose account is gone, telling {} guilds",
+            deliveries.len()
+        );
+
+        for (delivery_id, query) in deliveries {
+            self.queue_delivery(delivery_id, BotEndpoint::UserRemoved, query)
+                .await?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encodes_user_removed_query() {
+        assert_eq!(
+            user_removed_query(1337, "Cool User&Co", 42),
+            "user_id=1337&user_name=Cool+User%26Co&guild_id=42"
+        );
+    }
+
+    #[test]
+    fn tells_every_guild_about_removed_user() {
+        assert_eq!(
+            user_removed_queries(1337, "user", &[1, 2]),
+            [
+                "user_id=1337&user_name=user&guild_id=1",
+                "user_id=1337&user_name=user&guild_id=2",
+            ]
+        );
+        assert!(user_removed_queries(1337, "user", &[]).is_empty());
+    }
+
+    #[test]
+    fn removes_user_whose_account_is_gone() {
+        assert_eq!(recovery(&EventsubStatus::UserRemoved), Recovery::RemoveUser);
+    }
+
+    #[test]
+    fn resubscribes_replaceable_eventsubs() {
+        assert_eq!(
+            recovery(&EventsubStatus::NotificationFailuresExceeded),
+            Recovery::Resubscribe
+        );
+        assert_eq!(
+            recovery(&EventsubStatus::VersionRemoved),
+            Recovery::Resubscribe
+        );
+    }
+
+    #[test]
+    fn leaves_revoked_authorization_to_reconcile() {
+        assert_eq!(
+            recovery(&EventsubStatus::AuthorizationRevoked),
+            Recovery::Reconcile
+        );
+        assert_eq!(
+            recovery(&EventsubStatus::ModeratorRemoved),
+            Recovery::Reconcile
+        );
+        assert_eq!(recovery(&EventsubStatus::Unknown), Recovery::Reconcile);
+    }
+}
@@ -0,0 +1,174 @@
+use std::collections::BTreeMap;
+
+use actix_web::{get, web, HttpResponse};
+use log::warn;
+use serde::Serialize;
+use sqlx::Row;
+
+use crate::errors::Error;
+use crate::routes::method_not_allowed;
+use crate::structs::{AppState, Result};
+
+/// Deliveries pending longer than this make the service unready.
+const STUCK_DELIVERY_AGE_MINUTES: i32 = 10;
+
+#[derive(Serialize)]
+struct HealthResponse {
+    status: CheckStatus,
+    checks: BTreeMap<&'static str, CheckResult>,
+}
+
+#[derive(Serialize)]
+struct CheckResult {
+    status: CheckStatus,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    message: Option<String>,
+}
+
+#[derive(Serialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum CheckStatus {
+    Ok,
+    Failing,
+}
+
+impl CheckResult {
+    /// Result of a check. The endpoint is public, so a failure is only described with the
+    /// given message and the error itself is logged.
+    fn new(name: &str, result: Result<()>, message: &str) -> Self {
+        match result {
+            Ok(_) => Self {
+                status: CheckStatus::Ok,
+                message: None,
+            },
+            Err(e) => {
+                warn!("Readiness check {name} failed: {e}");
+
+                Self {
+                    status: CheckStatus::Failing,
+                    message: Some(message.to_string()),
+                }
+            }
+        }
+    }
+}
+
+impl HealthResponse {
+    fn new(checks: BTreeMap<&'static str, CheckResult>) -> Self {
+        let status = if checks.values().all(|c| c.status == CheckStatus::Ok) {
+            CheckStatus::Ok
+        } else {
+            CheckStatus::Failing
+        };
+
+        Self { status, checks }
+    }
+
+    fn into_response(self) -> HttpResponse {
+        match self.status {
+            CheckStatus::Ok => HttpResponse::Ok().json(self),
+            CheckStatus::Failing => HttpResponse::ServiceUnavailable().json(self),
+        }
+    }
+}
+
+/// # Liveness
+/// Reports that the process is up and serving requests.
+/// ## Responses
+/// - 200 Process is up
+#[get("healthz")]
+async fn healthz() -> HttpResponse {
+    HealthResponse::new(BTreeMap::new()).into_response()
+}
+
+/// # Readiness
+/// Checks that the database is reachable, a Twitch app token is available and no
+/// deliveries to the bot are stuck.
+/// ## Responses
+/// - 200 All checks passed
+/// - 503 At least one check failed
+#[get("readyz")]
+async fn readyz(state: web::Data<AppState>) -> HttpResponse {
+    let mut checks = BTreeMap::new();
+
+    let database = sqlx::query("SELECT 1")
+        .execute(&state.db)
+        .await
+        .map(|_| ())
+        .map_err(Error::from);
+    checks.insert(
+        "database",
+        CheckResult::new("database", database, "The database is not reachable"),
+    );
+
+    let twitch = state.get_access_token().await.map(|_| ());
+    checks.insert(
+        "twitch",
+        CheckResult::new("twitch", twitch, "No Twitch app access token is available"),
+    );
+
+    let deliveries = stuck_deliveries(&state)
+        .await
+        .and_then(|stuck| match stuck {
+            0 => Ok(()),
+            n => Err(Error::InternalServer(format!(
+                "{n} deliveries are pending for more than {} minutes",
+                STUCK_DELIVERY_AGE_MINUTES
+            ))),
+        });
+    checks.insert(
+        "deliveries",
+        CheckResult::new(
+            "deliveries",
+            deliveries,
+            "Deliveries to the bot are pending for too long",
+        ),
+    );
+
+    HealthResponse::new(checks).into_response()
+}
+
+async fn stuck_deliveries(state: &AppState) -> Result<i64> {
+    let row = sqlx::query(
+        "SELECT count(*) AS stuck FROM deliveries WHERE created_at < now() - make_interval(mins => $1)",
+    )
+    .bind(STUCK_DELIVERY_AGE_MINUTES)
+    .fetch_one(&state.db)
+    .await?;
+
+    Ok(row.get("stuck"))
+}
+
+pub fn init_health_routes(cfg: &mut web::ServiceConfig) {
+    cfg.service(healthz)
+        .service(readyz)
+        .service(method_not_allowed(&["healthz", "readyz"]));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hides_details_of_failed_checks() {
+        let error = Error::SQLx(sqlx::Error::Protocol(
+            "password authentication failed for user notificator".to_string(),
+        ));
+        let result = CheckResult::new("database", Err(error), "The database is not reachable");
+
+        assert_eq!(
+            serde_json::to_value(result).unwrap(),
+            serde_json::json!({"status": "failing", "message": "The database is not reachable"})
+        );
+    }
+
+    #[test]
+    fn omits_message_of_passed_checks() {
+        let result = CheckResult::new("database", Ok(()), "The database is not reachable");
+
+        assert_eq!(
+            serde_json::to_value(result).unwrap(),
+            serde_json::json!({"status": "ok"})
+        );
+    }
+}
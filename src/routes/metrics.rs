@@ -0,0 +1,46 @@
+use actix_web::{get, web, HttpResponse};
+use prometheus::{Encoder, TextEncoder};
+use sqlx::Row;
+
+use crate::errors::Error;
+use crate::metrics::{
+    ACTIVE_SUBSCRIPTIONS, DB_POOL_CONNECTIONS, DB_POOL_IDLE_CONNECTIONS, LIVE_STREAMS,
+    TRACKED_STREAMERS,
+};
+use crate::routes::method_not_allowed;
+use crate::structs::{AppState, Result};
+
+/// # Metrics
+/// Exposes all metrics in the Prometheus text format.
+/// ## Responses
+/// - 200 Metrics
+/// - 500 Internal server error
+#[get("metrics")]
+async fn metrics(state: web::Data<AppState>) -> Result<HttpResponse> {
+    let counts = sqlx::query(
+        "SELECT (SELECT count(*) FROM twitch_users) AS streamers, (SELECT count(DISTINCT eventsub_id) FROM twitch_users) AS subscriptions, (SELECT count(*) FROM twitch_streams WHERE ended_at IS NULL) AS live_streams",
+    )
+    .fetch_one(&state.db)
+    .await?;
+
+    TRACKED_STREAMERS.set(counts.get::<i64, &str>("streamers"));
+    ACTIVE_SUBSCRIPTIONS.set(counts.get::<i64, &str>("subscriptions"));
+    LIVE_STREAMS.set(counts.get::<i64, &str>("live_streams"));
+    DB_POOL_CONNECTIONS.set(state.db.size() as i64);
+    DB_POOL_IDLE_CONNECTIONS.set(state.db.num_idle() as i64);
+
+    let encoder = TextEncoder::new();
+    let mut buffer = vec![];
+    encoder
+        .encode(&prometheus::gather(), &mut buffer)
+        .map_err(|e| Error::InternalServer(e.to_string()))?;
+
+    Ok(HttpResponse::Ok()
+        .content_type(encoder.format_type())
+        .body(buffer))
+}
+
+pub fn init_metrics_routes(cfg: &mut web::ServiceConfig) {
+    cfg.service(metrics)
+        .service(method_not_allowed(&["metrics"]));
+}
@@ -1,7 +1,11 @@
 use actix_web::body::BoxBody;
+use actix_web::error::{JsonPayloadError, PathError, QueryPayloadError};
 use actix_web::http::StatusCode;
-use actix_web::{HttpResponse, ResponseError};
+use actix_web::{web, HttpRequest, HttpResponse, Resource, ResponseError};
+use log::error;
 
+pub use health::init_health_routes;
+pub use metrics::init_metrics_routes;
 pub use notifications::init_twitch_routes;
 pub use twitch::auth::init_auth_routes;
 pub use twitch::service::init_service_routes;
@@ -9,37 +13,174 @@ pub use twitch::service::init_service_routes;
 use crate::errors::Error;
 use crate::structs::ErrorResponse;
 
-mod notifications;
-mod twitch;
+mod health;
+mod metrics;
+pub(crate) mod notifications;
+pub mod twitch;
 
 impl ResponseError for Error {
     fn status_code(&self) -> StatusCode {
         match self {
-            Error::BadRequest(_) => StatusCode::BAD_REQUEST,
+            Error::BadRequest(_)
+            | Error::InvalidBody(_)
+            | Error::InvalidHeader(_)
+            | Error::ExpiredMessage
+            | Error::InvalidOauthCode
+            | Error::UnknownNotification => StatusCode::BAD_REQUEST,
+            Error::InvalidSignature => StatusCode::UNAUTHORIZED,
+            Error::MethodNotAllowed => StatusCode::METHOD_NOT_ALLOWED,
             Error::Conflict => StatusCode::CONFLICT,
-            Error::Twitch(_) => StatusCode::SERVICE_UNAVAILABLE,
-            _ => StatusCode::INTERNAL_SERVER_ERROR,
+            Error::PayloadTooLarge => StatusCode::PAYLOAD_TOO_LARGE,
+            Error::Awc(_)
+            | Error::Twitch(_)
+            | Error::TwitchUnavailable(_)
+            | Error::ShuttingDown
+            | Error::EventsubBudgetExceeded => StatusCode::SERVICE_UNAVAILABLE,
+            Error::InternalServer(_) | Error::Mutex | Error::SQLx(_) => {
+                StatusCode::INTERNAL_SERVER_ERROR
+            }
         }
     }
 
     fn error_response(&self) -> HttpResponse<BoxBody> {
-        match self {
-            Error::BadRequest(e) => HttpResponse::BadRequest().json(ErrorResponse {
-                code: StatusCode::BAD_REQUEST,
-                message: e.to_string(),
-            }),
-            Error::Conflict => HttpResponse::Conflict().json(ErrorResponse {
-                code: StatusCode::CONFLICT,
-                message: self.to_string(),
-            }),
-            Error::Twitch(_) => HttpResponse::ServiceUnavailable().json(ErrorResponse {
-                code: StatusCode::SERVICE_UNAVAILABLE,
-                message: self.to_string(),
-            }),
-            _ => HttpResponse::InternalServerError().json(ErrorResponse {
-                code: StatusCode::INTERNAL_SERVER_ERROR,
-                message: self.to_string(),
-            }),
+        let code = self.status_code();
+        if code.is_server_error() {
+            error!("Request failed: {self}");
+        }
+
+        HttpResponse::build(code).json(ErrorResponse {
+            code,
+            error_code: self.error_code(),
+            message: self.public_message(),
+        })
+    }
+}
+
+/// Answers requests to the paths with a method no handler is registered for. Has to be
+/// registered after the handlers, as their resources only match their own method.
+fn method_not_allowed(paths: &[&str]) -> Resource {
+    web::resource(paths.to_vec()).to(|| async { Err::<HttpResponse, _>(Error::MethodNotAllowed) })
+}
+
+fn json_error_handler(err: JsonPayloadError, _req: &HttpRequest) -> actix_web::Error {
+    match err {
+        JsonPayloadError::Overflow { .. } | JsonPayloadError::OverflowKnownLength { .. } => {
+            Error::PayloadTooLarge.into()
+        }
+        e => Error::InvalidBody(e.to_string()).into(),
+    }
+}
+
+fn path_error_handler(err: PathError, _req: &HttpRequest) -> actix_web::Error {
+    Error::BadRequest(err.to_string()).into()
+}
+
+fn query_error_handler(err: QueryPayloadError, _req: &HttpRequest) -> actix_web::Error {
+    Error::BadRequest(err.to_string()).into()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_utils::test_state;
+    use actix_web::test::{call_service, init_service, read_body_json, TestRequest};
+    use actix_web::App;
+    use serde_json::Value;
+
+    use super::*;
+
+    #[test]
+    fn maps_errors_to_status_codes() {
+        let statuses = [
+            (Error::UnknownNotification, StatusCode::BAD_REQUEST),
+            (Error::InvalidSignature, StatusCode::UNAUTHORIZED),
+            (Error::Conflict, StatusCode::CONFLICT),
+            (Error::MethodNotAllowed, StatusCode::METHOD_NOT_ALLOWED),
+            (Error::PayloadTooLarge, StatusCode::PAYLOAD_TOO_LARGE),
+            (
+                Error::TwitchUnavailable(503),
+                StatusCode::SERVICE_UNAVAILABLE,
+            ),
+            (
+                Error::EventsubBudgetExceeded,
+                StatusCode::SERVICE_UNAVAILABLE,
+            ),
+            (Error::Mutex, StatusCode::INTERNAL_SERVER_ERROR),
+        ];
+
+        for (error, status) in statuses {
+            assert_eq!(error.status_code(), status, "{error}");
         }
     }
+
+    /// Sends the request to the service routes. The requests are rejected before a handler
+    /// runs, so the pool never connects.
+    async fn send(req: TestRequest) -> (StatusCode, Value) {
+        let app = init_service(
+            App::new()
+                .app_data(web::Data::new(test_state()))
+                .configure(init_service_routes)
+                .configure(init_auth_routes),
+        )
+        .await;
+
+        let res = call_service(&app, req.to_request()).await;
+        let status = res.status();
+
+        (status, read_body_json(res).await)
+    }
+
+    #[actix_web::test]
+    async fn rejects_unknown_method() {
+        let (status, body) = send(TestRequest::put().uri("/service/twitch/notifications")).await;
+
+        assert_eq!(status, StatusCode::METHOD_NOT_ALLOWED);
+        assert_eq!(body["error_code"], "method_not_allowed");
+        assert_eq!(body["code"], 405);
+    }
+
+    #[actix_web::test]
+    async fn rejects_invalid_json() {
+        let (status, body) = send(
+            TestRequest::post()
+                .uri("/service/twitch/notifications")
+                .insert_header(("content-type", "application/json"))
+                .set_payload("{\"code\":"),
+        )
+        .await;
+
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["error_code"], "invalid_body");
+    }
+
+    #[actix_web::test]
+    async fn rejects_large_body() {
+        let (status, body) = send(
+            TestRequest::post()
+                .uri("/service/twitch/notifications")
+                .insert_header(("content-type", "application/json"))
+                // Above the default limit of 2 MiB of the JSON extractor
+                .set_payload(vec![b' '; 3 * 1024 * 1024]),
+        )
+        .await;
+
+        assert_eq!(status, StatusCode::PAYLOAD_TOO_LARGE);
+        assert_eq!(body["error_code"], "payload_too_large");
+    }
+
+    #[actix_web::test]
+    async fn rejects_invalid_path() {
+        let (status, body) =
+            send(TestRequest::delete().uri("/service/twitch/notifications/abc")).await;
+
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["error_code"], "bad_request");
+    }
+
+    #[actix_web::test]
+    async fn rejects_missing_query() {
+        let (status, body) = send(TestRequest::get().uri("/service/twitch/auth")).await;
+
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["error_code"], "bad_request");
+    }
 }
@@ -1,127 +1,286 @@
-use actix_web::{guard, HttpRequest, HttpResponse, post, web};
-use actix_web::guard::GuardContext;
-use awc::error::StatusCode;
-use hmac::{Hmac, Mac};
-use log::{error, warn};
-use sha2::Sha256;
+use actix_web::{post, web, HttpResponse};
+use futures_util::future::LocalBoxFuture;
+use log::error;
 
-use crate::errors::Error;
-use crate::structs::{AppState, ErrorResponse, Result};
+use crate::eventsub::{EventsubPayload, VerifiedEventsub};
+use crate::revocations;
+use crate::structs::{AppState, Result};
 
+use super::method_not_allowed;
 use super::twitch::structs::{
-    EventsubRevocationPayload, TwitchChallengePayload, TwitchNotificationPayload,
-    TwitchSubscriptionStatus,
+    EventsubRevocationPayload, TwitchChallengePayload, TwitchSubscriptionStatus,
 };
 
+/// Body of an eventsub message, parsed according to its type. Notifications are parsed by
+/// their handler, if there is one for the subscription type.
+pub(crate) enum EventsubMessage {
+    Challenge(TwitchChallengePayload),
+    Notification(Option<LocalBoxFuture<'static, Result<()>>>),
+    Revocation(EventsubRevocationPayload),
+    Unknown,
+}
+
+impl EventsubPayload for EventsubMessage {
+    fn parse(state: &web::Data<AppState>, message_type: &str, body: &[u8]) -> Result<Self> {
+        let message =
+            if message_type == TwitchSubscriptionStatus::WebhookCallbackVerification.as_str() {
+                EventsubMessage::Challenge(serde_json::from_slice(body)?)
+            } else if message_type == TwitchSubscriptionStatus::Notification.as_str() {
+                EventsubMessage::Notification(
+                    state
+                        .handlers
+                        .prepare(state, serde_json::from_slice(body)?)?,
+                )
+            } else if message_type == TwitchSubscriptionStatus::Revocation.as_str() {
+                EventsubMessage::Revocation(serde_json::from_slice(body)?)
+            } else {
+                EventsubMessage::Unknown
+            };
+
+        Ok(message)
+    }
+}
+
 #[post("twitch")]
 async fn handle_eventsub(
-    request: HttpRequest,
     state: web::Data<AppState>,
-    body: web::Bytes,
+    message: VerifiedEventsub<EventsubMessage>,
 ) -> Result<HttpResponse> {
-    let headers = request.headers();
-
-    let message_id = headers
-        .get("twitch-eventsub-message-id")
-        .unwrap()
-        .to_str()
-        .unwrap();
-    let message_signature = headers
-        .get("twitch-eventsub-message-signature")
-        .unwrap()
-        .to_str()
-        .unwrap();
-    let message_timestamp = headers
-        .get("twitch-eventsub-message-timestamp")
-        .unwrap()
-        .to_str()
-        .unwrap();
-    let message_type = headers
-        .get("twitch-eventsub-message-type")
-        .unwrap()
-        .to_str()
-        .unwrap();
-
-    if message_signature.len() < 10 {
-        error!(
-            "Received invalid signature from request: {}",
-            message_signature.len()
-        );
-
-        return Err(Error::BadRequest("Invalid signature received.".to_string()));
-    }
-
-    let shorted_signature = message_signature[7..message_signature.len()].to_owned();
-    let body_bytes = String::from_utf8(body.to_vec());
-    if body_bytes.is_err() {
-        error!("Could not decode body of eventsub.");
-
-        return Err(Error::InternalServer(
-            "Could not decode body of eventsub.".to_string(),
-        ));
-    }
-
-    let body_str = body_bytes.unwrap().as_str().to_string();
-    let mac_message = message_id.to_string() + message_timestamp + body_str.as_str();
-
-    let mut mac = Hmac::<Sha256>::new_from_slice(state.twitch.eventsub_secret.as_bytes()).unwrap();
-    mac.update(mac_message.as_bytes());
-
-    let decoded_signature = hex::decode(shorted_signature).unwrap();
-    if mac.verify_slice(&decoded_signature).is_err() {
-        return Ok(HttpResponse::Unauthorized().json(ErrorResponse {
-            code: StatusCode::UNAUTHORIZED,
-            message: "Invalid signature provided.".to_string(),
-        }));
-    }
-
-    if message_type == TwitchSubscriptionStatus::WebhookCallbackVerification.as_str() {
-        let data = serde_json::from_str::<TwitchChallengePayload>(body_str.as_str())?;
-
-        return Ok(HttpResponse::Ok().body(data.challenge));
-    } else if message_type == TwitchSubscriptionStatus::Notification.as_str() {
-        let data = serde_json::from_str::<TwitchNotificationPayload>(body_str.as_str())?;
-        let stream_data = state
-            .fetch_stream_data(data.event.broadcaster_user_id)
-            .await?;
-
-        let bot_url = format!(
-            "{}?user_id={}&user_name={}&game_name={}&viewer_count={}&started_at={}&thumbnail_url={}&title={}",
-            state.bot_url, stream_data.user_id, stream_data.user_login, stream_data.game_name.as_str(), stream_data.viewer_count,
-            stream_data.started_at.as_str(), stream_data.thumbnail_url.as_str(), stream_data.title.as_str(),
-        );
-        let req = state.client.get(bot_url);
-
-        actix_web::rt::spawn(async move {
-            if req.send().await.is_err() {
-                warn!("An error occurred while sending twitch notification to bot");
-            }
-        });
-    } else if message_type == TwitchSubscriptionStatus::Revocation.as_str() {
-        let data = serde_json::from_str::<EventsubRevocationPayload>(body_str.as_str())?;
-
-        sqlx::query("DELETE FROM twitch_users WHERE id = $1")
-            .bind(data.subscription.condition.broadcaster_user_id)
-            .execute(&state.db)
-            .await?;
-    }
-
-    Ok(HttpResponse::Ok().finish())
+    let response = message
+        .once(state.get_ref(), |payload| answer_message(&state, payload))
+        .await?;
+
+    // Retries of handled messages are only acknowledged
+    Ok(response.unwrap_or_else(|| HttpResponse::Ok().finish()))
 }
 
-fn route_guard(ctx: &GuardContext) -> bool {
-    let h = ctx.head().headers();
+async fn answer_message(
+    state: &web::Data<AppState>,
+    message: EventsubMessage,
+) -> Result<HttpResponse> {
+    match message {
+        EventsubMessage::Challenge(data) => Ok(HttpResponse::Ok().body(data.challenge)),
+        message => {
+            process_message(state, message).await?;
 
-    h.contains_key("twitch-eventsub-message-id")
-        && h.contains_key("twitch-eventsub-message-signature")
-        && h.contains_key("twitch-eventsub-message-timestamp")
-        && h.contains_key("twitch-eventsub-message-type")
+            Ok(HttpResponse::Ok().finish())
+        }
+    }
+}
+
+/// Handles a verified message that has not been handled before, regardless of the transport
+/// it was received with. Runs through [`crate::eventsub::handle_once`].
+pub(crate) async fn process_message(
+    state: &web::Data<AppState>,
+    message: EventsubMessage,
+) -> Result<()> {
+    match message {
+        EventsubMessage::Notification(Some(handler)) => {
+            // Acknowledge the notification right away, handlers like the stream announcement
+            // may take a while
+            state.shutdown.spawn(async move {
+                if let Err(e) = handler.await {
+                    error!("Could not handle eventsub notification: {e}");
+                }
+            });
+        }
+        EventsubMessage::Revocation(data) => {
+            revocations::handle_revocation(state, data.subscription).await?
+        }
+        EventsubMessage::Challenge(_)
+        | EventsubMessage::Notification(None)
+        | EventsubMessage::Unknown => {}
+    }
+
+    Ok(())
 }
 
 pub fn init_twitch_routes(cfg: &mut web::ServiceConfig) {
     cfg.service(
         web::scope("_notify")
-            .guard(guard::fn_guard(route_guard))
-            .service(handle_eventsub),
+            .service(handle_eventsub)
+            .service(method_not_allowed(&["twitch"])),
     );
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::eventsub::sign_message;
+    use crate::test_utils::test_state;
+    use actix_web::http::StatusCode;
+    use actix_web::{test, App};
+    use chrono::{SecondsFormat, Utc};
+    use serde_json::Value;
+
+    use super::*;
+
+    const SECRET: &str = "s3cRe7s3cRe7";
+
+    fn now() -> String {
+        Utc::now().to_rfc3339_opts(SecondsFormat::Nanos, true)
+    }
+
+    fn sign(message_id: &str, timestamp: &str, body: &str) -> String {
+        sign_message(SECRET, message_id, timestamp, body.as_bytes())
+    }
+
+    /// Sends the request to the route. None of the requests get far enough to use the
+    /// database, so the pool never connects.
+    async fn call(req: test::TestRequest) -> (StatusCode, Value) {
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(test_state()))
+                .configure(init_twitch_routes),
+        )
+        .await;
+
+        let res = test::call_service(&app, req.uri("/_notify/twitch").to_request()).await;
+        let status = res.status();
+
+        (status, test::read_body_json(res).await)
+    }
+
+    async fn send(
+        message_type: &str,
+        timestamp: &str,
+        signature: &str,
+        body: &str,
+    ) -> (StatusCode, Value) {
+        call(
+            test::TestRequest::post()
+                .insert_header(("twitch-eventsub-message-id", "message-id"))
+                .insert_header(("twitch-eventsub-message-signature", signature))
+                .insert_header(("twitch-eventsub-message-timestamp", timestamp))
+                .insert_header(("twitch-eventsub-message-type", message_type))
+                .set_payload(body.to_string()),
+        )
+        .await
+    }
+
+    #[actix_web::test]
+    async fn rejects_signature_without_prefix() {
+        let timestamp = now();
+        let signature = sign("message-id", &timestamp, "{}");
+        let (status, body) = send("notification", &timestamp, &signature[7..], "{}").await;
+
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        assert_eq!(body["error_code"], "invalid_signature");
+    }
+
+    #[actix_web::test]
+    async fn rejects_short_signature() {
+        let (status, body) = send("notification", &now(), "sha", "{}").await;
+
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        assert_eq!(body["error_code"], "invalid_signature");
+    }
+
+    #[actix_web::test]
+    async fn rejects_non_hex_signature() {
+        let (status, body) = send("notification", &now(), "sha256=zzzzzzzzzzzz", "{}").await;
+
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        assert_eq!(body["error_code"], "invalid_signature");
+    }
+
+    #[actix_web::test]
+    async fn rejects_non_ascii_header() {
+        let (status, body) = call(
+            test::TestRequest::post()
+                .insert_header(("twitch-eventsub-message-id", "message-id"))
+                .insert_header((
+                    "twitch-eventsub-message-signature",
+                    actix_web::http::header::HeaderValue::from_bytes(b"sha256=\xff").unwrap(),
+                ))
+                .insert_header(("twitch-eventsub-message-timestamp", "2023-01-01T00:00:00Z"))
+                .insert_header(("twitch-eventsub-message-type", "notification")),
+        )
+        .await;
+
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["error_code"], "invalid_header");
+    }
+
+    #[actix_web::test]
+    async fn rejects_notification_with_missing_fields() {
+        let payload = r#"{"subscription":{"id":"f1c2a387-161a-49f9-a165-0f21d7a4e1c4"}}"#;
+        let timestamp = now();
+        let signature = sign("message-id", &timestamp, payload);
+        let (status, body) = send("notification", &timestamp, &signature, payload).await;
+
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["error_code"], "invalid_body");
+    }
+
+    #[actix_web::test]
+    async fn rejects_body_that_is_not_json() {
+        let payload = "<html>not json</html>";
+        let timestamp = now();
+        let signature = sign("message-id", &timestamp, payload);
+        let (status, body) = send(
+            "webhook_callback_verification",
+            &timestamp,
+            &signature,
+            payload,
+        )
+        .await;
+
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["error_code"], "invalid_body");
+    }
+
+    #[actix_web::test]
+    async fn rejects_event_the_handler_cannot_parse() {
+        let payload = r#"{
+            "subscription": {
+                "id": "f1c2a387-161a-49f9-a165-0f21d7a4e1c4",
+                "type": "stream.online",
+                "version": "1",
+                "status": "enabled",
+                "cost": 0,
+                "condition": {"broadcaster_user_id": "1337"},
+                "transport": {"method": "webhook", "callback": "https://example.com/_notify/twitch"},
+                "created_at": "2019-11-16T10:11:12.634234626Z"
+            },
+            "event": {"broadcaster_user_id": "1337"}
+        }"#;
+        let timestamp = now();
+        let signature = sign("message-id", &timestamp, payload);
+        let (status, body) = send("notification", &timestamp, &signature, payload).await;
+
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["error_code"], "invalid_body");
+    }
+
+    #[actix_web::test]
+    async fn rejects_expired_message() {
+        let timestamp = "2023-01-01T00:00:00Z";
+        let signature = sign("message-id", timestamp, "{}");
+        let (status, body) = send("notification", timestamp, &signature, "{}").await;
+
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["error_code"], "expired_message");
+    }
+
+    #[actix_web::test]
+    async fn rejects_invalid_timestamp() {
+        let signature = sign("message-id", "yesterday", "{}");
+        let (status, body) = send("notification", "yesterday", &signature, "{}").await;
+
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["error_code"], "invalid_header");
+    }
+
+    #[actix_web::test]
+    async fn rejects_missing_header() {
+        let (status, body) = call(
+            test::TestRequest::post()
+                .insert_header(("twitch-eventsub-message-id", "message-id"))
+                .insert_header(("twitch-eventsub-message-type", "notification")),
+        )
+        .await;
+
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["error_code"], "invalid_header");
+    }
+}
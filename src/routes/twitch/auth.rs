@@ -1,6 +1,7 @@
 use actix_web::{get, web};
 
 use crate::routes::twitch::structs::StatePayload;
+use crate::routes::{method_not_allowed, query_error_handler};
 use crate::structs::AppState;
 
 #[get("")]
@@ -14,5 +15,10 @@ async fn login_url(state: web::Data<AppState>, req_state: web::Query<StatePayloa
 }
 
 pub fn init_auth_routes(cfg: &mut web::ServiceConfig) {
-    cfg.service(web::scope("service/twitch/auth").service(login_url));
+    cfg.service(
+        web::scope("service/twitch/auth")
+            .app_data(web::QueryConfig::default().error_handler(query_error_handler))
+            .service(login_url)
+            .service(method_not_allowed(&[""])),
+    );
 }
@@ -1,13 +1,25 @@
 use std::collections::HashMap;
 
+use actix_web::web::Bytes;
+use awc::error::PayloadError;
+use awc::ClientResponse;
+use futures_core::Stream;
 use log::{error, warn};
+use serde::de::DeserializeOwned;
 
+use crate::budget::EventsubCost;
+use crate::config::EventsubTransport;
 use crate::errors::Error;
+use crate::eventsub::EventsubEvent;
+use crate::metrics::{track_auth, track_helix, RESULT_FAILURE, RESULT_SUCCESS, TOKEN_REFRESHES};
 use crate::structs::{AppState, Result};
 use crate::utils::current_unix_timestamp;
 
 use self::structs::{
-    AppAccessTokenResponse, CreateTwitchEventsub, EventsubType, TwitchEventsubResponse,
+    AppAccessTokenResponse, ConduitShard, ConduitShardError, ConduitShardUpdate,
+    ConduitShardsResponse, CreateTwitchConduit, CreateTwitchEventsub, StreamOnlineEvent,
+    TwitchConduit, TwitchConduitResponse, TwitchEventsubResponse, UpdateConduitShards,
+    UpdateTwitchConduit,
 };
 use self::structs::{
     EventsubCondition, EventsubTransportData, StreamData, TokenExchangeResponse,
@@ -21,6 +33,40 @@ pub mod structs;
 
 const TWITCH_API_ENDPOINT: &str = "https://api.twitch.tv/helix";
 const TWITCH_AUTH_ENDPOINT: &str = "https://id.twitch.tv";
+/// Maximum amount of `user_id` parameters Helix accepts on `/streams`.
+const STREAMS_BATCH_SIZE: usize = 100;
+
+/// Parses the body of a successful response. Bodies not matching the model are reported
+/// as Twitch errors instead of failing the request with a panic.
+async fn read_json<T, S>(res: &mut ClientResponse<S>) -> Result<T>
+where
+    T: DeserializeOwned,
+    S: Stream<Item = std::result::Result<Bytes, PayloadError>> + Unpin,
+{
+    res.json::<T>()
+        .await
+        .map_err(|e| Error::Twitch(format!("Unexpected response body: {e}")))
+}
+
+/// Describes an error response for the logs. Twitch does not always send JSON errors, e.g.
+/// when a proxy in front of the API fails.
+async fn read_error<S>(res: &mut ClientResponse<S>) -> String
+where
+    S: Stream<Item = std::result::Result<Bytes, PayloadError>> + Unpin,
+{
+    let body = match res.body().await {
+        Ok(body) => body,
+        Err(e) => return format!("Unreadable body: {e}"),
+    };
+
+    if let Ok(e) = serde_json::from_slice::<TwitchApiErrorResponse>(&body) {
+        format!("{} ({})", e.message, e.error)
+    } else if let Ok(e) = serde_json::from_slice::<TwitchAuthErrorResponse>(&body) {
+        e.message
+    } else {
+        String::from_utf8_lossy(&body).into_owned()
+    }
+}
 
 impl AppState {
     async fn fetch_access_token(&self) -> Result<String> {
@@ -29,15 +75,18 @@ impl AppState {
         params.insert("client_secret", self.twitch.client_secret);
         params.insert("grant_type", "client_credentials");
 
-        let mut res = self
-            .client
-            .post(format!("{TWITCH_AUTH_ENDPOINT}/oauth2/token"))
-            .send_form(&params)
-            .await?;
+        let mut res = track_auth(
+            "oauth2/token",
+            self.client
+                .post(format!("{TWITCH_AUTH_ENDPOINT}/oauth2/token"))
+                .send_form(&params),
+        )
+        .await?;
 
         match res.status().as_u16() {
             200 => {
-                let body = res.json::<AppAccessTokenResponse>().await.unwrap();
+                TOKEN_REFRESHES.with_label_values(&[RESULT_SUCCESS]).inc();
+                let body: AppAccessTokenResponse = read_json(&mut res).await?;
 
                 let mut data = self.twitch.app_token.lock().map_err(|_| Error::Mutex)?;
 
@@ -47,15 +96,13 @@ impl AppState {
                 Ok(body.access_token)
             }
             _ => {
-                let body = res.body().await.unwrap();
-                let text = String::from_utf8(body.to_vec())
-                    .map_err(|_| Error::Twitch("Received invalid utf8".to_string()))?;
-                Err(Error::Twitch(text))
+                TOKEN_REFRESHES.with_label_values(&[RESULT_FAILURE]).inc();
+                Err(Error::Twitch(read_error(&mut res).await))
             }
         }
     }
 
-    async fn get_access_token(&self) -> Result<String> {
+    pub async fn get_access_token(&self) -> Result<String> {
         let token = {
             let token_mutex = self.twitch.app_token.lock().map_err(|_| Error::Mutex)?;
 
@@ -69,31 +116,159 @@ impl AppState {
         }
     }
 
+    /// Exchanges the refresh token of the websocket transport for a user access token.
+    async fn fetch_user_access_token(&self, refresh_token: &str) -> Result<String> {
+        let mut params = HashMap::new();
+        params.insert("client_id", self.twitch.client_id);
+        params.insert("client_secret", self.twitch.client_secret);
+        params.insert("grant_type", "refresh_token");
+        params.insert("refresh_token", refresh_token);
+
+        let mut res = track_auth(
+            "oauth2/token",
+            self.client
+                .post(format!("{TWITCH_AUTH_ENDPOINT}/oauth2/token"))
+                .send_form(&params),
+        )
+        .await?;
+
+        match res.status().as_u16() {
+            200 => {
+                TOKEN_REFRESHES.with_label_values(&[RESULT_SUCCESS]).inc();
+                let body: TokenExchangeResponse = read_json(&mut res).await?;
+
+                let mut data = self.twitch.user_token.lock().map_err(|_| Error::Mutex)?;
+
+                data.access_token = body.access_token.clone();
+                data.expires_at = current_unix_timestamp() + body.expires_in.max(0) as u64;
+
+                Ok(body.access_token)
+            }
+            _ => {
+                TOKEN_REFRESHES.with_label_values(&[RESULT_FAILURE]).inc();
+                Err(Error::Twitch(read_error(&mut res).await))
+            }
+        }
+    }
+
+    /// Returns the token eventsubs are managed with. Twitch only accepts user access tokens
+    /// for websocket subscriptions.
+    async fn get_eventsub_token(&self) -> Result<String> {
+        let EventsubTransport::Websocket { refresh_token, .. } = self.twitch.transport else {
+            return self.get_access_token().await;
+        };
+
+        let token = {
+            let token_mutex = self.twitch.user_token.lock().map_err(|_| Error::Mutex)?;
+
+            token_mutex.clone()
+        };
+
+        if !token.access_token.is_empty() && token.expires_at >= current_unix_timestamp() {
+            Ok(token.access_token)
+        } else {
+            self.fetch_user_access_token(refresh_token).await
+        }
+    }
+
+    /// Transport new eventsubs are registered with. Websocket subscriptions are bound to the
+    /// session of the connection, so they can't be created while it is down.
+    async fn eventsub_transport(&self) -> Result<EventsubTransportData> {
+        match self.twitch.transport {
+            EventsubTransport::Webhook { callback_url } => Ok(EventsubTransportData {
+                method: "webhook".to_owned(),
+                callback: Some(callback_url.to_owned()),
+                secret: Some(self.twitch.eventsub_secret.to_owned()),
+                ..EventsubTransportData::default()
+            }),
+            EventsubTransport::Websocket { .. } => {
+                let session_id = self.websocket_session.id().ok_or_else(|| {
+                    Error::Twitch("Eventsub websocket is not connected".to_string())
+                })?;
+
+                Ok(EventsubTransportData {
+                    method: "websocket".to_owned(),
+                    session_id: Some(session_id),
+                    ..EventsubTransportData::default()
+                })
+            }
+            EventsubTransport::Conduit { .. } => {
+                let conduit_id = self
+                    .conduit_id()
+                    .await?
+                    .ok_or_else(|| Error::Twitch("No conduit has been created yet".to_string()))?;
+
+                Ok(EventsubTransportData {
+                    method: "conduit".to_owned(),
+                    conduit_id: Some(conduit_id),
+                    ..EventsubTransportData::default()
+                })
+            }
+        }
+    }
+
     pub async fn fetch_user(&self, token: &str) -> Result<TwitchUser> {
         let url = format!("{TWITCH_API_ENDPOINT}/users");
-        let mut res = self
-            .client
-            .get(url.as_str())
-            .bearer_auth(token)
-            .insert_header(("Client-Id", self.twitch.client_id))
-            .send()
-            .await?;
+        let mut res = track_helix(
+            "users",
+            self.client
+                .get(url.as_str())
+                .bearer_auth(token)
+                .insert_header(("Client-Id", self.twitch.client_id))
+                .send(),
+        )
+        .await?;
 
         match res.status().as_u16() {
             200 => {
-                let res_data = res.json::<TwitchUserResponse>().await.unwrap();
-                Ok(res_data.data[0].clone())
+                let res_data: TwitchUserResponse = read_json(&mut res).await?;
+                res_data
+                    .data
+                    .into_iter()
+                    .next()
+                    .ok_or_else(|| Error::Twitch("No user returned".to_string()))
             }
-            400 => Err(Error::InternalServer(
+            400 => Err(Error::Twitch(
                 "Bad request while fetching users".to_string(),
             )),
             401 => Err(Error::Twitch("Invalid authorization used".to_string())),
             c => {
-                let res_data = res.json::<TwitchAuthErrorResponse>().await.unwrap();
-                error!(target: "twitch", "GET {url} resulted in {c}: {res_data:?}");
+                let res_data = read_error(&mut res).await;
+                error!(target: "twitch", "GET {url} resulted in {c}: {res_data}");
+
+                Err(Error::Twitch("Received unhandled status code".to_string()))
+            }
+        }
+    }
+
+    /// Looks up a user by login with the app access token.
+    pub async fn fetch_user_by_login(&self, login: &str) -> Result<Option<TwitchUser>> {
+        let token = self.get_access_token().await?;
+
+        let url = format!("{TWITCH_API_ENDPOINT}/users");
+        let mut res = track_helix(
+            "users",
+            self.client
+                .get(url.as_str())
+                .query(&[("login", login)])
+                .map_err(|e| Error::BadRequest(e.to_string()))?
+                .bearer_auth(token)
+                .insert_header(("Client-Id", self.twitch.client_id))
+                .send(),
+        )
+        .await?;
+
+        match res.status().as_u16() {
+            200 => {
+                let res_data: TwitchUserResponse = read_json(&mut res).await?;
+                Ok(res_data.data.into_iter().next())
+            }
+            c => {
+                let res_data = read_error(&mut res).await;
+                error!(target: "twitch", "GET {url} resulted in {c}: {res_data}");
 
-                Err(Error::InternalServer(
-                    "Received unhandled status code".to_string(),
+                Err(Error::Twitch(
+                    "An error occurred while fetching a user".to_string(),
                 ))
             }
         }
@@ -108,107 +283,221 @@ impl AppState {
         params.insert("code", code);
 
         let url = format!("{TWITCH_AUTH_ENDPOINT}/oauth2/token");
-        let mut res = self.client.post(url.as_str()).send_form(&params).await?;
+        let mut res = track_auth(
+            "oauth2/token",
+            self.client.post(url.as_str()).send_form(&params),
+        )
+        .await?;
 
         match res.status().as_u16() {
             200 => {
-                let body = res.json::<TokenExchangeResponse>().await.unwrap();
+                let body: TokenExchangeResponse = read_json(&mut res).await?;
 
                 Ok(body.access_token)
             }
+            400 => Err(Error::InvalidOauthCode),
             c => {
-                let res_data = res.json::<TwitchAuthErrorResponse>().await.unwrap();
-                error!(target: "twitch", "POST {} resulted in {c}: {res_data:?}", url.as_str());
+                let res_data = read_error(&mut res).await;
+                error!(target: "twitch", "POST {} resulted in {c}: {res_data}", url.as_str());
 
-                Err(Error::InternalServer(
+                Err(Error::Twitch(
                     "An error occurred while fetching an eventsub".to_string(),
                 ))
             }
         }
     }
 
-    async fn fetch_eventsub_by_user(&self, user_id: i32) -> Result<Option<TwitchEventsub>> {
-        let app_token = self.fetch_access_token().await?;
+    /// Fetches the `stream.online` eventsub of the user with the given transport. A user may
+    /// have more than one while their eventsub is moved to another callback.
+    async fn fetch_eventsub_by_user(
+        &self,
+        user_id: i64,
+        transport: &EventsubTransportData,
+    ) -> Result<Option<TwitchEventsub>> {
+        let token = self.get_eventsub_token().await?;
 
         let url = format!("{TWITCH_API_ENDPOINT}/eventsub/subscriptions?user_id={user_id}");
-        let mut res = self
-            .client
-            .get(url.as_str())
-            .insert_header(("Client-Id", self.twitch.client_id))
-            .bearer_auth(app_token)
-            .send()
-            .await?;
+        let mut res = track_helix(
+            "eventsub/subscriptions",
+            self.client
+                .get(url.as_str())
+                .insert_header(("Client-Id", self.twitch.client_id))
+                .bearer_auth(token)
+                .send(),
+        )
+        .await?;
 
         match res.status().as_u16() {
             200 => {
-                let body = res.json::<TwitchEventsubResponse>().await.unwrap();
-
-                Ok(if body.data.is_empty() {
-                    None
-                } else {
-                    Some(body.data[0].clone())
-                })
+                let body: TwitchEventsubResponse = read_json(&mut res).await?;
+                self.track_eventsub_cost(&body);
+
+                Ok(body.data.into_iter().find(|s| {
+                    s.event_type == StreamOnlineEvent::TYPE
+                        && s.transport.method == transport.method
+                        && s.transport.callback == transport.callback
+                        && s.transport.session_id == transport.session_id
+                        && s.transport.conduit_id == transport.conduit_id
+                }))
             }
             c => {
-                let res_data = res.json::<TwitchApiErrorResponse>().await.unwrap();
-                error!(target: "twitch", "GET {} resulted in {c}: {res_data:?}", url.as_str());
+                let res_data = read_error(&mut res).await;
+                error!(target: "twitch", "GET {} resulted in {c}: {res_data}", url.as_str());
 
-                Err(Error::InternalServer(
+                Err(Error::Twitch(
                     "An error occurred while fetching an eventsub".to_string(),
                 ))
             }
         }
     }
 
-    pub async fn register_eventsub(&self, user_id: i32) -> Result<String> {
-        let token = self.get_access_token().await?;
+    /// Fetches all `stream.online` subscriptions of the app, following the pagination.
+    pub async fn fetch_eventsubs(&self) -> Result<Vec<TwitchEventsub>> {
+        let token = self.get_eventsub_token().await?;
+        let mut subscriptions = vec![];
+        let mut cursor: Option<String> = None;
+
+        loop {
+            let mut url =
+                format!("{TWITCH_API_ENDPOINT}/eventsub/subscriptions?type=stream.online");
+            if let Some(cursor) = &cursor {
+                url.push_str(&format!("&after={cursor}"));
+            }
+
+            let mut res = track_helix(
+                "eventsub/subscriptions",
+                self.client
+                    .get(url.as_str())
+                    .insert_header(("Client-Id", self.twitch.client_id))
+                    .bearer_auth(token.as_str())
+                    .send(),
+            )
+            .await?;
+
+            match res.status().as_u16() {
+                200 => {
+                    let body: TwitchEventsubResponse = read_json(&mut res).await?;
+                    self.track_eventsub_cost(&body);
+                    subscriptions.extend(body.data);
+
+                    match body.pagination.cursor {
+                        Some(next) if !next.is_empty() => cursor = Some(next),
+                        _ => return Ok(subscriptions),
+                    }
+                }
+                c => {
+                    let res_data = read_error(&mut res).await;
+                    error!(target: "twitch", "GET {} resulted in {c}: {res_data}", url.as_str());
+
+                    return Err(Error::Twitch(
+                        "An error occurred while fetching eventsubs".to_string(),
+                    ));
+                }
+            }
+        }
+    }
+
+    /// Records the cost of the eventsubs Helix reports along with them.
+    fn track_eventsub_cost(&self, res: &TwitchEventsubResponse) {
+        self.eventsub_budget.update(
+            EventsubCost {
+                total_cost: res.total_cost,
+                max_total_cost: res.max_total_cost,
+            },
+            self.twitch.eventsub_cost_thresholds,
+        );
+    }
+
+    /// Registers the `stream.online` eventsub of a user, or returns the one they already have,
+    /// and records its status so the verification tracker can follow it.
+    pub async fn register_eventsub(&self, user_id: i64) -> Result<String> {
+        let subscription = match self.create_eventsub(user_id).await? {
+            Some(subscription) => subscription,
+            None => {
+                let transport = self.eventsub_transport().await?;
+                let subscription = self.fetch_eventsub_by_user(user_id, &transport).await?;
+
+                if let Some(s) = subscription {
+                    s
+                } else {
+                    error!(target: "twitch", "Cannot find existing eventsub for user {user_id}");
+                    return Err(Error::Twitch(
+                        "Cannot find existing eventsub for user".to_string(),
+                    ));
+                }
+            }
+        };
+
+        self.record_eventsub_status(user_id, &subscription.id, &subscription.status)
+            .await?;
+
+        Ok(subscription.id)
+    }
+
+    /// Creates the `stream.online` eventsub of a user. Returns `None` if the user already has
+    /// one with the same transport.
+    pub(crate) async fn create_eventsub(&self, user_id: i64) -> Result<Option<TwitchEventsub>> {
+        // Held until the cost of the new eventsub is reported
+        let _reservation = self.eventsub_budget.reserve()?;
+        let token = self.get_eventsub_token().await?;
 
         let body = CreateTwitchEventsub {
-            event_type: EventsubType::StreamOnline,
-            version: "1".to_string(),
+            event_type: StreamOnlineEvent::TYPE.to_string(),
+            version: StreamOnlineEvent::VERSION.to_string(),
             condition: EventsubCondition {
                 broadcaster_user_id: user_id.to_string(),
             },
-            transport: EventsubTransportData {
-                callback: self.twitch.callback_url.to_owned(),
-                secret: Some(self.twitch.eventsub_secret.to_owned()),
-                method: "webhook".to_owned(),
-            },
+            transport: self.eventsub_transport().await?,
         };
 
         let url = format!("{TWITCH_API_ENDPOINT}/eventsub/subscriptions");
-        let mut res = self
-            .client
-            .post(url.as_str())
-            .bearer_auth(token)
-            .insert_header(("Client-Id", self.twitch.client_id))
-            .send_json(&body)
-            .await?;
+        let mut res = track_helix(
+            "eventsub/subscriptions",
+            self.client
+                .post(url.as_str())
+                .bearer_auth(token)
+                .insert_header(("Client-Id", self.twitch.client_id))
+                .send_json(&body),
+        )
+        .await?;
 
         match res.status().as_u16() {
             202 => {
-                let body = res.json::<TwitchEventsubResponse>().await.unwrap();
-                let event_sub = body.data.first().unwrap();
+                let body: TwitchEventsubResponse = read_json(&mut res).await?;
+                self.track_eventsub_cost(&body);
+
+                let eventsub = body
+                    .data
+                    .into_iter()
+                    .next()
+                    .ok_or_else(|| Error::Twitch("No eventsub returned".to_string()))?;
+                if eventsub.cost > 0 {
+                    warn!(
+                        target: "twitch",
+                        "Eventsub {} of user {user_id} costs {}, the user has not authorized the app",
+                        eventsub.id, eventsub.cost
+                    );
+                }
 
-                Ok(event_sub.id.clone())
+                Ok(Some(eventsub))
             }
-            409 => {
-                let subscription = self.fetch_eventsub_by_user(user_id).await?;
-
-                if let Some(s) = subscription {
-                    Ok(s.id)
-                } else {
-                    error!(target: "twitch", "Cannot find existing eventsub for user {user_id}");
-                    Err(Error::Twitch(
-                        "Cannot find existing eventsub for user".to_string(),
-                    ))
-                }
+            409 => Ok(None),
+            429 => {
+                let error = rate_limit_error(
+                    res.headers()
+                        .get("Ratelimit-Remaining")
+                        .and_then(|h| h.to_str().ok()),
+                );
+                let res_data = read_error(&mut res).await;
+                warn!(target: "twitch", "POST {} resulted in 429: {res_data}", url.as_str());
+
+                Err(error)
             }
             c => {
-                let res_data = res.json::<TwitchApiErrorResponse>().await.unwrap();
+                let res_data = read_error(&mut res).await;
 
-                error!(target: "twitch", "POST {} resulted in {c}: {res_data:?}", url.as_str());
-                Err(Error::InternalServer(
+                error!(target: "twitch", "POST {} resulted in {c}: {res_data}", url.as_str());
+                Err(Error::Twitch(
                     "An error occurred while registering an eventsub".to_string(),
                 ))
             }
@@ -216,16 +505,18 @@ impl AppState {
     }
 
     pub async fn delete_eventsub(&self, id: &str) -> Result<()> {
-        let token = self.get_access_token().await?;
+        let token = self.get_eventsub_token().await?;
 
         let url = format!("{TWITCH_API_ENDPOINT}/eventsub/subscriptions?id={id}");
-        let mut res = self
-            .client
-            .delete(url.as_str())
-            .bearer_auth(token)
-            .insert_header(("Client-Id", self.twitch.client_id))
-            .send()
-            .await?;
+        let mut res = track_helix(
+            "eventsub/subscriptions",
+            self.client
+                .delete(url.as_str())
+                .bearer_auth(token)
+                .insert_header(("Client-Id", self.twitch.client_id))
+                .send(),
+        )
+        .await?;
 
         match res.status().as_u16() {
             204 => Ok(()),
@@ -235,45 +526,383 @@ impl AppState {
                 Ok(())
             }
             c => {
-                let res_data = res.json::<TwitchApiErrorResponse>().await.unwrap();
-                error!(target: "twitch", "DELETE {} resulted in {c}: {res_data:?}", url.as_str());
+                let res_data = read_error(&mut res).await;
+                error!(target: "twitch", "DELETE {} resulted in {c}: {res_data}", url.as_str());
 
-                Err(Error::InternalServer(
-                    "Twitch response is not handled".to_string(),
-                ))
+                Err(Error::Twitch("Twitch response is not handled".to_string()))
             }
         }
     }
 
-    pub async fn fetch_stream_data(&self, user_id: i32) -> Result<StreamData> {
+    pub async fn create_conduit(&self, shard_count: i32) -> Result<TwitchConduit> {
         let token = self.get_access_token().await?;
 
-        let url = format!("https://api.twitch.tv/helix/streams?user_id={user_id}");
-        let mut res = self
-            .client
-            .get(url.as_str())
-            .bearer_auth(token)
-            .insert_header(("Client-Id", self.twitch.client_id))
-            .send()
-            .await?;
+        let url = format!("{TWITCH_API_ENDPOINT}/eventsub/conduits");
+        let mut res = track_helix(
+            "eventsub/conduits",
+            self.client
+                .post(url.as_str())
+                .bearer_auth(token)
+                .insert_header(("Client-Id", self.twitch.client_id))
+                .send_json(&CreateTwitchConduit { shard_count }),
+        )
+        .await?;
 
         match res.status().as_u16() {
             200 => {
-                let body = res.json::<TwitchStreamsResponse>().await.unwrap();
+                let body: TwitchConduitResponse = read_json(&mut res).await?;
 
-                if body.data.is_empty() {
-                    Err(Error::Twitch("No stream data returned.".to_string()))
-                } else {
-                    Ok(body.data[0].clone())
-                }
+                body.data
+                    .into_iter()
+                    .next()
+                    .ok_or_else(|| Error::Twitch("No conduit returned".to_string()))
             }
             c => {
-                let res_data = res.json::<TwitchApiErrorResponse>().await.unwrap();
-                error!(target: "twitch", "GET {} resulted in {c}: {res_data:?}", url.as_str());
+                let res_data = read_error(&mut res).await;
+                error!(target: "twitch", "POST {} resulted in {c}: {res_data}", url.as_str());
 
-                Err(Error::InternalServer(
-                    "An error occurred while fetching a stream.".to_string(),
-                ))
+                Err(Error::Twitch("Could not create conduit".to_string()))
+            }
+        }
+    }
+
+    /// Changes the amount of shards. Shards with an id above the new count are removed.
+    pub async fn update_conduit(&self, id: &str, shard_count: i32) -> Result<()> {
+        let token = self.get_access_token().await?;
+
+        let body = UpdateTwitchConduit {
+            id: id.to_string(),
+            shard_count,
+        };
+
+        let url = format!("{TWITCH_API_ENDPOINT}/eventsub/conduits");
+        let mut res = track_helix(
+            "eventsub/conduits",
+            self.client
+                .patch(url.as_str())
+                .bearer_auth(token)
+                .insert_header(("Client-Id", self.twitch.client_id))
+                .send_json(&body),
+        )
+        .await?;
+
+        match res.status().as_u16() {
+            200 => Ok(()),
+            c => {
+                let res_data = read_error(&mut res).await;
+                error!(target: "twitch", "PATCH {} resulted in {c}: {res_data}", url.as_str());
+
+                Err(Error::Twitch("Could not update conduit".to_string()))
+            }
+        }
+    }
+
+    /// Points the shards to new transports. Returns the shards Twitch did not update.
+    pub async fn update_conduit_shards(
+        &self,
+        conduit_id: &str,
+        shards: Vec<ConduitShardUpdate>,
+    ) -> Result<Vec<ConduitShardError>> {
+        let token = self.get_access_token().await?;
+
+        let body = UpdateConduitShards {
+            conduit_id: conduit_id.to_string(),
+            shards,
+        };
+
+        let url = format!("{TWITCH_API_ENDPOINT}/eventsub/conduits/shards");
+        let mut res = track_helix(
+            "eventsub/conduits/shards",
+            self.client
+                .patch(url.as_str())
+                .bearer_auth(token)
+                .insert_header(("Client-Id", self.twitch.client_id))
+                .send_json(&body),
+        )
+        .await?;
+
+        match res.status().as_u16() {
+            202 => {
+                let body: ConduitShardsResponse = read_json(&mut res).await?;
+
+                Ok(body.errors)
+            }
+            c => {
+                let res_data = read_error(&mut res).await;
+                error!(target: "twitch", "PATCH {} resulted in {c}: {res_data}", url.as_str());
+
+                Err(Error::Twitch("Could not update conduit shards".to_string()))
+            }
+        }
+    }
+
+    /// Fetches all shards of the conduit, following the pagination.
+    pub async fn fetch_conduit_shards(&self, conduit_id: &str) -> Result<Vec<ConduitShard>> {
+        let token = self.get_access_token().await?;
+        let mut shards = vec![];
+        let mut cursor: Option<String> = None;
+
+        loop {
+            let mut url =
+                format!("{TWITCH_API_ENDPOINT}/eventsub/conduits/shards?conduit_id={conduit_id}");
+            if let Some(cursor) = &cursor {
+                url.push_str(&format!("&after={cursor}"));
+            }
+
+            let mut res = track_helix(
+                "eventsub/conduits/shards",
+                self.client
+                    .get(url.as_str())
+                    .insert_header(("Client-Id", self.twitch.client_id))
+                    .bearer_auth(token.as_str())
+                    .send(),
+            )
+            .await?;
+
+            match res.status().as_u16() {
+                200 => {
+                    let body: ConduitShardsResponse = read_json(&mut res).await?;
+                    shards.extend(body.data);
+
+                    match body.pagination.cursor {
+                        Some(next) if !next.is_empty() => cursor = Some(next),
+                        _ => return Ok(shards),
+                    }
+                }
+                c => {
+                    let res_data = read_error(&mut res).await;
+                    error!(target: "twitch", "GET {} resulted in {c}: {res_data}", url.as_str());
+
+                    return Err(Error::Twitch(
+                        "An error occurred while fetching conduit shards".to_string(),
+                    ));
+                }
+            }
+        }
+    }
+
+    /// Fetches the streams of the given users, chunked into requests of at most
+    /// [`STREAMS_BATCH_SIZE`] ids. Every requested id is present in the returned map,
+    /// users that are offline map to `None`.
+    pub async fn fetch_streams(
+        &self,
+        user_ids: &[i64],
+    ) -> Result<HashMap<i64, Option<StreamData>>> {
+        let mut listed = vec![];
+
+        if user_ids.is_empty() {
+            return Ok(map_streams(user_ids, listed));
+        }
+
+        let token = self.get_access_token().await?;
+
+        for query in streams_queries(user_ids) {
+            let url = format!("{TWITCH_API_ENDPOINT}/streams?{query}");
+            let mut res = track_helix(
+                "streams",
+                self.client
+                    .get(url.as_str())
+                    .bearer_auth(token.as_str())
+                    .insert_header(("Client-Id", self.twitch.client_id))
+                    .send(),
+            )
+            .await?;
+
+            match res.status().as_u16() {
+                200 => {
+                    let body: TwitchStreamsResponse = read_json(&mut res).await?;
+                    listed.extend(body.data);
+                }
+                c if c == 429 || c >= 500 => {
+                    let res_data = read_error(&mut res).await;
+                    warn!(target: "twitch", "GET {} resulted in {c}: {res_data}", url.as_str());
+
+                    return Err(Error::TwitchUnavailable(c));
+                }
+                c => {
+                    let res_data = read_error(&mut res).await;
+                    error!(target: "twitch", "GET {} resulted in {c}: {res_data}", url.as_str());
+
+                    return Err(Error::Twitch(
+                        "An error occurred while fetching a stream.".to_string(),
+                    ));
+                }
+            }
+        }
+
+        Ok(map_streams(user_ids, listed))
+    }
+
+    /// Fetches the stream of a user, `None` if Helix does not list it.
+    pub async fn fetch_stream_data(&self, user_id: i64) -> Result<Option<StreamData>> {
+        let mut streams = self.fetch_streams(&[user_id]).await?;
+
+        Ok(streams.remove(&user_id).flatten())
+    }
+}
+
+/// Query strings of the `/streams` requests for the given users, one per chunk of at most
+/// [`STREAMS_BATCH_SIZE`] ids.
+fn streams_queries(user_ids: &[i64]) -> Vec<String> {
+    user_ids
+        .chunks(STREAMS_BATCH_SIZE)
+        .map(|chunk| {
+            let ids = chunk
+                .iter()
+                .map(|id| format!("user_id={id}"))
+                .collect::<Vec<_>>()
+                .join("&");
+
+            format!("first={STREAMS_BATCH_SIZE}&{ids}")
+        })
+        .collect()
+}
+
+/// Maps every requested user to the stream Helix listed for them, `None` if it omitted them.
+fn map_streams(
+    user_ids: &[i64],
+    listed: impl IntoIterator<Item = StreamData>,
+) -> HashMap<i64, Option<StreamData>> {
+    let mut streams: HashMap<i64, Option<StreamData>> =
+        user_ids.iter().map(|id| (*id, None)).collect();
+
+    for stream in listed {
+        streams.insert(stream.user_id, Some(stream));
+    }
+
+    streams
+}
+
+/// Tells a 429 of the eventsub creation apart. Helix sends it both when the app exhausted
+/// its request rate limit, which passes, and when the eventsubs exceed their maximum cost.
+fn rate_limit_error(ratelimit_remaining: Option<&str>) -> Error {
+    match ratelimit_remaining.and_then(|r| r.trim().parse::<u32>().ok()) {
+        Some(remaining) if remaining > 0 => Error::EventsubBudgetExceeded,
+        // Without the header it is unclear, the transient error lets the caller retry
+        _ => Error::TwitchUnavailable(429),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use awc::test::TestResponse;
+    use chrono::Utc;
+
+    use super::*;
+
+    fn response(body: &'static str) -> ClientResponse {
+        TestResponse::with_header(("content-type", "application/json"))
+            .set_payload(body)
+            .finish()
+    }
+
+    #[actix_web::test]
+    async fn read_json_rejects_non_json_body() {
+        let mut res = response("<html>Bad Gateway</html>");
+        let result = read_json::<TwitchUserResponse, _>(&mut res).await;
+
+        assert!(matches!(result, Err(Error::Twitch(_))));
+    }
+
+    #[actix_web::test]
+    async fn read_json_rejects_missing_fields() {
+        let mut res = response(r#"{"data":[{"id":"141981764"}]}"#);
+        let result = read_json::<TwitchUserResponse, _>(&mut res).await;
+
+        assert!(matches!(result, Err(Error::Twitch(_))));
+    }
+
+    #[actix_web::test]
+    async fn read_error_uses_twitch_message() {
+        let mut res =
+            response(r#"{"error":"Unauthorized","status":401,"message":"Invalid OAuth token"}"#);
+
+        assert_eq!(
+            read_error(&mut res).await,
+            "Invalid OAuth token (Unauthorized)"
+        );
+    }
+
+    #[actix_web::test]
+    async fn read_error_falls_back_to_text() {
+        let mut res = response("<html>Bad Gateway</html>");
+
+        assert_eq!(read_error(&mut res).await, "<html>Bad Gateway</html>");
+    }
+
+    #[test]
+    fn tells_rate_limits_from_exceeded_budget() {
+        assert!(matches!(
+            rate_limit_error(Some("0")),
+            Error::TwitchUnavailable(429)
+        ));
+        assert!(matches!(
+            rate_limit_error(Some("799")),
+            Error::EventsubBudgetExceeded
+        ));
+        assert!(matches!(
+            rate_limit_error(None),
+            Error::TwitchUnavailable(429)
+        ));
+    }
+
+    fn stream(user_id: i64) -> StreamData {
+        StreamData {
+            id: user_id * 10,
+            user_id,
+            user_login: format!("user{user_id}"),
+            user_name: format!("User{user_id}"),
+            game_id: None,
+            game_name: String::new(),
+            title: String::new(),
+            viewer_count: 0,
+            started_at: Utc::now(),
+            thumbnail_url: String::new(),
+        }
+    }
+
+    #[test]
+    fn chunks_streams_queries() {
+        for (count, chunks) in [
+            (0, vec![]),
+            (100, vec![100]),
+            (101, vec![100, 1]),
+            (250, vec![100, 100, 50]),
+        ] {
+            let user_ids = (1..=count).collect::<Vec<i64>>();
+            let queries = streams_queries(&user_ids);
+
+            assert_eq!(
+                queries
+                    .iter()
+                    .map(|q| q.matches("user_id=").count())
+                    .collect::<Vec<_>>(),
+                chunks,
+                "{count} ids"
+            );
+            assert!(queries.iter().all(|q| q.starts_with("first=100&")));
+        }
+
+        assert_eq!(streams_queries(&[1, 2]), ["first=100&user_id=1&user_id=2"]);
+    }
+
+    #[test]
+    fn maps_omitted_users_to_none() {
+        for count in [0, 100, 101, 250] {
+            let user_ids = (1..=count).collect::<Vec<i64>>();
+            let listed = user_ids
+                .iter()
+                .filter(|id| *id % 2 == 0)
+                .map(|id| stream(*id));
+
+            let streams = map_streams(&user_ids, listed);
+
+            assert_eq!(streams.len(), user_ids.len());
+            for id in &user_ids {
+                assert_eq!(
+                    streams[id].as_ref().map(|s| s.user_id),
+                    (id % 2 == 0).then_some(*id)
+                );
             }
         }
     }
@@ -1,24 +1,27 @@
 use std::fmt::Display;
 use std::str::FromStr;
 
+use chrono::{DateTime, Utc};
 use serde::ser::SerializeStruct;
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use serde_json::value::RawValue;
 use validator::Validate;
 
+use crate::eventsub::EventsubEvent;
 use crate::structs::ErrorResponse;
 
-#[derive(Serialize)]
-struct RegisterEventsubData {
-    pub version: &'static str,
-    #[serde(rename = "type")]
-    pub event_type: EventsubType,
-    pub transport: EventsubTransportData,
-    pub condition: EventsubCondition,
-}
-
 #[derive(Deserialize)]
 pub struct TwitchEventsubResponse {
     pub data: Vec<TwitchEventsub>,
+    pub total_cost: u32,
+    pub max_total_cost: u32,
+    #[serde(default)]
+    pub pagination: Pagination,
+}
+
+#[derive(Deserialize, Default)]
+pub struct Pagination {
+    pub cursor: Option<String>,
 }
 
 #[derive(Deserialize, Clone)]
@@ -26,10 +29,10 @@ pub struct TwitchEventsub {
     pub id: String,
     pub status: String,
     #[serde(rename = "type")]
-    pub event_type: EventsubType,
-    pub version: String,
+    pub event_type: String,
     pub condition: EventsubCondition,
-    pub created_at: String,
+    #[allow(dead_code)]
+    pub created_at: DateTime<Utc>,
     pub transport: EventsubTransportData,
     pub cost: u16,
 }
@@ -37,28 +40,12 @@ pub struct TwitchEventsub {
 #[derive(Serialize)]
 pub struct CreateTwitchEventsub {
     #[serde(rename = "type")]
-    pub event_type: EventsubType,
+    pub event_type: String,
     pub version: String,
     pub condition: EventsubCondition,
     pub transport: EventsubTransportData,
 }
 
-#[derive(Deserialize, Serialize, Clone)]
-pub enum EventsubType {
-    #[serde(rename = "stream.online")]
-    StreamOnline,
-    #[serde(rename = "WebhookCallbackVerificationPending")]
-    WebhookCallbackVerificationPending,
-    #[serde(rename = "webhook_callback_verification_failed")]
-    WebhookCallbackVerificationFailed,
-    #[serde(rename = "notification_failures_exceeded")]
-    NotificationFailuresExceeded,
-    #[serde(rename = "user_removed")]
-    UserRemoved,
-    #[serde(rename = "authorization_revoked")]
-    AuthorizationRevoked,
-}
-
 #[derive(Deserialize)]
 pub enum EventsubStatus {
     #[serde(rename = "enabled")]
@@ -77,33 +64,107 @@ pub enum EventsubStatus {
     UserRemoved,
     #[serde(rename = "version_removed")]
     VersionRemoved,
+    #[serde(other)]
+    Unknown,
 }
 
-// impl EventsubStatus {
-//     pub fn as_str(&self) -> &'static str {
-//         match self {
-//             EventsubStatus::Enabled => "enabled",
-//             EventsubStatus::WebhookCallbackVerificationPending => "webhook_callback_verification_pending",
-//             EventsubStatus::WebhookCallbackVerificationFailed => "webhook_callback_verification_failed",
-//             EventsubStatus::NotificationFailuresExceeded => "notification_failures_exceeded",
-//             EventsubStatus::AuthorizationRevoked => "authorization_revoked",
-//             EventsubStatus::ModeratorRemoved => "moderator_removed",
-//             EventsubStatus::UserRemoved => "user_removed",
-//             EventsubStatus::VersionRemoved => "version_removed",
-//         }
-//     }
-// }
+impl EventsubStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            EventsubStatus::Enabled => "enabled",
+            EventsubStatus::WebhookCallbackVerificationPending => {
+                "webhook_callback_verification_pending"
+            }
+            EventsubStatus::WebhookCallbackVerificationFailed => {
+                "webhook_callback_verification_failed"
+            }
+            EventsubStatus::NotificationFailuresExceeded => "notification_failures_exceeded",
+            EventsubStatus::AuthorizationRevoked => "authorization_revoked",
+            EventsubStatus::ModeratorRemoved => "moderator_removed",
+            EventsubStatus::UserRemoved => "user_removed",
+            EventsubStatus::VersionRemoved => "version_removed",
+            EventsubStatus::Unknown => "unknown",
+        }
+    }
+}
 
 #[derive(Deserialize, Serialize, Clone)]
 pub struct EventsubCondition {
     pub broadcaster_user_id: String,
 }
 
-#[derive(Deserialize, Serialize, Clone)]
+#[derive(Deserialize, Serialize, Clone, Default)]
 pub struct EventsubTransportData {
     pub method: String,
-    pub callback: String,
+    /// Set for the `webhook` method.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub callback: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
     pub secret: Option<String>,
+    /// Set for the `websocket` method.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub session_id: Option<String>,
+    /// Set for the `conduit` method.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub conduit_id: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct TwitchConduitResponse {
+    pub data: Vec<TwitchConduit>,
+}
+
+#[derive(Deserialize)]
+pub struct TwitchConduit {
+    pub id: String,
+    pub shard_count: i32,
+}
+
+#[derive(Serialize)]
+pub struct CreateTwitchConduit {
+    pub shard_count: i32,
+}
+
+#[derive(Serialize)]
+pub struct UpdateTwitchConduit {
+    pub id: String,
+    pub shard_count: i32,
+}
+
+#[derive(Serialize)]
+pub struct UpdateConduitShards {
+    pub conduit_id: String,
+    pub shards: Vec<ConduitShardUpdate>,
+}
+
+#[derive(Serialize)]
+pub struct ConduitShardUpdate {
+    pub id: String,
+    pub transport: EventsubTransportData,
+}
+
+#[derive(Deserialize)]
+pub struct ConduitShardsResponse {
+    pub data: Vec<ConduitShard>,
+    /// Shards of an update that could not be changed.
+    #[serde(default)]
+    pub errors: Vec<ConduitShardError>,
+    #[serde(default)]
+    pub pagination: Pagination,
+}
+
+#[derive(Deserialize)]
+pub struct ConduitShard {
+    pub id: String,
+    pub status: String,
+    pub transport: EventsubTransportData,
+}
+
+#[derive(Deserialize)]
+pub struct ConduitShardError {
+    pub id: String,
+    pub message: String,
+    pub code: String,
 }
 
 #[derive(Deserialize, Serialize)]
@@ -113,6 +174,18 @@ pub struct AppAccessTokenResponse {
     pub token_type: String,
 }
 
+/// Tracked eventsub that is not enabled, returned by the admin API.
+#[derive(Serialize)]
+pub struct StuckEventsub {
+    pub user_id: i64,
+    pub username: String,
+    pub eventsub_id: String,
+    pub status: String,
+    /// How often the eventsub was registered again after its verification failed.
+    pub attempts: i32,
+    pub updated_at: DateTime<Utc>,
+}
+
 #[derive(Deserialize, Validate)]
 pub struct TwitchCodePayload {
     #[validate(length(min = 28, max = 28))]
@@ -124,10 +197,7 @@ pub struct TwitchCodePayload {
 #[derive(Deserialize)]
 pub struct TokenExchangeResponse {
     pub access_token: String,
-    pub token_type: String,
     pub expires_in: i32,
-    pub refresh_token: String,
-    // pub scope: String,
 }
 
 #[derive(Deserialize)]
@@ -138,29 +208,22 @@ pub struct TwitchUserResponse {
 #[derive(Deserialize, Clone)]
 pub struct TwitchUser {
     #[serde(deserialize_with = "str_to_int")]
-    pub id: i32,
+    pub id: i64,
     pub login: String,
     pub display_name: String,
-    #[serde(rename = "type")]
-    pub kind: String,
-    pub email: String,
-    pub broadcaster_type: String,
-    pub description: String,
     pub profile_image_url: String,
-    pub offline_image_url: String,
-    pub created_at: String,
+    #[allow(dead_code)]
+    pub created_at: DateTime<Utc>,
 }
 
 #[derive(Debug, Deserialize)]
 pub struct TwitchApiErrorResponse {
     pub error: String,
-    pub status: i16,
     pub message: String,
 }
 
 #[derive(Debug, Deserialize)]
 pub struct TwitchAuthErrorResponse {
-    pub status: u16,
     pub message: String,
 }
 
@@ -171,41 +234,46 @@ pub struct TwitchSubscriptionData {
     pub kind: String,
     pub status: EventsubStatus,
     pub version: String,
-    pub cost: u8,
     pub condition: EventsubConditionData,
-    pub transport: EventsubTransportData,
-    pub created_at: String,
+    #[allow(dead_code)]
+    pub created_at: DateTime<Utc>,
 }
 
+/// Condition of a received subscription, only set for types with a broadcaster condition.
 #[derive(Deserialize)]
 pub struct EventsubConditionData {
-    #[serde(deserialize_with = "str_to_int")]
-    pub broadcaster_user_id: i32,
+    #[serde(default, deserialize_with = "opt_str_to_int")]
+    pub broadcaster_user_id: Option<i64>,
 }
 
+/// Body of a `stream.online` notification.
 #[derive(Deserialize)]
-pub struct EventsubEventData {
+pub struct StreamOnlineEvent {
     #[serde(deserialize_with = "str_to_int")]
-    pub id: i32,
+    pub id: i64,
     #[serde(deserialize_with = "str_to_int")]
-    pub broadcaster_user_id: i32,
+    pub broadcaster_user_id: i64,
     pub broadcaster_user_login: String,
     pub broadcaster_user_name: String,
-    pub started_at: String,
-    #[serde(rename = "type")]
-    pub kind: String,
+    pub started_at: DateTime<Utc>,
+}
+
+impl EventsubEvent for StreamOnlineEvent {
+    const TYPE: &'static str = "stream.online";
+    const VERSION: &'static str = "1";
 }
 
 #[derive(Deserialize)]
 pub struct TwitchChallengePayload {
     pub challenge: String,
-    pub subscription: TwitchSubscriptionData,
 }
 
+/// Notification of any subscription type. The event is kept raw until the handler of the
+/// type parses it into its body.
 #[derive(Deserialize)]
-pub struct TwitchNotificationPayload {
+pub struct TwitchNotificationPayload<E = Box<RawValue>> {
     pub subscription: TwitchSubscriptionData,
-    pub event: EventsubEventData,
+    pub event: E,
 }
 
 #[derive(Deserialize)]
@@ -213,6 +281,33 @@ pub struct EventsubRevocationPayload {
     pub subscription: TwitchSubscriptionData,
 }
 
+/// Message received over the eventsub websocket. The payload is parsed according to the
+/// message type of the metadata.
+#[derive(Deserialize)]
+pub struct WebsocketMessage {
+    pub metadata: WebsocketMetadata,
+    pub payload: Box<RawValue>,
+}
+
+#[derive(Deserialize)]
+pub struct WebsocketMetadata {
+    pub message_id: String,
+    pub message_type: String,
+}
+
+/// Payload of the `session_welcome` and `session_reconnect` messages.
+#[derive(Deserialize)]
+pub struct WebsocketSessionPayload {
+    pub session: WebsocketSessionData,
+}
+
+#[derive(Deserialize)]
+pub struct WebsocketSessionData {
+    pub id: String,
+    pub keepalive_timeout_seconds: Option<u64>,
+    pub reconnect_url: Option<String>,
+}
+
 #[derive(Deserialize)]
 pub enum TwitchSubscriptionStatus {
     #[serde(rename = "notification")]
@@ -228,22 +323,20 @@ pub enum TwitchSubscriptionStatus {
 #[derive(Deserialize, Clone)]
 pub struct StreamData {
     #[serde(deserialize_with = "str_to_int")]
-    pub id: i32,
+    pub id: i64,
     #[serde(deserialize_with = "str_to_int")]
-    pub user_id: i32,
+    pub user_id: i64,
     pub user_login: String,
+    /// Display name, the bot is sent the login.
+    #[allow(dead_code)]
     pub user_name: String,
-    #[serde(deserialize_with = "str_to_int")]
-    pub game_id: i32,
+    #[serde(deserialize_with = "empty_str_to_none")]
+    pub game_id: Option<String>,
     pub game_name: String,
-    #[serde(rename = "type")]
-    pub kind: String,
     pub title: String,
     pub viewer_count: i32,
-    pub started_at: String,
+    pub started_at: DateTime<Utc>,
     pub thumbnail_url: String,
-    pub language: String,
-    pub tags: Vec<String>,
 }
 
 #[derive(Deserialize)]
@@ -267,6 +360,24 @@ impl TwitchSubscriptionStatus {
     }
 }
 
+/// Builds the stream from an `stream.online` event, for when Helix does not list the stream.
+impl From<&StreamOnlineEvent> for StreamData {
+    fn from(event: &StreamOnlineEvent) -> Self {
+        Self {
+            id: event.id,
+            user_id: event.broadcaster_user_id,
+            user_login: event.broadcaster_user_login.clone(),
+            user_name: event.broadcaster_user_name.clone(),
+            game_id: None,
+            game_name: String::new(),
+            title: String::new(),
+            viewer_count: 0,
+            started_at: event.started_at,
+            thumbnail_url: String::new(),
+        }
+    }
+}
+
 impl Serialize for ErrorResponse {
     fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
     where
@@ -275,6 +386,7 @@ impl Serialize for ErrorResponse {
         let mut state = serializer.serialize_struct("ErrorResponse", 3)?;
 
         state.serialize_field("code", &self.code.as_u16())?;
+        state.serialize_field("error_code", self.error_code)?;
         state.serialize_field("message", &self.message)?;
         state.end()
     }
@@ -289,3 +401,191 @@ where
     let s = String::deserialize(deserializer)?;
     T::from_str(&s).map_err(serde::de::Error::custom)
 }
+
+fn opt_str_to_int<'de, T, D>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    T: FromStr,
+    T::Err: Display,
+    D: Deserializer<'de>,
+{
+    let s = Option::<String>::deserialize(deserializer)?;
+    s.map(|s| T::from_str(&s).map_err(serde::de::Error::custom))
+        .transpose()
+}
+
+/// Twitch sends an empty string instead of `null` for some unset ids, e.g. the game of a
+/// stream without a category.
+fn empty_str_to_none<'de, D>(deserializer: D) -> Result<Option<String>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let s = Option::<String>::deserialize(deserializer)?;
+    Ok(s.filter(|s| !s.is_empty()))
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{TimeZone, Utc};
+
+    use super::*;
+
+    #[test]
+    fn parses_streams_response() {
+        let body = r#"{
+            "data": [
+                {
+                    "id": "40952121085",
+                    "user_id": "101051819",
+                    "user_login": "afro",
+                    "user_name": "Afro",
+                    "game_id": "32982",
+                    "game_name": "Grand Theft Auto V",
+                    "type": "live",
+                    "title": "Jacob: Digital Den Laptops & Routers | NoPixel | !MAINGEAR !FCF",
+                    "tags": ["English"],
+                    "viewer_count": 1490,
+                    "started_at": "2021-03-10T03:18:11Z",
+                    "language": "en",
+                    "thumbnail_url": "https://static-cdn.jtvnw.net/previews-ttv/live_user_afro-{width}x{height}.jpg",
+                    "tag_ids": [],
+                    "is_mature": false
+                },
+                {
+                    "id": "41375541868",
+                    "user_id": "459331509",
+                    "user_login": "auronplay",
+                    "user_name": "auronplay",
+                    "game_id": "",
+                    "game_name": "",
+                    "type": "live",
+                    "title": "hablamos y le damos a Little Nightmares 1",
+                    "tags": [],
+                    "viewer_count": 78365,
+                    "started_at": "2021-03-10T15:04:21Z",
+                    "language": "es",
+                    "thumbnail_url": "https://static-cdn.jtvnw.net/previews-ttv/live_user_auronplay-{width}x{height}.jpg",
+                    "tag_ids": [],
+                    "is_mature": false
+                }
+            ],
+            "pagination": {}
+        }"#;
+
+        let res = serde_json::from_str::<TwitchStreamsResponse>(body).unwrap();
+
+        assert_eq!(res.data[0].id, 40952121085);
+        assert_eq!(res.data[0].user_id, 101051819);
+        assert_eq!(res.data[0].game_id.as_deref(), Some("32982"));
+        assert_eq!(
+            res.data[0].started_at,
+            Utc.with_ymd_and_hms(2021, 3, 10, 3, 18, 11).unwrap()
+        );
+        assert_eq!(res.data[1].game_id, None);
+    }
+
+    #[test]
+    fn parses_stream_online_notification() {
+        let body = r#"{
+            "subscription": {
+                "id": "f1c2a387-161a-49f9-a165-0f21d7a4e1c4",
+                "type": "stream.online",
+                "version": "1",
+                "status": "enabled",
+                "cost": 0,
+                "condition": {
+                    "broadcaster_user_id": "1337"
+                },
+                "transport": {
+                    "method": "webhook",
+                    "callback": "https://example.com/webhooks/callback"
+                },
+                "created_at": "2019-11-16T10:11:12.634234626Z"
+            },
+            "event": {
+                "id": "9001",
+                "broadcaster_user_id": "1337",
+                "broadcaster_user_login": "cool_user",
+                "broadcaster_user_name": "Cool_User",
+                "type": "live",
+                "started_at": "2020-10-11T10:11:12.123Z"
+            }
+        }"#;
+
+        let res =
+            serde_json::from_str::<TwitchNotificationPayload<StreamOnlineEvent>>(body).unwrap();
+
+        assert_eq!(res.subscription.condition.broadcaster_user_id, Some(1337));
+        assert_eq!(res.event.id, 9001);
+        assert_eq!(res.event.broadcaster_user_id, 1337);
+        assert_eq!(
+            res.event.started_at.timestamp_millis(),
+            Utc.with_ymd_and_hms(2020, 10, 11, 10, 11, 12)
+                .unwrap()
+                .timestamp_millis()
+                + 123
+        );
+    }
+
+    #[test]
+    fn parses_users_response() {
+        let body = r#"{
+            "data": [
+                {
+                    "id": "141981764",
+                    "login": "twitchdev",
+                    "display_name": "TwitchDev",
+                    "type": "",
+                    "broadcaster_type": "partner",
+                    "description": "Supporting third-party developers building Twitch integrations from chatbots to game integrations.",
+                    "profile_image_url": "https://static-cdn.jtvnw.net/jtv_user_pictures/8a6381c7-d0c0-4576-b179-38bd5ce1d6af-profile_image-300x300.png",
+                    "offline_image_url": "https://static-cdn.jtvnw.net/jtv_user_pictures/3f13ab61-ec78-4fe6-8481-8682cb3b0ac2-channel_offline_image-1920x1080.png",
+                    "view_count": 5980557,
+                    "email": "not-real@email.com",
+                    "created_at": "2016-12-14T20:32:28Z"
+                }
+            ]
+        }"#;
+
+        let res = serde_json::from_str::<TwitchUserResponse>(body).unwrap();
+
+        assert_eq!(res.data[0].id, 141981764);
+        assert_eq!(
+            res.data[0].created_at,
+            Utc.with_ymd_and_hms(2016, 12, 14, 20, 32, 28).unwrap()
+        );
+    }
+
+    #[test]
+    fn parses_create_eventsub_response() {
+        let body = r#"{
+            "data": [
+                {
+                    "id": "26b1c993-bfcf-44d9-b876-379dacafe75a",
+                    "status": "webhook_callback_verification_pending",
+                    "type": "stream.online",
+                    "version": "1",
+                    "condition": {
+                        "broadcaster_user_id": "1337"
+                    },
+                    "created_at": "2020-11-10T14:32:18.730260295Z",
+                    "transport": {
+                        "method": "webhook",
+                        "callback": "https://example.com/webhooks/callback"
+                    },
+                    "cost": 1
+                }
+            ],
+            "total": 1,
+            "total_cost": 1,
+            "max_total_cost": 10000
+        }"#;
+
+        let res = serde_json::from_str::<TwitchEventsubResponse>(body).unwrap();
+
+        assert_eq!(res.data[0].id, "26b1c993-bfcf-44d9-b876-379dacafe75a");
+        assert_eq!(res.data[0].condition.broadcaster_user_id, "1337");
+        assert_eq!(res.data[0].cost, 1);
+        assert_eq!(res.total_cost, 1);
+        assert_eq!(res.max_total_cost, 10000);
+    }
+}
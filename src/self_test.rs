@@ -0,0 +1,259 @@
+use std::io;
+use std::time::Duration;
+
+use awc::error::{ConnectError, SendRequestError};
+use chrono::{SecondsFormat, Utc};
+use log::{error, info};
+use rand::Rng;
+use serde_json::json;
+
+use crate::config::{EventsubTransport, ShardTransport};
+use crate::eventsub::sign_message;
+use crate::routes::twitch::structs::TwitchSubscriptionStatus;
+use crate::structs::AppState;
+
+/// Time for the webserver to start listening before the challenge is sent.
+const SELF_TEST_DELAY: Duration = Duration::from_secs(2);
+/// Bodies of unexpected responses are cut to this length in the report.
+const MAX_REPORTED_BODY: usize = 200;
+
+/// Step of the request through the public callback that failed.
+#[derive(Debug, derive_more::Display)]
+pub enum SelfTestError {
+    #[display(fmt = "DNS: could not resolve the host of the callback: {}", _0)]
+    Dns(String),
+    #[display(
+        fmt = "TLS: could not establish a secure connection to the callback: {}",
+        _0
+    )]
+    Tls(String),
+    #[display(fmt = "Connection: could not reach the callback: {}", _0)]
+    Connection(String),
+    #[display(
+        fmt = "Missing headers: the eventsub headers did not reach the service, check that the proxy forwards them: {}",
+        _0
+    )]
+    MissingHeaders(String),
+    #[display(
+        fmt = "Signature mismatch: the service rejected the signature, check that the proxy does not alter the body and that the replicas use the same secret"
+    )]
+    SignatureMismatch,
+    #[display(fmt = "Rejected: the callback responded with {}: {}", status, body)]
+    Rejected { status: u16, body: String },
+    #[display(
+        fmt = "Challenge mismatch: the callback did not echo the challenge but responded with: {}",
+        _0
+    )]
+    ChallengeMismatch(String),
+}
+
+impl std::error::Error for SelfTestError {}
+
+/// Returns the public callback Twitch sends the messages of this replica to, if any.
+pub fn callback_url(transport: &EventsubTransport) -> Option<&str> {
+    match transport {
+        EventsubTransport::Webhook { callback_url }
+        | EventsubTransport::Conduit {
+            shard: ShardTransport::Webhook { callback_url },
+            ..
+        } => Some(callback_url),
+        _ => None,
+    }
+}
+
+/// Sends a signed callback verification through the public callback once the webserver is
+/// up, so a proxy breaking the verification shows up at startup instead of when go-lives
+/// stop being announced.
+pub async fn run_self_test(state: AppState, callback_url: &'static str) {
+    tokio::select! {
+        _ = actix_web::rt::time::sleep(SELF_TEST_DELAY) => {}
+        _ = state.shutdown.triggered() => return,
+    }
+
+    match self_test(&state.client, callback_url, state.twitch.eventsub_secret).await {
+        Ok(()) => info!("Callback self-test passed, {callback_url} echoed the challenge"),
+        Err(e) => error!("Callback self-test of {callback_url} failed at {e}"),
+    }
+}
+
+/// Sends a `webhook_callback_verification` message signed with the secret to the callback
+/// and checks that the challenge is echoed.
+pub async fn self_test(
+    client: &awc::Client,
+    callback_url: &str,
+    secret: &str,
+) -> Result<(), SelfTestError> {
+    let message_id = hex::encode(rand::thread_rng().gen::<[u8; 16]>());
+    let challenge = hex::encode(rand::thread_rng().gen::<[u8; 16]>());
+    let timestamp = Utc::now().to_rfc3339_opts(SecondsFormat::Nanos, true);
+    let body = json!({
+        "challenge": challenge,
+        "subscription": {
+            "id": format!("self-test-{message_id}"),
+            "type": "stream.online",
+            "status": "webhook_callback_verification_pending",
+            "version": "1",
+            "cost": 0,
+            "condition": {},
+            "transport": {
+                "method": "webhook",
+                "callback": callback_url,
+            },
+            "created_at": timestamp,
+        },
+    })
+    .to_string();
+
+    let mut res = client
+        .post(callback_url)
+        .insert_header(("Twitch-Eventsub-Message-Id", message_id.as_str()))
+        .insert_header(("Twitch-Eventsub-Message-Timestamp", timestamp.as_str()))
+        .insert_header((
+            "Twitch-Eventsub-Message-Signature",
+            sign_message(secret, &message_id, &timestamp, body.as_bytes()),
+        ))
+        .insert_header((
+            "Twitch-Eventsub-Message-Type",
+            TwitchSubscriptionStatus::WebhookCallbackVerification.as_str(),
+        ))
+        .content_type("application/json")
+        .send_body(body)
+        .await
+        .map_err(connect_error)?;
+
+    let status = res.status().as_u16();
+    let body = res
+        .body()
+        .await
+        .map_err(|e| SelfTestError::Connection(e.to_string()))?;
+
+    check_response(status, &String::from_utf8_lossy(&body), &challenge)
+}
+
+fn connect_error(e: SendRequestError) -> SelfTestError {
+    match e {
+        SendRequestError::Connect(
+            e @ (ConnectError::Resolver(_) | ConnectError::NoRecords | ConnectError::Unresolved),
+        ) => SelfTestError::Dns(e.to_string()),
+        SendRequestError::Connect(e @ ConnectError::SslIsNotSupported) => {
+            SelfTestError::Tls(e.to_string())
+        }
+        // rustls reports failed handshakes as invalid data, which plain TCP never does
+        SendRequestError::Connect(ConnectError::Io(e))
+            if e.kind() == io::ErrorKind::InvalidData =>
+        {
+            SelfTestError::Tls(e.to_string())
+        }
+        e => SelfTestError::Connection(e.to_string()),
+    }
+}
+
+fn check_response(status: u16, body: &str, challenge: &str) -> Result<(), SelfTestError> {
+    if status == 200 {
+        return if body == challenge {
+            Ok(())
+        } else {
+            Err(SelfTestError::ChallengeMismatch(truncate(body)))
+        };
+    }
+
+    let error = serde_json::from_str::<serde_json::Value>(body).unwrap_or_default();
+    match error["error_code"].as_str() {
+        Some("invalid_header") => Err(SelfTestError::MissingHeaders(
+            error["message"].as_str().unwrap_or_default().to_string(),
+        )),
+        Some("invalid_signature") => Err(SelfTestError::SignatureMismatch),
+        _ => Err(SelfTestError::Rejected {
+            status,
+            body: truncate(body),
+        }),
+    }
+}
+
+fn truncate(body: &str) -> String {
+    body.chars().take(MAX_REPORTED_BODY).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::init_twitch_routes;
+    use crate::test_utils::{test_config, test_pool};
+    use actix_web::{web, App, HttpServer};
+
+    use super::*;
+
+    #[test]
+    fn accepts_echoed_challenge() {
+        assert!(check_response(200, "challenge", "challenge").is_ok());
+    }
+
+    #[test]
+    fn reports_other_response_body() {
+        let result = check_response(200, "<html>Welcome</html>", "challenge");
+
+        assert!(
+            matches!(result, Err(SelfTestError::ChallengeMismatch(b)) if b == "<html>Welcome</html>")
+        );
+    }
+
+    #[test]
+    fn reports_missing_headers() {
+        let body = r#"{"code":400,"error_code":"invalid_header","message":"Invalid header twitch-eventsub-message-id"}"#;
+
+        assert!(matches!(
+            check_response(400, body, "challenge"),
+            Err(SelfTestError::MissingHeaders(m)) if m == "Invalid header twitch-eventsub-message-id"
+        ));
+    }
+
+    #[test]
+    fn reports_responses_of_other_services() {
+        assert!(matches!(
+            check_response(502, "Bad Gateway", "challenge"),
+            Err(SelfTestError::Rejected { status: 502, .. })
+        ));
+    }
+
+    #[actix_web::test]
+    async fn reports_unresolvable_host() {
+        let result = self_test(
+            &awc::Client::default(),
+            "https://callback.invalid/_notify/twitch",
+            "s3cRe7s3cRe7",
+        )
+        .await;
+
+        assert!(matches!(result, Err(SelfTestError::Dns(_))));
+    }
+
+    #[actix_web::test]
+    async fn reports_signature_mismatch() {
+        let config = test_config();
+        let pool = test_pool(config);
+        let server = HttpServer::new(move || {
+            App::new()
+                .app_data(web::Data::new(
+                    AppState::builder(config, pool.clone()).build(),
+                ))
+                .configure(init_twitch_routes)
+        })
+        .bind(("127.0.0.1", 0))
+        .unwrap()
+        .workers(1)
+        .disable_signals();
+        let addr = server.addrs()[0];
+        let server = server.run();
+        let handle = server.handle();
+        actix_web::rt::spawn(server);
+
+        let result = self_test(
+            &awc::Client::default(),
+            &format!("http://{addr}/_notify/twitch"),
+            "an0therS3cRe7",
+        )
+        .await;
+        handle.stop(false).await;
+
+        assert!(matches!(result, Err(SelfTestError::SignatureMismatch)));
+    }
+}
@@ -0,0 +1,105 @@
+use std::future::Future;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::sync::{watch, Notify};
+
+/// Coordinates the graceful shutdown between the workers, the background jobs and the
+/// deliveries they spawned.
+#[derive(Clone)]
+pub struct Shutdown {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    signal: watch::Sender<bool>,
+    tasks: AtomicUsize,
+    idle: Notify,
+}
+
+/// Decrements the task count once the tracked future completes or is dropped.
+struct TaskGuard(Arc<Inner>);
+
+impl Drop for TaskGuard {
+    fn drop(&mut self) {
+        if self.0.tasks.fetch_sub(1, Ordering::SeqCst) == 1 {
+            self.0.idle.notify_waiters();
+        }
+    }
+}
+
+impl Default for Shutdown {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Shutdown {
+    pub fn new() -> Self {
+        let (signal, _) = watch::channel(false);
+
+        Self {
+            inner: Arc::new(Inner {
+                signal,
+                tasks: AtomicUsize::new(0),
+                idle: Notify::new(),
+            }),
+        }
+    }
+
+    pub fn trigger(&self) {
+        self.inner.signal.send_replace(true);
+    }
+
+    pub fn is_triggered(&self) -> bool {
+        *self.inner.signal.borrow()
+    }
+
+    /// Resolves once the shutdown has been triggered.
+    pub async fn triggered(&self) {
+        let mut receiver = self.inner.signal.subscribe();
+
+        // The sender lives as long as self, so `changed` can't fail
+        while !*receiver.borrow_and_update() {
+            if receiver.changed().await.is_err() {
+                return;
+            }
+        }
+    }
+
+    /// Spawns a future the shutdown waits for.
+    pub fn spawn<F>(&self, future: F)
+    where
+        F: Future + 'static,
+    {
+        self.inner.tasks.fetch_add(1, Ordering::SeqCst);
+        let guard = TaskGuard(self.inner.clone());
+
+        crate::logging::spawn(async move {
+            let _guard = guard;
+            future.await;
+        });
+    }
+
+    pub fn running_tasks(&self) -> usize {
+        self.inner.tasks.load(Ordering::SeqCst)
+    }
+
+    /// Waits until all spawned futures are finished. Returns `false` if they did not finish
+    /// within the timeout.
+    pub async fn wait_idle(&self, timeout: Duration) -> bool {
+        let wait = async {
+            loop {
+                let idle = self.inner.idle.notified();
+                if self.running_tasks() == 0 {
+                    return;
+                }
+
+                idle.await;
+            }
+        };
+
+        actix_web::rt::time::timeout(timeout, wait).await.is_ok()
+    }
+}
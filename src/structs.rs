@@ -1,9 +1,15 @@
-use std::sync::Mutex;
+use std::sync::{Arc, Mutex};
 
 use actix_web::http::StatusCode;
 use sqlx::PgPool;
 
+use crate::budget::EventsubBudget;
+use crate::config::{Config, EventsubTransport};
+use crate::dispatcher::DeliveryQueue;
 use crate::errors::Error;
+use crate::eventsub::EventHandlers;
+use crate::shutdown::Shutdown;
+use crate::websocket::WebsocketSession;
 
 pub type Result<T> = std::result::Result<T, Error>;
 
@@ -11,16 +17,132 @@ pub struct AppState {
     pub twitch: TwitchState,
     pub db: PgPool,
     pub bot_url: &'static str,
+    pub bot_user_removed_url: Option<&'static str>,
     pub client: awc::Client,
+    pub shutdown: Shutdown,
+    pub deliveries: DeliveryQueue,
+    pub websocket_session: WebsocketSession,
+    pub handlers: Arc<EventHandlers>,
+    pub eventsub_budget: EventsubBudget,
+}
+
+impl AppState {
+    pub fn builder(config: &'static Config, db: PgPool) -> AppStateBuilder {
+        AppStateBuilder {
+            config,
+            db,
+            client: None,
+            shutdown: None,
+            deliveries: None,
+            websocket_session: None,
+            handlers: None,
+            eventsub_budget: None,
+        }
+    }
+}
+
+/// Builds the state shared by the routes. Every actix worker needs its own state, as the
+/// http client can't be shared between threads.
+pub struct AppStateBuilder {
+    config: &'static Config,
+    db: PgPool,
+    client: Option<awc::Client>,
+    shutdown: Option<Shutdown>,
+    deliveries: Option<DeliveryQueue>,
+    websocket_session: Option<WebsocketSession>,
+    handlers: Option<Arc<EventHandlers>>,
+    eventsub_budget: Option<EventsubBudget>,
+}
+
+impl AppStateBuilder {
+    /// Uses the given http client instead of one with the configured timeout.
+    pub fn client(mut self, client: awc::Client) -> Self {
+        self.client = Some(client);
+        self
+    }
+
+    pub fn shutdown(mut self, shutdown: Shutdown) -> Self {
+        self.shutdown = Some(shutdown);
+        self
+    }
+
+    /// Sets the queue of a running dispatcher. Without one, deliveries are left in the
+    /// database for a dispatcher of another process.
+    pub fn deliveries(mut self, deliveries: DeliveryQueue) -> Self {
+        self.deliveries = Some(deliveries);
+        self
+    }
+
+    /// Sets the session of the running websocket transport, which eventsubs are registered
+    /// with when using the websocket transport.
+    pub fn websocket_session(mut self, session: WebsocketSession) -> Self {
+        self.websocket_session = Some(session);
+        self
+    }
+
+    /// Sets the handlers of the eventsub notifications, instead of the builtin ones.
+    pub fn handlers(mut self, handlers: Arc<EventHandlers>) -> Self {
+        self.handlers = Some(handlers);
+        self
+    }
+
+    /// Shares the eventsub cost reported by Helix with the other states.
+    pub fn eventsub_budget(mut self, budget: EventsubBudget) -> Self {
+        self.eventsub_budget = Some(budget);
+        self
+    }
+
+    pub fn build(self) -> AppState {
+        let config = self.config;
+
+        AppState {
+            db: self.db,
+            twitch: TwitchState {
+                client_secret: config.twitch.client_secret.as_str(),
+                client_id: config.twitch.client_id.as_str(),
+                redirect_url: config.twitch.redirect_url.as_str(),
+                transport: &config.twitch.transport,
+                eventsub_secret: config.twitch.eventsub_secret.as_str(),
+                previous_eventsub_secrets: config.twitch.previous_eventsub_secrets.as_slice(),
+                eventsub_cost_thresholds: config.twitch.eventsub_cost_thresholds.as_slice(),
+                app_token: Mutex::new(TwitchAccessToken {
+                    access_token: String::from(""),
+                    expires_at: 0u64,
+                }),
+                user_token: Mutex::new(TwitchAccessToken {
+                    access_token: String::from(""),
+                    expires_at: 0u64,
+                }),
+            },
+            client: self
+                .client
+                .unwrap_or_else(|| awc::Client::builder().timeout(config.http_timeout).finish()),
+            bot_url: config.bot_url.as_str(),
+            bot_user_removed_url: config.bot_user_removed_url.as_deref(),
+            shutdown: self.shutdown.unwrap_or_default(),
+            deliveries: self
+                .deliveries
+                .unwrap_or_else(|| DeliveryQueue::new(config.delivery_queue_size).0),
+            websocket_session: self.websocket_session.unwrap_or_default(),
+            handlers: self
+                .handlers
+                .unwrap_or_else(|| Arc::new(EventHandlers::builtin())),
+            eventsub_budget: self.eventsub_budget.unwrap_or_default(),
+        }
+    }
 }
 
 pub struct TwitchState {
     pub client_id: &'static str,
     pub client_secret: &'static str,
     pub redirect_url: &'static str,
-    pub callback_url: &'static str,
+    pub transport: &'static EventsubTransport,
     pub eventsub_secret: &'static str,
+    pub previous_eventsub_secrets: &'static [String],
+    pub eventsub_cost_thresholds: &'static [u8],
     pub app_token: Mutex<TwitchAccessToken>,
+    /// Token of the user the websocket subscriptions are created with.
+    pub user_token: Mutex<TwitchAccessToken>,
 }
 
 #[derive(Clone)]
@@ -31,5 +153,6 @@ pub struct TwitchAccessToken {
 
 pub struct ErrorResponse {
     pub(crate) code: StatusCode,
+    pub(crate) error_code: &'static str,
     pub(crate) message: String,
 }
@@ -0,0 +1,210 @@
+use log::error;
+use sqlx::{PgConnection, Row};
+
+use crate::errors::Error;
+use crate::routes::twitch::structs::TwitchUser;
+use crate::structs::{AppState, Result};
+
+impl AppState {
+    /// Creates a notification of the user for the guild, registering the eventsub if the user
+    /// is not tracked with a working one yet, which costs nothing otherwise. Returns the id of
+    /// the notification.
+    pub async fn create_notification(&self, user: &TwitchUser, guild_id: i64) -> Result<i32> {
+        let existing =
+            sqlx::query("SELECT 1 FROM twitch_notifications WHERE user_id = $1 AND guild_id = $2")
+                .bind(user.id)
+                .bind(guild_id)
+                .fetch_optional(&self.db)
+                .await?;
+
+        if existing.is_some() {
+            return Err(Error::Conflict);
+        }
+
+        // Registered before the transaction, which must not be kept open across Helix calls
+        let (eventsub_id, registered) = match self.working_eventsub(user.id).await? {
+            Some(eventsub_id) => (eventsub_id, false),
+            None => (self.register_eventsub(user.id).await?, true),
+        };
+
+        let (notification_id, replaced) = match self
+            .insert_notification(user, guild_id, &eventsub_id)
+            .await
+        {
+            Ok(inserted) => inserted,
+            Err(e) => {
+                if registered {
+                    if let Err(e) = self.delete_unreferenced_eventsub(&eventsub_id).await {
+                        error!(
+                            "Could not delete eventsub {eventsub_id} of failed notification: {e}"
+                        );
+                    }
+                }
+
+                return Err(e);
+            }
+        };
+
+        // The user was tracked with an eventsub that did not work
+        if let Some(replaced) = replaced.filter(|replaced| *replaced != eventsub_id) {
+            self.delete_eventsub(&replaced).await?;
+        }
+
+        Ok(notification_id)
+    }
+
+    /// Tracks the user with the eventsub and inserts the notification for the guild. Returns
+    /// the id of the notification and the eventsub the user was tracked with before.
+    async fn insert_notification(
+        &self,
+        user: &TwitchUser,
+        guild_id: i64,
+        eventsub_id: &str,
+    ) -> Result<(i32, Option<String>)> {
+        let mut transaction = self.db.begin().await?;
+
+        let previous = sqlx::query("SELECT eventsub_id FROM twitch_users WHERE id = $1 FOR UPDATE")
+            .bind(user.id)
+            .fetch_optional(&mut transaction)
+            .await?
+            .map(|row| row.get::<String, &str>("eventsub_id"));
+
+        // The secret only changes along with the eventsub
+        sqlx::query(
+            "INSERT INTO twitch_users (id, username, avatar, eventsub_id, eventsub_secret) VALUES ($1, $2, $3, $4, $5) ON CONFLICT (id) DO UPDATE SET username = $2, avatar = $3, eventsub_id = $4, eventsub_secret = CASE WHEN twitch_users.eventsub_id = $4 THEN twitch_users.eventsub_secret ELSE $5 END",
+        )
+        .bind(user.id)
+        .bind(user.display_name.as_str())
+        .bind(user.profile_image_url.as_str())
+        .bind(eventsub_id)
+        .bind(self.eventsub_secret_fingerprint())
+        .execute(&mut transaction)
+        .await?;
+
+        // Another request may have created the notification meanwhile
+        let Some(pg_res) = sqlx::query("INSERT INTO twitch_notifications (guild_id, user_id) VALUES ($1, $2) ON CONFLICT DO NOTHING RETURNING id")
+            .bind(guild_id)
+            .bind(user.id)
+            .fetch_optional(&mut transaction)
+            .await?
+        else {
+            return Err(Error::Conflict);
+        };
+
+        transaction.commit().await?;
+
+        Ok((pg_res.get::<i32, &str>("id"), previous))
+    }
+
+    /// Deletes an eventsub no user is tracked with, e.g. one registered for a notification
+    /// that could not be created.
+    async fn delete_unreferenced_eventsub(&self, eventsub_id: &str) -> Result<()> {
+        let referenced = sqlx::query("SELECT 1 FROM twitch_users WHERE eventsub_id = $1")
+            .bind(eventsub_id)
+            .fetch_optional(&self.db)
+            .await?;
+
+        if referenced.is_none() {
+            self.delete_eventsub(eventsub_id).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Deletes a notification. If no other notifications for its user are present, the
+    /// eventsub and the user are deleted as well. Users still notified in other guilds keep
+    /// their eventsub.
+    pub async fn delete_notification(&self, notification_id: i32) -> Result<()> {
+        let mut transaction = self.db.begin().await?;
+
+        let pg_res =
+            sqlx::query("DELETE FROM twitch_notifications WHERE id = $1 RETURNING user_id")
+                .bind(notification_id)
+                .fetch_optional(&mut transaction)
+                .await?;
+
+        let Some(pg_res) = pg_res else {
+            return Err(Error::UnknownNotification);
+        };
+
+        let user_id = pg_res.get::<i64, &str>("user_id");
+        let unused_user = sqlx::query(
+            "DELETE FROM twitch_users tu WHERE tu.id = $1 AND NOT EXISTS (SELECT 1 FROM twitch_notifications tn WHERE tn.user_id = tu.id) RETURNING eventsub_id",
+        )
+        .bind(user_id)
+        .fetch_optional(&mut transaction)
+        .await?;
+
+        transaction.commit().await?;
+
+        if let Some(row) = unused_user {
+            let eventsub_id = row.get::<String, &str>("eventsub_id");
+            self.delete_eventsub(eventsub_id.as_str()).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Deletes all notifications of a guild along with the users no longer referenced by any
+    /// guild, in one transaction.
+    pub async fn delete_guild_notifications(&self, guild_id: i64) -> Result<()> {
+        let mut transaction = self.db.begin().await?;
+
+        sqlx::query("DELETE FROM twitch_notifications WHERE guild_id = $1")
+            .bind(guild_id)
+            .execute(&mut transaction)
+            .await?;
+
+        let unused_users = delete_unused_users(&mut transaction).await?;
+
+        transaction.commit().await?;
+
+        self.delete_eventsubs_of(&unused_users).await
+    }
+
+    /// Deletes the users without notifications and their eventsubs, this also cleans up
+    /// some lost entries. Returns the amount of deleted users.
+    pub async fn delete_unused_users(&self) -> Result<usize> {
+        let mut connection = self.db.acquire().await?;
+        let unused_users = delete_unused_users(&mut connection).await?;
+        drop(connection);
+
+        // The users are gone already, a leftover eventsub is removed by `reconcile`
+        for eventsub_id in unused_users.iter() {
+            if let Err(e) = self.delete_eventsub(eventsub_id).await {
+                error!("Could not delete eventsub {eventsub_id}: {e}");
+            }
+        }
+
+        Ok(unused_users.len())
+    }
+
+    /// Deletes the eventsubs of deleted users. All of them are tried, the first error is
+    /// returned.
+    async fn delete_eventsubs_of(&self, eventsub_ids: &[String]) -> Result<()> {
+        let mut result = Ok(());
+
+        for eventsub_id in eventsub_ids {
+            if let Err(e) = self.delete_eventsub(eventsub_id).await {
+                error!("Could not delete eventsub {eventsub_id}: {e}");
+                result = result.and(Err(e));
+            }
+        }
+
+        result
+    }
+}
+
+/// Deletes the users no notification references. Returns the ids of their eventsubs.
+async fn delete_unused_users(connection: &mut PgConnection) -> Result<Vec<String>> {
+    let unused_users = sqlx::query(
+        "DELETE FROM twitch_users tu WHERE NOT EXISTS (SELECT 1 FROM twitch_notifications tn WHERE tn.user_id = tu.id) RETURNING eventsub_id",
+    )
+    .fetch_all(connection)
+    .await?;
+
+    Ok(unused_users
+        .iter()
+        .map(|row| row.get::<String, &str>("eventsub_id"))
+        .collect())
+}
@@ -0,0 +1,31 @@
+use std::time::Duration;
+
+use sqlx::postgres::PgPoolOptions;
+use sqlx::PgPool;
+
+use crate::config::Config;
+use crate::structs::AppState;
+
+/// Config of the tests, leaked as the state needs a static one.
+pub fn test_config() -> &'static Config {
+    Box::leak(Box::new(Config::test()))
+}
+
+/// Pool connecting lazily to an address nothing listens on, so queries fail fast. Tests that
+/// don't reach the database never connect.
+pub fn test_pool(config: &Config) -> PgPool {
+    PgPoolOptions::new()
+        .acquire_timeout(Duration::from_millis(100))
+        .connect_lazy(&config.postgres_dsn)
+        .unwrap()
+}
+
+pub fn test_state() -> AppState {
+    test_state_with(Config::test())
+}
+
+pub fn test_state_with(config: Config) -> AppState {
+    let config: &'static Config = Box::leak(Box::new(config));
+
+    AppState::builder(config, test_pool(config)).build()
+}
@@ -0,0 +1,409 @@
+use std::collections::HashMap;
+use std::time::Duration;
+
+use log::{error, info, warn};
+use sqlx::pool::PoolConnection;
+use sqlx::{Postgres, Row};
+
+use crate::errors::Error;
+use crate::routes::twitch::structs::StuckEventsub;
+use crate::structs::{AppState, Result};
+
+const CHECK_INTERVAL: Duration = Duration::from_secs(60);
+/// Eventsubs whose callback verification failed are registered again this many times.
+const MAX_VERIFICATION_ATTEMPTS: i32 = 3;
+/// Advisory lock keeping the replicas from retrying the same eventsubs at once.
+const VERIFICATION_LOCK_ID: i64 = 0x7665_7269_6679;
+/// Statuses of eventsubs no user is tracked with are kept this long, as the status is
+/// recorded before the user is created or pointed to the eventsub.
+const ORPHAN_RETENTION: Duration = Duration::from_secs(60 * 60);
+
+const ENABLED: &str = "enabled";
+const PENDING: &str = "webhook_callback_verification_pending";
+const FAILED: &str = "webhook_callback_verification_failed";
+/// Recorded for eventsubs Helix does not list anymore.
+const MISSING: &str = "missing";
+
+/// Follows the eventsubs that wait for their callback verification until Twitch enables
+/// them, registering the ones that failed again a bounded number of times. Eventsubs Twitch
+/// disabled for other reasons, like a revoked authorization, are only reported and left to
+/// be reconciled.
+pub async fn run_verification_tracker(state: AppState) {
+    info!("Starting eventsub verification tracker");
+
+    let mut interval = actix_web::rt::time::interval(CHECK_INTERVAL);
+
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {}
+            _ = state.shutdown.triggered() => break,
+        }
+
+        if let Err(e) = check_verifications(&state).await {
+            error!("Could not check eventsub verifications: {e}");
+        }
+    }
+
+    info!("Eventsub verification tracker stopped");
+}
+
+#[derive(Debug, PartialEq)]
+enum Step {
+    Enabled,
+    Wait,
+    Retry,
+    GiveUp,
+    /// A new eventsub would fail for the same reason, e.g. a revoked authorization.
+    Report,
+}
+
+fn next_step(status: &str, attempts: i32) -> Step {
+    match status {
+        ENABLED => Step::Enabled,
+        PENDING => Step::Wait,
+        FAILED | MISSING if attempts < MAX_VERIFICATION_ATTEMPTS => Step::Retry,
+        FAILED | MISSING => Step::GiveUp,
+        _ => Step::Report,
+    }
+}
+
+/// Eventsub a user is tracked with that is not known to be enabled yet.
+#[derive(Debug, PartialEq)]
+struct Unverified {
+    user_id: i64,
+    eventsub_id: String,
+    attempts: i32,
+}
+
+/// Decides what to do with every unverified eventsub, given the statuses Helix lists.
+fn plan_verifications(
+    unverified: Vec<Unverified>,
+    statuses: &HashMap<String, String>,
+) -> Vec<(Unverified, &str, Step)> {
+    unverified
+        .into_iter()
+        .map(|eventsub| {
+            let status = statuses
+                .get(&eventsub.eventsub_id)
+                .map_or(MISSING, String::as_str);
+            let step = next_step(status, eventsub.attempts);
+
+            (eventsub, status, step)
+        })
+        .collect()
+}
+
+/// Takes the session-level lock of the tracker without waiting for it. Unlike the lock of a
+/// transaction, it can be held across the Helix calls without keeping a transaction open.
+async fn try_lock_verifications(state: &AppState) -> Result<Option<PoolConnection<Postgres>>> {
+    let mut connection = state.db.acquire().await?;
+
+    let locked = sqlx::query("SELECT pg_try_advisory_lock($1) AS locked")
+        .bind(VERIFICATION_LOCK_ID)
+        .fetch_one(&mut connection)
+        .await?
+        .get::<bool, &str>("locked");
+
+    Ok(locked.then_some(connection))
+}
+
+async fn unlock_verifications(mut connection: PoolConnection<Postgres>) {
+    let unlocked = sqlx::query("SELECT pg_advisory_unlock($1)")
+        .bind(VERIFICATION_LOCK_ID)
+        .execute(&mut connection)
+        .await;
+
+    if let Err(e) = unlocked {
+        // Closing the session releases its locks
+        error!("Could not release the verification lock, closing the connection: {e}");
+        drop(connection.detach());
+    }
+}
+
+async fn check_verifications(state: &AppState) -> Result<()> {
+    let Some(lock) = try_lock_verifications(state).await? else {
+        return Ok(());
+    };
+
+    let result = check_verifications_locked(state).await;
+    unlock_verifications(lock).await;
+
+    result
+}
+
+async fn check_verifications_locked(state: &AppState) -> Result<()> {
+    sqlx::query(
+        "DELETE FROM eventsub_statuses s WHERE updated_at < now() - make_interval(secs => $1) AND NOT EXISTS (SELECT 1 FROM twitch_users u WHERE u.id = s.user_id AND u.eventsub_id = s.eventsub_id)",
+    )
+    .bind(ORPHAN_RETENTION.as_secs_f64())
+    .execute(&state.db)
+    .await?;
+
+    let unverified = sqlx::query(
+        "SELECT s.user_id, s.eventsub_id, s.attempts FROM eventsub_statuses s INNER JOIN twitch_users u ON u.id = s.user_id AND u.eventsub_id = s.eventsub_id WHERE s.status = $1 OR (s.status = ANY($2) AND s.attempts < $3)",
+    )
+    .bind(PENDING)
+    .bind(vec![FAILED, MISSING])
+    .bind(MAX_VERIFICATION_ATTEMPTS)
+    .fetch_all(&state.db)
+    .await?
+    .iter()
+    .map(|row| Unverified {
+        user_id: row.get("user_id"),
+        eventsub_id: row.get("eventsub_id"),
+        attempts: row.get("attempts"),
+    })
+    .collect::<Vec<_>>();
+
+    if unverified.is_empty() {
+        return Ok(());
+    }
+
+    let statuses = state
+        .fetch_eventsubs()
+        .await?
+        .into_iter()
+        .map(|s| (s.id, s.status))
+        .collect::<HashMap<_, _>>();
+
+    for (eventsub, status, step) in plan_verifications(unverified, &statuses) {
+        let Unverified {
+            user_id,
+            eventsub_id,
+            attempts,
+        } = eventsub;
+
+        match step {
+            Step::Enabled => {
+                state
+                    .record_eventsub_status(user_id, &eventsub_id, status)
+                    .await?;
+                info!("Eventsub {eventsub_id} of user {user_id} was verified");
+            }
+            Step::Wait => {}
+            Step::Retry => {
+                warn!(
+                    "Verification of eventsub {eventsub_id} of user {user_id} failed ({status}), registering it again"
+                );
+
+                if let Err(e) = retry_verification(state, user_id, &eventsub_id, attempts).await {
+                    error!("Could not register eventsub of user {user_id} again: {e}");
+                }
+            }
+            Step::GiveUp => {
+                state
+                    .record_eventsub_status(user_id, &eventsub_id, status)
+                    .await?;
+                error!(
+                    "Eventsub {eventsub_id} of user {user_id} was not verified after {attempts} attempts ({status})"
+                );
+            }
+            Step::Report => {
+                state
+                    .record_eventsub_status(user_id, &eventsub_id, status)
+                    .await?;
+                warn!(
+                    "Eventsub {eventsub_id} of user {user_id} has status {status}, reconcile the eventsubs once the cause is fixed"
+                );
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Replaces an eventsub whose verification failed with a new one, which Twitch verifies
+/// again. The new eventsub is registered first, Twitch only rejects it while the old one
+/// still has the same callback, which is deleted and registered again then.
+async fn retry_verification(
+    state: &AppState,
+    user_id: i64,
+    eventsub_id: &str,
+    attempts: i32,
+) -> Result<()> {
+    let (new_eventsub, old_deleted) = match state.create_eventsub(user_id).await? {
+        Some(new_eventsub) => (new_eventsub, false),
+        None => {
+            state.delete_eventsub(eventsub_id).await?;
+            let new_eventsub = state.create_eventsub(user_id).await?.ok_or_else(|| {
+                Error::Twitch("Eventsub still conflicts after deleting the old one".to_string())
+            })?;
+
+            (new_eventsub, true)
+        }
+    };
+    let new_eventsub_id = new_eventsub.id.as_str();
+
+    let updated = sqlx::query(
+        "UPDATE twitch_users SET eventsub_id = $2, eventsub_secret = $3 WHERE id = $1 AND eventsub_id = $4",
+    )
+    .bind(user_id)
+    .bind(new_eventsub_id)
+    .bind(state.eventsub_secret_fingerprint())
+    .bind(eventsub_id)
+    .execute(&state.db)
+    .await?
+    .rows_affected();
+
+    // The user was removed or moved to another eventsub meanwhile
+    if updated == 0 {
+        state.delete_eventsub(new_eventsub_id).await?;
+        return Ok(());
+    }
+
+    let mut transaction = state.db.begin().await?;
+
+    sqlx::query(
+        "INSERT INTO eventsub_statuses (user_id, eventsub_id, status, attempts) VALUES ($1, $2, $3, $4) ON CONFLICT (eventsub_id) DO UPDATE SET status = $3, attempts = $4, updated_at = now()",
+    )
+    .bind(user_id)
+    .bind(new_eventsub_id)
+    .bind(new_eventsub.status.as_str())
+    .bind(attempts + 1)
+    .execute(&mut transaction)
+    .await?;
+
+    sqlx::query("DELETE FROM eventsub_statuses WHERE eventsub_id = $1")
+        .bind(eventsub_id)
+        .execute(&mut transaction)
+        .await?;
+
+    transaction.commit().await?;
+
+    if !old_deleted {
+        state.delete_eventsub(eventsub_id).await?;
+    }
+
+    info!("Replaced eventsub {eventsub_id} of user {user_id} with {new_eventsub_id}");
+
+    Ok(())
+}
+
+impl AppState {
+    /// Returns the eventsub a user is tracked with, unless it is known not to work. Eventsubs
+    /// tracked before their statuses were recorded are taken as working.
+    pub(crate) async fn working_eventsub(&self, user_id: i64) -> Result<Option<String>> {
+        let eventsub_id = sqlx::query(
+            "SELECT u.eventsub_id FROM twitch_users u LEFT JOIN eventsub_statuses s ON s.eventsub_id = u.eventsub_id WHERE u.id = $1 AND (s.status IS NULL OR s.status = ANY($2))",
+        )
+        .bind(user_id)
+        .bind(vec![ENABLED, PENDING])
+        .fetch_optional(&self.db)
+        .await?
+        .map(|row| row.get::<String, &str>("eventsub_id"));
+
+        Ok(eventsub_id)
+    }
+
+    /// Records the status of an eventsub of a user, which they might not be tracked with yet,
+    /// e.g. while it replaces another one. A new eventsub starts with no retried verifications.
+    pub(crate) async fn record_eventsub_status(
+        &self,
+        user_id: i64,
+        eventsub_id: &str,
+        status: &str,
+    ) -> Result<()> {
+        sqlx::query(
+            "INSERT INTO eventsub_statuses (user_id, eventsub_id, status) VALUES ($1, $2, $3) ON CONFLICT (eventsub_id) DO UPDATE SET status = $3, updated_at = now()",
+        )
+        .bind(user_id)
+        .bind(eventsub_id)
+        .bind(status)
+        .execute(&self.db)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Returns the tracked eventsubs that are not enabled, e.g. because their callback
+    /// verification is pending or failed.
+    pub async fn stuck_eventsubs(&self) -> Result<Vec<StuckEventsub>> {
+        let eventsubs = sqlx::query(
+            "SELECT s.user_id, u.username, s.eventsub_id, s.status, s.attempts, s.updated_at FROM eventsub_statuses s INNER JOIN twitch_users u ON u.id = s.user_id AND u.eventsub_id = s.eventsub_id WHERE s.status <> $1 ORDER BY s.updated_at",
+        )
+        .bind(ENABLED)
+        .fetch_all(&self.db)
+        .await?
+        .iter()
+        .map(|row| StuckEventsub {
+            user_id: row.get("user_id"),
+            username: row.get("username"),
+            eventsub_id: row.get("eventsub_id"),
+            status: row.get("status"),
+            attempts: row.get("attempts"),
+            updated_at: row.get("updated_at"),
+        })
+        .collect();
+
+        Ok(eventsubs)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn waits_for_pending_verification() {
+        assert_eq!(next_step(PENDING, MAX_VERIFICATION_ATTEMPTS), Step::Wait);
+        assert_eq!(next_step(ENABLED, 1), Step::Enabled);
+    }
+
+    #[test]
+    fn retries_failed_verification_a_bounded_number_of_times() {
+        assert_eq!(next_step(FAILED, 0), Step::Retry);
+        assert_eq!(
+            next_step(MISSING, MAX_VERIFICATION_ATTEMPTS - 1),
+            Step::Retry
+        );
+        assert_eq!(next_step(FAILED, MAX_VERIFICATION_ATTEMPTS), Step::GiveUp);
+    }
+
+    #[test]
+    fn reports_eventsubs_left_to_reconcile() {
+        assert_eq!(next_step("authorization_revoked", 0), Step::Report);
+        assert_eq!(next_step("user_removed", 0), Step::Report);
+        assert_eq!(next_step("notification_failures_exceeded", 0), Step::Report);
+    }
+
+    fn unverified(eventsub_id: &str, attempts: i32) -> Unverified {
+        Unverified {
+            user_id: 1,
+            eventsub_id: eventsub_id.to_string(),
+            attempts,
+        }
+    }
+
+    #[test]
+    fn plans_verifications_from_listed_statuses() {
+        let statuses = HashMap::from([
+            ("enabled".to_string(), ENABLED.to_string()),
+            ("pending".to_string(), PENDING.to_string()),
+            ("failed".to_string(), FAILED.to_string()),
+        ]);
+
+        let plan = plan_verifications(
+            vec![
+                unverified("enabled", 0),
+                unverified("pending", 0),
+                unverified("failed", MAX_VERIFICATION_ATTEMPTS),
+                unverified("deleted", 1),
+            ],
+            &statuses,
+        );
+
+        assert_eq!(
+            plan,
+            vec![
+                (unverified("enabled", 0), ENABLED, Step::Enabled),
+                (unverified("pending", 0), PENDING, Step::Wait),
+                (
+                    unverified("failed", MAX_VERIFICATION_ATTEMPTS),
+                    FAILED,
+                    Step::GiveUp
+                ),
+                (unverified("deleted", 1), MISSING, Step::Retry),
+            ]
+        );
+    }
+}
@@ -0,0 +1,516 @@
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use actix_codec::Framed;
+use actix_web::web::Data;
+use awc::ws::{Codec, Frame, Message};
+use awc::BoxedSocket;
+use futures_util::{SinkExt, StreamExt};
+use log::{error, info, warn};
+use sqlx::Row;
+
+use crate::conduit;
+use crate::config::EventsubTransport;
+use crate::errors::Error;
+use crate::eventsub::{handle_once, message_type_label, EventsubPayload};
+use crate::logging::{self, LogContext};
+use crate::routes::notifications::{process_message, EventsubMessage};
+use crate::routes::twitch::structs::{
+    WebsocketMessage, WebsocketSessionData, WebsocketSessionPayload,
+};
+use crate::structs::{AppState, Result};
+
+const MESSAGE_WELCOME: &str = "session_welcome";
+const MESSAGE_KEEPALIVE: &str = "session_keepalive";
+const MESSAGE_RECONNECT: &str = "session_reconnect";
+
+/// Keepalive timeout Twitch uses if the welcome message does not contain one.
+const DEFAULT_KEEPALIVE: Duration = Duration::from_secs(10);
+/// Added to the keepalive timeout before the connection is considered dead.
+const KEEPALIVE_GRACE: Duration = Duration::from_secs(5);
+const WELCOME_TIMEOUT: Duration = Duration::from_secs(10);
+/// Time the old connection is read after a reconnect, for messages sent before the switch.
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(5);
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(60);
+
+type Socket = Framed<BoxedSocket, Codec>;
+
+/// Id of the current websocket session, shared between the transport and the workers
+/// registering new eventsubs.
+#[derive(Clone, Default)]
+pub struct WebsocketSession(Arc<RwLock<Option<String>>>);
+
+impl WebsocketSession {
+    /// Returns `None` while the transport is not connected.
+    pub fn id(&self) -> Option<String> {
+        self.0.read().ok().and_then(|id| id.clone())
+    }
+
+    fn set(&self, id: Option<String>) {
+        if let Ok(mut current) = self.0.write() {
+            *current = id;
+        }
+    }
+}
+
+/// Receives the eventsub messages over a websocket connection to Twitch, reconnecting with a
+/// new session whenever the connection fails.
+pub async fn run_websocket(state: Data<AppState>, url: &'static str) {
+    info!("Starting eventsub websocket transport with {url}");
+
+    let mut delay = RECONNECT_DELAY;
+
+    loop {
+        let result = tokio::select! {
+            result = run_connection(&state, url, &mut delay) => result,
+            _ = state.shutdown.triggered() => break,
+        };
+
+        state.websocket_session.set(None);
+        if let Err(e) = result {
+            warn!("Eventsub websocket disconnected, reconnecting in {delay:?}: {e}");
+        }
+
+        tokio::select! {
+            _ = actix_web::rt::time::sleep(delay) => {}
+            _ = state.shutdown.triggered() => break,
+        }
+
+        delay = (delay * 2).min(MAX_RECONNECT_DELAY);
+    }
+
+    state.websocket_session.set(None);
+    info!("Eventsub websocket transport stopped");
+}
+
+/// Runs a connection until it fails. Twitch moves the session to another connection with a
+/// `session_reconnect` message, the subscriptions are kept in that case.
+async fn run_connection(state: &Data<AppState>, url: &str, delay: &mut Duration) -> Result<()> {
+    let (mut socket, session) = connect(state, url).await?;
+    *delay = RECONNECT_DELAY;
+
+    info!("Connected eventsub websocket session {}", session.id);
+    start_session(state, &session);
+
+    let mut keepalive = keepalive_timeout(&session);
+
+    loop {
+        let message = next_message(&mut socket, keepalive).await?;
+
+        if let Some(reconnect_url) = handle_message(state, message).await {
+            // Twitch keeps the old connection open until the new one is welcomed
+            let (new_socket, session) = connect(state, &reconnect_url).await?;
+            drain(state, socket).await;
+
+            info!("Moved eventsub websocket session {}", session.id);
+            if state.websocket_session.id().as_deref() != Some(session.id.as_str()) {
+                start_session(state, &session);
+            }
+
+            keepalive = keepalive_timeout(&session);
+            socket = new_socket;
+        }
+    }
+}
+
+/// Makes the messages of the subscriptions arrive at the session. Websocket subscriptions are
+/// bound to their session, a new one starts without any. A conduit shard has to be pointed
+/// to the session instead. This runs in the background, as Twitch closes sessions whose
+/// messages are not read in time.
+fn start_session(state: &Data<AppState>, session: &WebsocketSessionData) {
+    state.websocket_session.set(Some(session.id.clone()));
+
+    let state = state.clone();
+    let session_id = session.id.clone();
+
+    state.clone().shutdown.spawn(async move {
+        let result = match state.twitch.transport {
+            EventsubTransport::Conduit { replica_id, .. } => {
+                conduit::sync_shards(&state, replica_id).await
+            }
+            _ => subscribe_users(&state, &session_id).await,
+        };
+
+        if let Err(e) = result {
+            error!("Could not subscribe with websocket session {session_id}: {e}");
+        }
+    });
+}
+
+/// Connects to the url and waits for the welcome message of the session.
+async fn connect(state: &AppState, url: &str) -> Result<(Socket, WebsocketSessionData)> {
+    let (_, mut socket) = state
+        .client
+        .ws(url)
+        .connect()
+        .await
+        .map_err(|e| Error::Twitch(format!("Could not connect to eventsub websocket: {e}")))?;
+
+    let message = next_message(&mut socket, WELCOME_TIMEOUT).await?;
+    if message.metadata.message_type != MESSAGE_WELCOME {
+        return Err(Error::Twitch(format!(
+            "Expected welcome message, received {}",
+            message.metadata.message_type
+        )));
+    }
+
+    let payload = serde_json::from_str::<WebsocketSessionPayload>(message.payload.get())
+        .map_err(|e| Error::Twitch(format!("Unexpected welcome message: {e}")))?;
+
+    Ok((socket, payload.session))
+}
+
+fn keepalive_timeout(session: &WebsocketSessionData) -> Duration {
+    session
+        .keepalive_timeout_seconds
+        .map(Duration::from_secs)
+        .unwrap_or(DEFAULT_KEEPALIVE)
+        + KEEPALIVE_GRACE
+}
+
+/// Reads the next message, answering pings. Fails if nothing is received within the timeout,
+/// Twitch sends keepalive messages on idle connections to prevent that.
+async fn next_message(socket: &mut Socket, timeout: Duration) -> Result<WebsocketMessage> {
+    loop {
+        let frame = match actix_web::rt::time::timeout(timeout, socket.next()).await {
+            Ok(Some(Ok(frame))) => frame,
+            Ok(Some(Err(e))) => {
+                return Err(Error::Twitch(format!("Eventsub websocket failed: {e}")))
+            }
+            Ok(None) => return Err(Error::Twitch("Eventsub websocket closed".to_string())),
+            Err(_) => return Err(Error::Twitch("Eventsub websocket timed out".to_string())),
+        };
+
+        match frame {
+            Frame::Text(text) => match serde_json::from_slice::<WebsocketMessage>(&text) {
+                Ok(message) => return Ok(message),
+                Err(e) => warn!("Ignoring unexpected eventsub websocket message: {e}"),
+            },
+            Frame::Ping(data) => socket
+                .send(Message::Pong(data))
+                .await
+                .map_err(|e| Error::Twitch(format!("Eventsub websocket failed: {e}")))?,
+            Frame::Close(reason) => {
+                return Err(Error::Twitch(format!(
+                    "Eventsub websocket closed: {reason:?}"
+                )))
+            }
+            Frame::Binary(_) | Frame::Pong(_) | Frame::Continuation(_) => {}
+        }
+    }
+}
+
+/// Handles a message of an established session. Returns the url to reconnect to if Twitch
+/// asks for it.
+async fn handle_message(state: &Data<AppState>, message: WebsocketMessage) -> Option<String> {
+    match message.metadata.message_type.as_str() {
+        MESSAGE_WELCOME | MESSAGE_KEEPALIVE => None,
+        MESSAGE_RECONNECT => {
+            match serde_json::from_str::<WebsocketSessionPayload>(message.payload.get()) {
+                Ok(payload) => payload.session.reconnect_url,
+                Err(e) => {
+                    error!("Could not parse eventsub websocket reconnect message: {e}");
+                    None
+                }
+            }
+        }
+        _ => {
+            let context = LogContext {
+                eventsub_message_id: Some(message.metadata.message_id.clone()),
+                ..LogContext::default()
+            };
+
+            logging::with_context(context, async {
+                if let Err(e) = handle_event(state, &message).await {
+                    error!("Could not handle eventsub websocket message: {e}");
+                }
+            })
+            .await;
+
+            None
+        }
+    }
+}
+
+/// Feeds notifications and revocations into the pipeline of the webhook messages.
+async fn handle_event(state: &Data<AppState>, message: &WebsocketMessage) -> Result<()> {
+    let message_type = message.metadata.message_type.as_str();
+    let event = EventsubMessage::parse(state, message_type, message.payload.get().as_bytes())?;
+
+    handle_once(
+        state.get_ref(),
+        &message.metadata.message_id,
+        message_type_label(message_type),
+        process_message(state, event),
+    )
+    .await?;
+
+    Ok(())
+}
+
+/// Handles the messages Twitch sent on the old connection before the new one was welcomed.
+async fn drain(state: &Data<AppState>, mut socket: Socket) {
+    while let Ok(message) = next_message(&mut socket, DRAIN_TIMEOUT).await {
+        if handle_message(state, message).await.is_some() {
+            warn!("Ignoring reconnect message of the replaced eventsub websocket connection");
+        }
+    }
+}
+
+/// Registers the eventsubs of all tracked users with the session. Stops once the session is
+/// replaced, the next one subscribes all users again.
+async fn subscribe_users(state: &AppState, session_id: &str) -> Result<()> {
+    let user_ids = sqlx::query("SELECT id FROM twitch_users")
+        .fetch_all(&state.db)
+        .await?
+        .iter()
+        .map(|row| row.get::<i64, &str>("id"))
+        .collect::<Vec<_>>();
+
+    for user_id in user_ids {
+        if state.websocket_session.id().as_deref() != Some(session_id) {
+            info!("Websocket session {session_id} was replaced, stopping its subscriptions");
+            return Ok(());
+        }
+
+        let eventsub_id = match state.register_eventsub(user_id).await {
+            Ok(id) => id,
+            Err(e) => {
+                error!("Could not subscribe user {user_id}: {e}");
+                continue;
+            }
+        };
+
+        sqlx::query("UPDATE twitch_users SET eventsub_id = $2 WHERE id = $1")
+            .bind(user_id)
+            .bind(eventsub_id)
+            .execute(&state.db)
+            .await?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+
+    use serde_json::json;
+
+    use crate::test_utils::test_state;
+
+    use super::*;
+
+    fn state() -> Data<AppState> {
+        Data::new(test_state())
+    }
+
+    fn session_message(message_type: &str, reconnect_url: Option<&str>) -> String {
+        json!({
+            "metadata": {
+                "message_id": "96a3f3b5-5dec-4eed-908e-e11ee657416c",
+                "message_type": message_type,
+                "message_timestamp": "2023-07-19T14:56:51.634234626Z"
+            },
+            "payload": {
+                "session": {
+                    "id": "AQoQILE98gtqShGmLD7AM6yJThAB",
+                    "status": if reconnect_url.is_some() { "reconnecting" } else { "connected" },
+                    "connected_at": "2023-07-19T14:56:51.616329898Z",
+                    "keepalive_timeout_seconds": reconnect_url.map_or(Some(10), |_| None),
+                    "reconnect_url": reconnect_url
+                }
+            }
+        })
+        .to_string()
+    }
+
+    fn keepalive_message() -> String {
+        json!({
+            "metadata": {
+                "message_id": "84c1e79a-2a4b-4c13-ba0b-4312293e9308",
+                "message_type": "session_keepalive",
+                "message_timestamp": "2023-07-19T10:11:12.634234626Z"
+            },
+            "payload": {}
+        })
+        .to_string()
+    }
+
+    /// Stands in for the Twitch websocket server, accepting one connection and sending it the
+    /// messages. Returns the url to connect to.
+    fn serve(messages: Vec<String>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("ws://{}/ws", listener.local_addr().unwrap());
+
+        std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+
+            let mut request = vec![];
+            let mut buf = [0; 1024];
+            while !request.ends_with(b"\r\n\r\n") {
+                let n = stream.read(&mut buf).unwrap();
+                request.extend_from_slice(&buf[..n]);
+            }
+
+            let request = String::from_utf8(request).unwrap();
+            let key = request
+                .lines()
+                .filter_map(|l| l.split_once(':'))
+                .find(|(name, _)| name.eq_ignore_ascii_case("sec-websocket-key"))
+                .map(|(_, value)| value.trim())
+                .unwrap();
+            let accept = actix_http::ws::hash_key(key.as_bytes());
+
+            write!(
+                stream,
+                "HTTP/1.1 101 Switching Protocols\r\nupgrade: websocket\r\nconnection: upgrade\r\nsec-websocket-accept: {}\r\n\r\n",
+                std::str::from_utf8(&accept).unwrap()
+            )
+            .unwrap();
+
+            for message in messages {
+                // Unmasked text frame, the messages are shorter than 64KiB
+                let mut frame = vec![0x81, 126];
+                frame.extend_from_slice(&(message.len() as u16).to_be_bytes());
+                frame.extend_from_slice(message.as_bytes());
+                stream.write_all(&frame).unwrap();
+            }
+
+            // Keeps the connection open until the client is done
+            let _ = stream.read(&mut buf);
+        });
+
+        url
+    }
+
+    #[actix_web::test]
+    async fn connect_reads_welcome() {
+        let state = state();
+        let url = serve(vec![
+            session_message(MESSAGE_WELCOME, None),
+            keepalive_message(),
+        ]);
+
+        let (mut socket, session) = connect(&state, &url).await.unwrap();
+        assert_eq!(session.id, "AQoQILE98gtqShGmLD7AM6yJThAB");
+        assert_eq!(keepalive_timeout(&session), Duration::from_secs(15));
+
+        let message = next_message(&mut socket, Duration::from_secs(1))
+            .await
+            .unwrap();
+        assert_eq!(message.metadata.message_type, MESSAGE_KEEPALIVE);
+        assert!(handle_message(&state, message).await.is_none());
+    }
+
+    #[actix_web::test]
+    async fn subscribes_in_background() {
+        let state = state();
+        let url = serve(vec![
+            session_message(MESSAGE_WELCOME, None),
+            keepalive_message(),
+        ]);
+
+        let (mut socket, session) = connect(&state, &url).await.unwrap();
+        start_session(&state, &session);
+        assert_eq!(state.websocket_session.id(), Some(session.id.clone()));
+        assert_eq!(state.shutdown.running_tasks(), 1);
+
+        // The session is read while the users are subscribed
+        let message = next_message(&mut socket, Duration::from_secs(1))
+            .await
+            .unwrap();
+        assert_eq!(message.metadata.message_type, MESSAGE_KEEPALIVE);
+        assert!(state.shutdown.wait_idle(Duration::from_secs(5)).await);
+    }
+
+    #[actix_web::test]
+    async fn connect_requires_welcome() {
+        let state = state();
+        let url = serve(vec![keepalive_message()]);
+
+        assert!(matches!(connect(&state, &url).await, Err(Error::Twitch(_))));
+    }
+
+    #[actix_web::test]
+    async fn times_out_without_keepalive() {
+        let state = state();
+        let url = serve(vec![session_message(MESSAGE_WELCOME, None)]);
+
+        let (mut socket, _) = connect(&state, &url).await.unwrap();
+
+        assert!(next_message(&mut socket, Duration::from_millis(100))
+            .await
+            .is_err());
+    }
+
+    #[actix_web::test]
+    async fn follows_reconnect_message() {
+        let state = state();
+        let new_url = serve(vec![session_message(MESSAGE_WELCOME, None)]);
+        let url = serve(vec![
+            session_message(MESSAGE_WELCOME, None),
+            session_message(MESSAGE_RECONNECT, Some(&new_url)),
+        ]);
+
+        let (mut socket, _) = connect(&state, &url).await.unwrap();
+        let message = next_message(&mut socket, Duration::from_secs(1))
+            .await
+            .unwrap();
+        let reconnect_url = handle_message(&state, message).await.unwrap();
+        assert_eq!(reconnect_url, new_url);
+
+        let (_, session) = connect(&state, &reconnect_url).await.unwrap();
+        assert_eq!(session.id, "AQoQILE98gtqShGmLD7AM6yJThAB");
+    }
+
+    #[actix_web::test]
+    async fn prepares_handler_of_notification() {
+        let message = r#"{
+            "metadata": {
+                "message_id": "befa7b53-d79d-478f-86b9-120f112b044e",
+                "message_type": "notification",
+                "message_timestamp": "2023-07-19T10:11:12.464757833Z",
+                "subscription_type": "stream.online",
+                "subscription_version": "1"
+            },
+            "payload": {
+                "subscription": {
+                    "id": "f1c2a387-161a-49f9-a165-0f21d7a4e1c4",
+                    "status": "enabled",
+                    "type": "stream.online",
+                    "version": "1",
+                    "cost": 1,
+                    "condition": {
+                        "broadcaster_user_id": "1337"
+                    },
+                    "transport": {
+                        "method": "websocket",
+                        "session_id": "AQoQexAWVYKSTIu4ec_2VAxyuhAB"
+                    },
+                    "created_at": "2023-07-19T10:11:12.464757833Z"
+                },
+                "event": {
+                    "id": "9001",
+                    "broadcaster_user_id": "1337",
+                    "broadcaster_user_login": "cool_user",
+                    "broadcaster_user_name": "Cool_User",
+                    "type": "live",
+                    "started_at": "2023-07-19T10:11:12.464757833Z"
+                }
+            }
+        }"#;
+
+        let message = serde_json::from_str::<WebsocketMessage>(message).unwrap();
+        let event = EventsubMessage::parse(
+            &state(),
+            &message.metadata.message_type,
+            message.payload.get().as_bytes(),
+        )
+        .unwrap();
+
+        assert!(matches!(event, EventsubMessage::Notification(Some(_))));
+    }
+}